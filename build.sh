@@ -18,4 +18,5 @@
 # along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
 
 set -o xtrace
-cargo rustc --release -- -Clink-args="-shared -Wl,-soname,libnss_alexandria.so.2"
+cargo rustc --release --lib -- -Clink-args="-shared -Wl,-soname,libnss_alexandria.so.2"
+cargo build --release --bins
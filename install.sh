@@ -19,3 +19,7 @@
 
 set -o xtrace
 install -v -m 755 target/release/libnss_alexandria.so /lib64/libnss_alexandria.so.2
+ln -v -sf libnss_alexandria.so.2 /lib64/libsubid_alexandria.so
+install -v -m 755 target/release/alexandria-getent /usr/sbin/alexandria-getent
+install -v -m 755 target/release/alexandria-check /usr/sbin/alexandria-check
+install -v -m 755 target/release/alexandria-cache /usr/sbin/alexandria-cache
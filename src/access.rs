@@ -0,0 +1,118 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// Access policies for the shadow database, and for enumerations.
+//
+// NOTE: the *real* security is implemented by the permissions of the privileged socket. This is
+//       just to short-circuit callers which can't read it anyway, and to not ask the service on
+//       behalf of callers which are not supposed to see shadow entries at all.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::ptr::null_mut;
+use libc::gid_t;
+use libc::getegid;
+use libc::geteuid;
+use libc::getgroups;
+use config::{SHADOW_ALLOW_UIDS, SHADOW_ALLOW_GIDS, SHADOW_ALLOW_GROUPS};
+use config::ENUMERATE_ALLOW_COMM;
+use context;
+use types::Enumerate;
+use util::debug;
+
+// shadow decides if the calling process may read the shadow database: by its effective uid, its
+// effective gid or one of its supplementary groups
+pub fn shadow() -> bool {
+    let euid = unsafe { geteuid() };
+    if SHADOW_ALLOW_UIDS.contains(&euid) {
+        debug(format!("shadow access allowed: euid {}", euid).as_str());
+        return true;
+    }
+
+    let allowed = allowed_gids();
+    let egid = unsafe { getegid() };
+    if allowed.contains(&egid) {
+        debug(format!("shadow access allowed: euid {}, egid {}", euid, egid).as_str());
+        return true;
+    }
+    for gid in supplementary_groups() {
+        if allowed.contains(&gid) {
+            debug(format!("shadow access allowed: euid {}, supplementary group {}", euid, gid).as_str());
+            return true;
+        }
+    }
+
+    debug(format!("shadow access denied: euid {}, egid {}", euid, egid).as_str());
+    false
+}
+
+// allowed_gids returns SHADOW_ALLOW_GIDS and the gids of SHADOW_ALLOW_GROUPS. The names are looked
+// up in /etc/group directly, as asking NSS from within an NSS module could end up right here again.
+fn allowed_gids() -> Vec<gid_t> {
+    let mut gids = SHADOW_ALLOW_GIDS.to_vec();
+    if SHADOW_ALLOW_GROUPS.is_empty() {
+        return gids;
+    }
+    let f = match File::open("/etc/group") {
+        Ok(f) => f,
+        Err(_) => return gids,
+    };
+    for line in BufReader::new(f).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        // name:password:gid:members
+        let mut fields = line.split(':');
+        let name = fields.next().unwrap_or("");
+        if !SHADOW_ALLOW_GROUPS.contains(&name) {
+            continue;
+        }
+        if let Some(Ok(gid)) = fields.nth(1).map(|gid| gid.parse::<gid_t>()) {
+            gids.push(gid);
+        }
+    }
+    gids
+}
+
+fn supplementary_groups() -> Vec<gid_t> {
+    let n = unsafe { getgroups(0, null_mut()) };
+    if n <= 0 {
+        return vec![];
+    }
+    let mut groups = vec![0 as gid_t; n as usize];
+    let n = unsafe { getgroups(n, groups.as_mut_ptr()) };
+    if n < 0 {
+        return vec![];
+    }
+    groups.truncate(n as usize);
+    groups
+}
+
+// enumerate returns how the calling process may enumerate a database of the given mode. Processes
+// whose names are not in ENUMERATE_ALLOW_COMM, if it is set, may not enumerate at all.
+pub fn enumerate(mode: Enumerate) -> Enumerate {
+    if mode == Enumerate::Disabled || ENUMERATE_ALLOW_COMM.is_empty() {
+        return mode;
+    }
+    let comm = context::comm().unwrap_or_default();
+    if ENUMERATE_ALLOW_COMM.iter().any(|allowed| allowed.as_bytes() == comm.as_slice()) {
+        return mode;
+    }
+    debug(format!("enumeration denied: process {}", String::from_utf8_lossy(&comm)).as_str());
+    Enumerate::Disabled
+}
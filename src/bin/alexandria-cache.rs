@@ -0,0 +1,127 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// alexandria-cache writes the cache file of config::MCACHE_PATH, which the NSS module looks
+// entries up in before it asks the Alexandria service. It fetches all passwd and group entries of
+// the default directory and replaces the file once, or every --interval seconds, which has to be
+// shorter than MCACHE_TTL_S. With config::GENERATION, it also replaces the file as soon as the
+// generation of the directory changed, as the module doesn't use it anymore then. It runs as a user
+// who may write the file, usually root next to the daemon, and the file is readable by everyone.
+//
+// It exits with 1 for wrong arguments and 3 if the entries can't be fetched or written.
+
+extern crate nss_alexandria;
+
+use std::env;
+use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant};
+use nss_alexandria::config::{MCACHE_PATH, MCACHE_TTL_S};
+use nss_alexandria::decode::PayloadError;
+use nss_alexandria::domains::{DEFAULT, Merged};
+use nss_alexandria::generation;
+use nss_alexandria::mcache;
+use nss_alexandria::mcache::Record;
+use nss_alexandria::routes;
+use nss_alexandria::types::{AlexandriaPassword, AlexandriaGroup};
+
+const USAGE: &'static str = "usage: alexandria-cache [--interval SECONDS]";
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_ERROR: i32 = 3;
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(EXIT_USAGE);
+}
+
+// records returns the elements of the default directory in list, key decodes the current element
+// to its name and id
+fn records<F>(list: &mut Merged, key: F) -> Result<Vec<Record>, PayloadError> where F: Fn(&Merged) -> Result<Option<(Vec<u8>, u32)>, PayloadError> {
+    let mut records = vec![];
+    loop {
+        let record = match list.raw() {
+            None => break,
+            // the cache only serves the default directory
+            Some((domain, _)) if !domain.name.is_empty() => None,
+            Some((_, json)) => try!(key(list)).map(|(name, id)| Record {
+                name: name,
+                id: id,
+                json: json.to_string(),
+            }),
+        };
+        records.extend(record);
+        list.advance();
+    }
+    Ok(records)
+}
+
+fn refresh(path: &str, generation: Option<u64>) -> Result<(usize, usize), String> {
+    let mut passwd = try!(routes::passwd(None).map_err(|e| format!("route passwd: {}", e)));
+    let passwd = try!(records(&mut passwd, |list| {
+        list.current::<AlexandriaPassword>().map(|e| e.map(|e| (e.pw_name.as_bytes().into_owned(), e.pw_uid)))
+    }).map_err(|e| format!("route passwd: {}", e)));
+    let mut group = try!(routes::group(None).map_err(|e| format!("route group: {}", e)));
+    let group = try!(records(&mut group, |list| {
+        list.current::<AlexandriaGroup>().map(|e| e.map(|e| (e.gr_name.as_bytes().into_owned(), e.gr_gid)))
+    }).map_err(|e| format!("route group: {}", e)));
+    try!(mcache::write(path, generation, &passwd, &group).map_err(|e| format!("{}: {}", path, e)));
+    Ok((passwd.len(), group.len()))
+}
+
+fn main() {
+    let mut interval = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--interval" => interval = match args.next().and_then(|n| n.parse::<u64>().ok()) {
+                Some(s) if s > 0 && s < MCACHE_TTL_S => Some(s),
+                _ => usage(),
+            },
+            _ => usage(),
+        }
+    }
+    let path = match MCACHE_PATH {
+        Some(path) => path,
+        None => {
+            eprintln!("alexandria-cache: MCACHE_PATH is not configured");
+            exit(EXIT_ERROR);
+        },
+    };
+
+    loop {
+        // read before the entries are fetched, so that a change during it counts as one
+        let written = generation::current(&DEFAULT);
+        match refresh(path, written) {
+            Ok((passwd, group)) => eprintln!("# {}: {} passwd and {} group entries", path, passwd, group),
+            // the old file stays until it expires, maybe the service is back by the next interval
+            Err(e) if interval.is_some() => eprintln!("# {}", e),
+            Err(e) => {
+                eprintln!("# {}", e);
+                exit(EXIT_ERROR);
+            },
+        }
+        let s = match interval {
+            Some(s) => s,
+            None => break,
+        };
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(s) && !generation::changed(&DEFAULT, written) {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// alexandria-check looks at everything between glibc and the Alexandria service and reports what
+// is wrong: the sockets, /etc/nsswitch.conf, whether the service answers every route, whether
+// lookups by name, by id and by enumeration agree, and whether the directory is consistent.
+//
+// It exits with 1 if anything failed, which includes an inconsistent directory. Warnings don't
+// change the exit code. Run it as root to include the shadow database.
+
+extern crate libc;
+extern crate nss_alexandria;
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::process::exit;
+use libc::{geteuid, getpwnam};
+use nss_alexandria::config::{SOCKET_PATH, SOCKET_PATH_PRIV, DOMAINS, SHADOW_ALLOW_GIDS, SHADOW_ALLOW_GROUPS};
+use nss_alexandria::mcache;
+use nss_alexandria::routes;
+use nss_alexandria::types::{AlexandriaPassword, AlexandriaGroup, AlexandriaShadow};
+
+const NSSWITCH_CONF: &'static str = "/etc/nsswitch.conf";
+// alexandria must be configured for these, the others are optional
+const REQUIRED_DATABASES: [&'static str; 3] = ["passwd", "group", "shadow"];
+const OPTIONAL_DATABASES: [&'static str; 2] = ["automount", "subid"];
+// how many entries of each enumeration are looked up by name and id again
+const ROUND_TRIPS: usize = 5;
+
+struct Findings {
+    failed: usize,
+    warnings: usize,
+}
+
+impl Findings {
+    fn ok(&mut self, msg: &str) {
+        println!("OK    {}", msg);
+    }
+
+    fn warn(&mut self, msg: &str) {
+        println!("WARN  {}", msg);
+        self.warnings += 1;
+    }
+
+    fn fail(&mut self, msg: &str) {
+        println!("FAIL  {}", msg);
+        self.failed += 1;
+    }
+}
+
+// check_socket makes sure path is a socket with the expected owner and mode. The public socket must
+// be usable by everyone, the privileged one only by root and the groups of the shadow policy.
+fn check_socket(f: &mut Findings, path: &str, privileged: bool) {
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(e) => return f.fail(format!("socket {}: {}", path, e).as_str()),
+    };
+    if !meta.file_type().is_socket() {
+        return f.fail(format!("socket {}: not a socket", path).as_str());
+    }
+    let mode = meta.mode() & 0o777;
+    if privileged {
+        let group_allowed = !SHADOW_ALLOW_GIDS.is_empty() || !SHADOW_ALLOW_GROUPS.is_empty();
+        let forbidden = if group_allowed { 0o007 } else { 0o077 };
+        if meta.uid() != 0 {
+            f.fail(format!("socket {}: owned by uid {}, expected root", path, meta.uid()).as_str());
+        } else if mode & forbidden != 0 {
+            f.fail(format!("socket {}: mode {:o} gives access to everyone, expected {}", path, mode, if group_allowed { "770" } else { "700" }).as_str());
+        } else if group_allowed && mode & 0o060 != 0o060 && SHADOW_ALLOW_GIDS.iter().all(|&gid| gid != meta.gid()) {
+            f.warn(format!("socket {}: mode {:o} and gid {}, the groups of the shadow policy can't use it", path, mode, meta.gid()).as_str());
+        } else {
+            f.ok(format!("socket {}: uid {}, gid {}, mode {:o}", path, meta.uid(), meta.gid(), mode).as_str());
+        }
+    } else if mode & 0o006 != 0o006 {
+        f.fail(format!("socket {}: mode {:o}, other users can't use it", path, mode).as_str());
+    } else {
+        f.ok(format!("socket {}: uid {}, gid {}, mode {:o}", path, meta.uid(), meta.gid(), mode).as_str());
+    }
+}
+
+// check_nsswitch makes sure alexandria is a source of the databases it serves
+fn check_nsswitch(f: &mut Findings) {
+    let file = match fs::File::open(NSSWITCH_CONF) {
+        Ok(file) => file,
+        Err(e) => return f.fail(format!("{}: {}", NSSWITCH_CONF, e).as_str()),
+    };
+    let mut sources: HashMap<String, Vec<String>> = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let line = line.split('#').next().unwrap_or("");
+        let mut parts = line.splitn(2, ':');
+        let (database, rest) = match (parts.next(), parts.next()) {
+            (Some(database), Some(rest)) => (database.trim(), rest),
+            _ => continue,
+        };
+        // actions like [NOTFOUND=return] are not sources
+        let list = rest.split_whitespace().filter(|s| !s.starts_with('[')).map(|s| s.to_string()).collect();
+        sources.insert(database.to_string(), list);
+    }
+
+    for database in REQUIRED_DATABASES.iter().chain(OPTIONAL_DATABASES.iter()) {
+        let required = REQUIRED_DATABASES.contains(database);
+        match sources.get(*database) {
+            Some(list) if list.iter().any(|s| s == "alexandria") => f.ok(format!("{}: {}: {}", NSSWITCH_CONF, database, list.join(" ")).as_str()),
+            Some(list) if required => f.fail(format!("{}: {}: alexandria missing in \"{}\"", NSSWITCH_CONF, database, list.join(" ")).as_str()),
+            None if required => f.fail(format!("{}: no {} line", NSSWITCH_CONF, database).as_str()),
+            _ => f.warn(format!("{}: alexandria is not used for {}", NSSWITCH_CONF, database).as_str()),
+        }
+    }
+}
+
+// passwd_consistency fails duplicate names and uids, and returns the names of all users
+fn passwd_consistency(f: &mut Findings, entries: &[AlexandriaPassword]) -> HashSet<Vec<u8>> {
+    let mut names = HashSet::new();
+    let mut uids: HashMap<u32, Vec<u8>> = HashMap::new();
+    for e in entries {
+        let name = e.pw_name.as_bytes().into_owned();
+        if !names.insert(name.clone()) {
+            f.fail(format!("passwd: duplicate name {}", String::from_utf8_lossy(&name)).as_str());
+        }
+        if let Some(other) = uids.insert(e.pw_uid, name.clone()) {
+            f.fail(format!("passwd: uid {} is shared by {} and {}", e.pw_uid, String::from_utf8_lossy(&other), String::from_utf8_lossy(&name)).as_str());
+        }
+    }
+    names
+}
+
+// local_user reports if a user who is not in the directory exists on this host anyway, e.g. in
+// /etc/passwd
+fn local_user(name: &[u8]) -> bool {
+    match CString::new(name) {
+        Ok(name) => !unsafe { getpwnam(name.as_ptr()) }.is_null(),
+        Err(_) => false,
+    }
+}
+
+// group_consistency fails duplicate names and gids, and members which don't exist
+fn group_consistency(f: &mut Findings, entries: &[AlexandriaGroup], users: Option<&HashSet<Vec<u8>>>) {
+    let mut names = HashSet::new();
+    let mut gids = HashSet::new();
+    for e in entries {
+        if !names.insert(e.gr_name.as_bytes().into_owned()) {
+            f.fail(format!("group: duplicate name {}", e.gr_name).as_str());
+        }
+        if !gids.insert(e.gr_gid) {
+            f.fail(format!("group: gid {} is used more than once", e.gr_gid).as_str());
+        }
+        if let Some(users) = users {
+            for mem in &e.gr_mem {
+                if !users.contains(mem.as_bytes().as_ref()) && !local_user(&mem.as_bytes()) {
+                    f.fail(format!("group: member {} of {} does not exist", mem, e.gr_name).as_str());
+                }
+            }
+        }
+    }
+}
+
+fn check_passwd(f: &mut Findings) -> Option<HashSet<Vec<u8>>> {
+    let list = match routes::passwd(None) {
+        Ok(list) => list,
+        Err(e) => {
+            f.fail(format!("route passwd: {}", e).as_str());
+            return None;
+        },
+    };
+    let entries: Vec<AlexandriaPassword> = match list.entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            f.fail(format!("route passwd: {}", e).as_str());
+            return None;
+        },
+    };
+    f.ok(format!("route passwd: {} entries", entries.len()).as_str());
+    let names = passwd_consistency(f, &entries);
+
+    for e in entries.iter().take(ROUND_TRIPS) {
+        let name = e.pw_name.as_bytes();
+        let mut body = String::new();
+        match routes::passwd_name(&name, &mut body) {
+            Ok(Some(ref o)) if o.pw_uid == e.pw_uid && o.pw_dir.as_bytes() == e.pw_dir.as_bytes() => {},
+            Ok(Some(_)) => f.fail(format!("passwd: lookup of {} by name differs from the enumeration", e.pw_name).as_str()),
+            Ok(None) => f.fail(format!("passwd: {} is enumerated, but not found by name", e.pw_name).as_str()),
+            Err(err) => f.fail(format!("passwd: lookup of {} by name: {}", e.pw_name, err).as_str()),
+        }
+        let mut body = String::new();
+        match routes::passwd_uid(e.pw_uid, &mut body) {
+            Ok(Some(_)) => {},
+            Ok(None) => f.fail(format!("passwd: uid {} of {} is enumerated, but not found by uid", e.pw_uid, e.pw_name).as_str()),
+            Err(err) => f.fail(format!("passwd: lookup of uid {}: {}", e.pw_uid, err).as_str()),
+        }
+    }
+    f.ok(format!("passwd: looked up {} entries by name and uid", entries.len().min(ROUND_TRIPS)).as_str());
+    Some(names)
+}
+
+fn check_group(f: &mut Findings, users: Option<&HashSet<Vec<u8>>>) {
+    let list = match routes::group(None) {
+        Ok(list) => list,
+        Err(e) => return f.fail(format!("route group: {}", e).as_str()),
+    };
+    let entries: Vec<AlexandriaGroup> = match list.entries() {
+        Ok(entries) => entries,
+        Err(e) => return f.fail(format!("route group: {}", e).as_str()),
+    };
+    f.ok(format!("route group: {} entries", entries.len()).as_str());
+    group_consistency(f, &entries, users);
+
+    for e in entries.iter().take(ROUND_TRIPS) {
+        let name = e.gr_name.as_bytes();
+        let mut body = String::new();
+        match routes::group_name(&name, &mut body) {
+            Ok(Some(ref o)) if o.gr_gid == e.gr_gid && o.gr_mem.len() == e.gr_mem.len() => {},
+            Ok(Some(_)) => f.fail(format!("group: lookup of {} by name differs from the enumeration", e.gr_name).as_str()),
+            Ok(None) => f.fail(format!("group: {} is enumerated, but not found by name", e.gr_name).as_str()),
+            Err(err) => f.fail(format!("group: lookup of {} by name: {}", e.gr_name, err).as_str()),
+        }
+        let mut body = String::new();
+        match routes::group_gid(e.gr_gid, &mut body) {
+            Ok(Some(_)) => {},
+            Ok(None) => f.fail(format!("group: gid {} of {} is enumerated, but not found by gid", e.gr_gid, e.gr_name).as_str()),
+            Err(err) => f.fail(format!("group: lookup of gid {}: {}", e.gr_gid, err).as_str()),
+        }
+    }
+    f.ok(format!("group: looked up {} entries by name and gid", entries.len().min(ROUND_TRIPS)).as_str());
+}
+
+fn check_shadow(f: &mut Findings, users: Option<&HashSet<Vec<u8>>>) {
+    if unsafe { geteuid() } != 0 {
+        return f.warn("route shadow: skipped, run as root to check it");
+    }
+    let list = match routes::shadow(None) {
+        Ok(list) => list,
+        Err(e) => return f.fail(format!("route shadow: {}", e).as_str()),
+    };
+    let entries: Vec<AlexandriaShadow> = match list.entries() {
+        Ok(entries) => entries,
+        Err(e) => return f.fail(format!("route shadow: {}", e).as_str()),
+    };
+    f.ok(format!("route shadow: {} entries", entries.len()).as_str());
+
+    for e in &entries {
+        if let Some(users) = users {
+            if !users.contains(e.sp_namp.as_bytes().as_ref()) {
+                f.fail(format!("shadow: {} has no passwd entry", e.sp_namp).as_str());
+            }
+        }
+    }
+    for e in entries.iter().take(ROUND_TRIPS) {
+        let name = e.sp_namp.as_bytes();
+        let mut body = String::new();
+        match routes::shadow_name(&name, &mut body) {
+            Ok(Some(_)) => {},
+            Ok(None) => f.fail(format!("shadow: {} is enumerated, but not found by name", e.sp_namp).as_str()),
+            Err(err) => f.fail(format!("shadow: lookup of {} by name: {}", e.sp_namp, err).as_str()),
+        }
+    }
+    f.ok(format!("shadow: looked up {} entries by name", entries.len().min(ROUND_TRIPS)).as_str());
+}
+
+// check_optional_routes only makes sure the service answers the routes of the optional databases,
+// an unknown map or id is fine
+fn check_optional_routes(f: &mut Findings) {
+    match routes::automount("auto.master") {
+        Ok(_) => f.ok("route automount: answered"),
+        Err(e) => f.warn(format!("route automount: {}", e).as_str()),
+    }
+    let mut body = String::new();
+    match routes::subid_id("uid", 0, &mut body) {
+        Ok(_) => f.ok("route subid: answered"),
+        Err(e) => f.warn(format!("route subid: {}", e).as_str()),
+    }
+}
+
+// check_cache makes sure the cache file of MCACHE_PATH is kept fresh, if there is one
+fn check_cache(f: &mut Findings) {
+    match mcache::status() {
+        Ok(status) => f.ok(format!("cache file: {}", status).as_str()),
+        Err(e) => f.warn(format!("cache file: {}", e).as_str()),
+    }
+}
+
+fn main() {
+    let mut f = Findings {
+        failed: 0,
+        warnings: 0,
+    };
+
+    check_socket(&mut f, SOCKET_PATH, false);
+    check_socket(&mut f, SOCKET_PATH_PRIV, true);
+    for domain in DOMAINS {
+        check_socket(&mut f, domain.socket, false);
+        check_socket(&mut f, domain.socket_priv, true);
+    }
+    check_nsswitch(&mut f);
+    let users = check_passwd(&mut f);
+    check_group(&mut f, users.as_ref());
+    check_shadow(&mut f, users.as_ref());
+    check_optional_routes(&mut f);
+    check_cache(&mut f);
+
+    println!();
+    if f.failed > 0 {
+        println!("{} checks failed, {} warnings", f.failed, f.warnings);
+        exit(1);
+    }
+    println!("all checks passed, {} warnings", f.warnings);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use nss_alexandria::decode;
+    use nss_alexandria::decode::List;
+    use nss_alexandria::domains;
+    use nss_alexandria::types::{AlexandriaPassword, AlexandriaGroup};
+    use super::{Findings, passwd_consistency, group_consistency};
+
+    fn findings() -> Findings {
+        Findings {
+            failed: 0,
+            warnings: 0,
+        }
+    }
+
+    #[test]
+    fn empty_directory_is_consistent() {
+        // a directory without entries answers [] or 404, both of which are an empty list
+        let passwd = domains::merge("passwd", None, |_| Ok(List::empty())).unwrap();
+        let group = domains::merge("group", None, |_| {
+            List::new("[]".to_string(), |b| decode::count::<AlexandriaGroup>(b)).map_err(From::from)
+        }).unwrap();
+        let mut f = findings();
+        let users = passwd_consistency(&mut f, &passwd.entries::<AlexandriaPassword>().unwrap());
+        group_consistency(&mut f, &group.entries::<AlexandriaGroup>().unwrap(), Some(&users));
+        assert_eq!(f.failed, 0);
+    }
+
+    #[test]
+    fn inconsistencies_fail() {
+        let passwd: Vec<AlexandriaPassword> = decode::list(r#"[{"pw_name":"alx-test-alice","pw_uid":1000,"pw_gid":1000,"pw_dir":"/"},
+                                                              {"pw_name":"alx-test-bob","pw_uid":1000,"pw_gid":1000,"pw_dir":"/"}]"#).unwrap();
+        let mut f = findings();
+        let users = passwd_consistency(&mut f, &passwd);
+        assert_eq!(f.failed, 1);
+
+        let group: Vec<AlexandriaGroup> = decode::list(r#"[{"gr_name":"staff","gr_gid":1000,"gr_mem":["alx-test-alice","alx-test-nobody"]}]"#).unwrap();
+        let mut f = findings();
+        group_consistency(&mut f, &group, Some(&users));
+        assert_eq!(f.failed, 1);
+        let mut f = findings();
+        group_consistency(&mut f, &group, Some(&HashSet::new()));
+        assert_eq!(f.failed, 2);
+    }
+}
@@ -0,0 +1,439 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// alexandria-getent asks the Alexandria service like the NSS module does, but without glibc or
+// nscd in between. It prints the entries like getent(1), or the JSON as it was received, and the
+// HTTP status and timing of every request on stderr. With --buffer it also writes every entry into
+// a buffer of the given size the way the NSS module does, to reproduce ERANGE problems.
+//
+// The exit codes are those of getent(1): 1 for wrong arguments, 2 if a key was not found. 3 means
+// that the service failed, 4 that an entry did not fit into the buffer.
+
+extern crate libc;
+extern crate nss_alexandria;
+
+use std::env;
+use std::ffi::CStr;
+use std::io::{self, Write};
+use std::mem::zeroed;
+use std::process::exit;
+use std::ptr::null_mut;
+use std::time::{Duration, Instant};
+use libc::c_char;
+use libc::c_int;
+use libc::passwd;
+use nss_alexandria::routes;
+use nss_alexandria::types::{group, spwd};
+use nss_alexandria::types::nss_status;
+use nss_alexandria::types::{AlexandriaPassword, AlexandriaGroup, AlexandriaShadow, AlexandriaAutomount, AlexandriaSubid, AlexandriaSvcError};
+use nss_alexandria::types::Text;
+use nss_alexandria::util;
+
+const USAGE: &'static str = "usage: alexandria-getent [--json] [--buffer SIZE] DATABASE [KEY...]
+
+databases and keys:
+  passwd [NAME|UID...]
+  group [NAME|GID...]
+  shadow [NAME...]
+  automount MAP [KEY...]
+  subuid OWNER|id=ID...
+  subgid OWNER|id=ID...
+
+Without keys, all entries of the database are enumerated.";
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_NOTFOUND: i32 = 2;
+const EXIT_ERROR: i32 = 3;
+const EXIT_ERANGE: i32 = 4;
+
+struct Options {
+    json: bool,
+    buffer: Option<usize>,
+}
+
+// Run keeps the exit code, the worst result of all keys wins
+struct Run {
+    opts: Options,
+    exit: i32,
+}
+
+impl Run {
+    fn fail(&mut self, code: i32) {
+        if code > self.exit {
+            self.exit = code;
+        }
+    }
+
+    // timed runs a request and prints its HTTP status and how long it took
+    fn timed<T, F>(&mut self, what: &str, f: F) -> Option<T> where F: FnOnce() -> Result<T, AlexandriaSvcError> {
+        let start = Instant::now();
+        let ret = f();
+        let status = match routes::last_status() {
+            Some(status) => status.to_string(),
+            None => "-".to_string(),
+        };
+        eprintln!("# {}: HTTP {}, {:.3} ms", what, status, millis(start.elapsed()));
+        match ret {
+            Ok(v) => Some(v),
+            Err(e) => {
+                eprintln!("# {}: {}", what, e);
+                self.fail(EXIT_ERROR);
+                None
+            },
+        }
+    }
+
+    // print prints one entry as line, read back from the buffer it was written to with --buffer
+    fn print<E, L, W>(&mut self, e: &E, line: L, write: W) where L: Fn(&E) -> Vec<u8>, W: Fn(&E, *mut c_char, usize, *mut c_int) -> (nss_status, Vec<u8>) {
+        let out = match self.opts.buffer {
+            None => line(e),
+            Some(size) => {
+                let mut buffer = vec![0u8; size];
+                let mut errno: c_int = 0;
+                match write(e, buffer.as_mut_ptr() as *mut c_char, size, &mut errno) {
+                    (nss_status::NSS_STATUS_SUCCESS, line) => line,
+                    (status, _) => {
+                        eprintln!("# {} bytes are not enough: {} (errno {})", size, status_name(&status), errno);
+                        self.fail(EXIT_ERANGE);
+                        return;
+                    },
+                }
+            },
+        };
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = stdout.write_all(&out);
+        let _ = stdout.write_all(b"\n");
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1000000.0
+}
+
+fn status_name(status: &nss_status) -> &'static str {
+    match *status {
+        nss_status::NSS_STATUS_TRYAGAIN => "NSS_STATUS_TRYAGAIN",
+        nss_status::NSS_STATUS_UNAVAIL => "NSS_STATUS_UNAVAIL",
+        nss_status::NSS_STATUS_NOTFOUND => "NSS_STATUS_NOTFOUND",
+        nss_status::NSS_STATUS_SUCCESS => "NSS_STATUS_SUCCESS",
+        nss_status::NSS_STATUS_RETURN => "NSS_STATUS_RETURN",
+    }
+}
+
+// line joins fields with ':' like the files in /etc do
+fn line(fields: &[&[u8]]) -> Vec<u8> {
+    let mut v = vec![];
+    for (i, f) in fields.iter().enumerate() {
+        if i > 0 {
+            v.push(b':');
+        }
+        v.extend_from_slice(f);
+    }
+    v
+}
+
+fn text(t: &Text) -> Vec<u8> {
+    t.as_bytes().into_owned()
+}
+
+fn cstr(p: *const c_char) -> Vec<u8> {
+    if p.is_null() {
+        return vec![];
+    }
+    unsafe { CStr::from_ptr(p) }.to_bytes().to_vec()
+}
+
+// aging prints the shadow aging fields like shadow(5), where -1 is an empty field
+fn aging(n: i64) -> Vec<u8> {
+    if n == -1 { vec![] } else { n.to_string().into_bytes() }
+}
+
+fn passwd_line(e: &AlexandriaPassword) -> Vec<u8> {
+    line(&[&text(&e.pw_name), &text(&e.pw_passwd), e.pw_uid.to_string().as_bytes(), e.pw_gid.to_string().as_bytes(), &text(&e.pw_gecos), &text(&e.pw_dir), &text(&e.pw_shell)])
+}
+
+fn passwd_write(e: &AlexandriaPassword, buffer: *mut c_char, buflen: usize, errnop: *mut c_int) -> (nss_status, Vec<u8>) {
+    let mut pwd: passwd = unsafe { zeroed() };
+    let status = util::write_passwd(e, &mut pwd, buffer, buflen, errnop);
+    let l = line(&[&cstr(pwd.pw_name), &cstr(pwd.pw_passwd), pwd.pw_uid.to_string().as_bytes(), pwd.pw_gid.to_string().as_bytes(), &cstr(pwd.pw_gecos), &cstr(pwd.pw_dir), &cstr(pwd.pw_shell)]);
+    (status, l)
+}
+
+fn group_line(e: &AlexandriaGroup) -> Vec<u8> {
+    let members: Vec<Vec<u8>> = e.gr_mem.iter().map(text).collect();
+    line(&[&text(&e.gr_name), &text(&e.gr_passwd), e.gr_gid.to_string().as_bytes(), &members.join(&b',')])
+}
+
+fn group_write(e: &AlexandriaGroup, buffer: *mut c_char, buflen: usize, errnop: *mut c_int) -> (nss_status, Vec<u8>) {
+    let mut grp: group = unsafe { zeroed() };
+    let status = util::write_group(e, &mut grp, buffer, buflen, errnop);
+    let mut members = vec![];
+    if !grp.gr_mem.is_null() {
+        let mut i = 0;
+        loop {
+            let mem = unsafe { *grp.gr_mem.offset(i) };
+            if mem.is_null() {
+                break;
+            }
+            members.push(cstr(mem));
+            i += 1;
+        }
+    }
+    let l = line(&[&cstr(grp.gr_name), &cstr(grp.gr_passwd), grp.gr_gid.to_string().as_bytes(), &members.join(&b',')]);
+    (status, l)
+}
+
+fn shadow_line(e: &AlexandriaShadow) -> Vec<u8> {
+    line(&[&text(&e.sp_namp), &text(&e.sp_pwdp), &aging(e.sp_lstchg), &aging(e.sp_min), &aging(e.sp_max), &aging(e.sp_warn), &aging(e.sp_inact), &aging(e.sp_expire), &aging(e.sp_flag as i64)])
+}
+
+fn shadow_write(e: &AlexandriaShadow, buffer: *mut c_char, buflen: usize, errnop: *mut c_int) -> (nss_status, Vec<u8>) {
+    let mut sp: spwd = unsafe { zeroed() };
+    let status = util::write_shadow(e, &mut sp, buffer, buflen, errnop);
+    let l = line(&[&cstr(sp.sp_namp), &cstr(sp.sp_pwdp), &aging(sp.sp_lstchg as i64), &aging(sp.sp_min as i64), &aging(sp.sp_max as i64), &aging(sp.sp_warn as i64), &aging(sp.sp_inact as i64), &aging(sp.sp_expire as i64), &aging(sp.sp_flag as i64)]);
+    (status, l)
+}
+
+fn automount_line(e: &AlexandriaAutomount) -> Vec<u8> {
+    let mut l = text(&e.am_key);
+    l.push(b'\t');
+    l.extend_from_slice(&text(&e.am_value));
+    l
+}
+
+fn automount_write(e: &AlexandriaAutomount, buffer: *mut c_char, buflen: usize, errnop: *mut c_int) -> (nss_status, Vec<u8>) {
+    let (mut key, mut value): (*mut c_char, *mut c_char) = (null_mut(), null_mut());
+    let status = util::write_automount(e, &mut key, &mut value, buffer, buflen, errnop);
+    let mut l = cstr(key);
+    l.push(b'\t');
+    l.extend_from_slice(&cstr(value));
+    (status, l)
+}
+
+// subid entries are handed to libsubid in malloc'ed arrays, there is no buffer to write them to
+fn subid_line(e: &AlexandriaSubid) -> Vec<u8> {
+    line(&[&text(&e.owner), e.start.to_string().as_bytes(), e.count.to_string().as_bytes()])
+}
+
+fn subid_write(e: &AlexandriaSubid, _buffer: *mut c_char, _buflen: usize, _errnop: *mut c_int) -> (nss_status, Vec<u8>) {
+    (nss_status::NSS_STATUS_SUCCESS, subid_line(e))
+}
+
+// enumerate prints all entries, or the response bodies they were decoded from for --json
+fn enumerate<E, L, W>(run: &mut Run, what: &str, bodies: &[&str], entries: Result<Vec<E>, AlexandriaSvcError>, line: L, write: W) where L: Fn(&E) -> Vec<u8>, W: Fn(&E, *mut c_char, usize, *mut c_int) -> (nss_status, Vec<u8>) {
+    if run.opts.json {
+        // an empty list has no body
+        for body in bodies {
+            println!("{}", if body.is_empty() { "[]" } else { body });
+        }
+        return;
+    }
+    match entries {
+        Ok(entries) => {
+            for e in &entries {
+                run.print(e, &line, &write);
+            }
+            eprintln!("# {}: {} entries", what, entries.len());
+        },
+        Err(e) => {
+            eprintln!("# {}: {}", what, e);
+            run.fail(EXIT_ERROR);
+        },
+    }
+}
+
+// found prints the entry of a lookup by key, if there is one. It returns whether there was one,
+// the caller prints the body for --json once the entry doesn't borrow it anymore.
+fn found<E, L, W>(run: &mut Run, what: &str, entry: Option<Option<E>>, line: L, write: W) -> bool where L: Fn(&E) -> Vec<u8>, W: Fn(&E, *mut c_char, usize, *mut c_int) -> (nss_status, Vec<u8>) {
+    match entry {
+        // the request failed, which timed() reported already
+        None => false,
+        Some(None) => {
+            eprintln!("# {}: not found", what);
+            run.fail(EXIT_NOTFOUND);
+            false
+        },
+        Some(Some(e)) => {
+            if !run.opts.json {
+                run.print(&e, line, write);
+            }
+            true
+        },
+    }
+}
+
+fn passwd(run: &mut Run, keys: &[String]) {
+    if keys.is_empty() {
+        if let Some(list) = run.timed("passwd", || routes::passwd(None)) {
+            enumerate(run, "passwd", &list.bodies(), list.entries::<AlexandriaPassword>(), passwd_line, passwd_write);
+        }
+        return;
+    }
+    for key in keys {
+        let what = format!("passwd {}", key);
+        let mut body = String::new();
+        let entry = match key.parse::<u32>() {
+            Ok(uid) => run.timed(&what, || routes::passwd_uid(uid, &mut body)),
+            Err(_) => run.timed(&what, || routes::passwd_name(key.as_bytes(), &mut body)),
+        };
+        if found(run, &what, entry, passwd_line, passwd_write) && run.opts.json {
+            println!("{}", body);
+        }
+    }
+}
+
+fn group(run: &mut Run, keys: &[String]) {
+    if keys.is_empty() {
+        if let Some(list) = run.timed("group", || routes::group(None)) {
+            enumerate(run, "group", &list.bodies(), list.entries::<AlexandriaGroup>(), group_line, group_write);
+        }
+        return;
+    }
+    for key in keys {
+        let what = format!("group {}", key);
+        let mut body = String::new();
+        let entry = match key.parse::<u32>() {
+            Ok(gid) => run.timed(&what, || routes::group_gid(gid, &mut body)),
+            Err(_) => run.timed(&what, || routes::group_name(key.as_bytes(), &mut body)),
+        };
+        if found(run, &what, entry, group_line, group_write) && run.opts.json {
+            println!("{}", body);
+        }
+    }
+}
+
+fn shadow(run: &mut Run, keys: &[String]) {
+    if keys.is_empty() {
+        if let Some(list) = run.timed("shadow", || routes::shadow(None)) {
+            enumerate(run, "shadow", &list.bodies(), list.entries::<AlexandriaShadow>(), shadow_line, shadow_write);
+        }
+        return;
+    }
+    for key in keys {
+        let what = format!("shadow {}", key);
+        let mut body = String::new();
+        let entry = run.timed(&what, || routes::shadow_name(key.as_bytes(), &mut body));
+        if found(run, &what, entry, shadow_line, shadow_write) && run.opts.json {
+            println!("{}", body);
+        }
+    }
+}
+
+fn automount(run: &mut Run, keys: &[String]) {
+    let (map, keys) = match keys.split_first() {
+        Some((map, keys)) => (map, keys),
+        None => usage(),
+    };
+    if keys.is_empty() {
+        let what = format!("automount {}", map);
+        if let Some(list) = run.timed(&what, || routes::automount(map)) {
+            enumerate(run, &what, &[list.body()], list.entries::<AlexandriaAutomount>().map_err(AlexandriaSvcError::from), automount_line, automount_write);
+        }
+        return;
+    }
+    for key in keys {
+        let what = format!("automount {} {}", map, key);
+        let mut body = String::new();
+        let entry = run.timed(&what, || routes::automount_key(map, key, &mut body));
+        if found(run, &what, entry, automount_line, automount_write) && run.opts.json {
+            println!("{}", body);
+        }
+    }
+}
+
+fn subid(run: &mut Run, kind: &str, keys: &[String]) {
+    if keys.is_empty() {
+        usage();
+    }
+    for key in keys {
+        let what = format!("sub{} {}", kind, key);
+        let mut body = String::new();
+        let entries = if key.starts_with("id=") {
+            match key[3..].parse::<u64>() {
+                Ok(id) => run.timed(&what, || routes::subid_id(kind, id, &mut body)),
+                Err(_) => usage(),
+            }
+        } else {
+            run.timed(&what, || routes::subid_owner(kind, key.as_bytes(), &mut body))
+        };
+        let n = match entries {
+            None => continue,
+            Some(entries) => {
+                if !run.opts.json {
+                    for e in &entries {
+                        run.print(e, subid_line, subid_write);
+                    }
+                }
+                entries.len()
+            },
+        };
+        if n == 0 {
+            eprintln!("# {}: not found", what);
+            run.fail(EXIT_NOTFOUND);
+            continue;
+        }
+        if run.opts.json {
+            println!("{}", body);
+        }
+        eprintln!("# {}: {} entries", what, n);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(EXIT_USAGE);
+}
+
+fn main() {
+    let mut opts = Options {
+        json: false,
+        buffer: None,
+    };
+    let mut args = env::args().skip(1);
+    let mut positional = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => opts.json = true,
+            "--buffer" => opts.buffer = match args.next().and_then(|n| n.parse().ok()) {
+                Some(size) => Some(size),
+                None => usage(),
+            },
+            "-h" | "--help" => usage(),
+            _ => positional.push(arg),
+        }
+    }
+    let (database, keys) = match positional.split_first() {
+        Some((database, keys)) => (database.clone(), keys.to_vec()),
+        None => usage(),
+    };
+
+    let mut run = Run {
+        opts: opts,
+        exit: 0,
+    };
+    match database.as_str() {
+        "passwd" => passwd(&mut run, &keys),
+        "group" => group(&mut run, &keys),
+        "shadow" => shadow(&mut run, &keys),
+        "automount" => automount(&mut run, &keys),
+        "subuid" => subid(&mut run, "uid", &keys),
+        "subgid" => subid(&mut run, "gid", &keys),
+        _ => usage(),
+    }
+    exit(run.exit);
+}
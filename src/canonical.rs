@@ -0,0 +1,113 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// Canonical names, see config::NAME_FOLD_CASE.
+//
+// Every name has two canonical forms: the one the directory knows, which lookups ask for, and the
+// one which is served on this host. They only differ with NAME_QUALIFY.
+
+use std::borrow::Cow;
+use config::{NAME_FOLD_CASE, DEFAULT_DOMAIN, NAME_QUALIFY};
+use types::AlexandriaPassword;
+use types::AlexandriaGroup;
+use types::AlexandriaShadow;
+use types::Text;
+
+// split returns the user and the domain of a name in the form user@domain or DOMAIN\user, and
+// no domain for any other name
+fn split(name: &[u8]) -> (&[u8], Option<&[u8]>) {
+    if let Some(at) = name.iter().rposition(|&b| b == b'@') {
+        return (&name[..at], Some(&name[at + 1..]));
+    }
+    if let Some(bs) = name.iter().position(|&b| b == b'\\') {
+        return (&name[bs + 1..], Some(&name[..bs]));
+    }
+    (name, None)
+}
+
+fn is_default(domain: &[u8]) -> bool {
+    DEFAULT_DOMAIN.map_or(false, |d| d.as_bytes().eq_ignore_ascii_case(domain))
+}
+
+// lookup returns name in the form the directory knows it
+pub fn lookup(name: &[u8]) -> Cow<[u8]> {
+    let mut canonical = match split(name) {
+        (_, None) if !NAME_FOLD_CASE => return Cow::Borrowed(name),
+        (user, None) => user.to_vec(),
+        (user, Some(domain)) if is_default(domain) => user.to_vec(),
+        (user, Some(domain)) => {
+            let mut v = Vec::with_capacity(name.len());
+            v.extend_from_slice(user);
+            v.push(b'@');
+            v.extend_from_slice(domain);
+            v
+        },
+    };
+    if NAME_FOLD_CASE {
+        canonical.make_ascii_lowercase();
+    }
+    Cow::Owned(canonical)
+}
+
+// host returns name in the form it is served on this host
+pub fn host(name: &[u8]) -> Cow<[u8]> {
+    let name = lookup(name);
+    match DEFAULT_DOMAIN {
+        Some(domain) if NAME_QUALIFY && !name.contains(&b'@') => {
+            let mut v = name.into_owned();
+            v.push(b'@');
+            v.extend_from_slice(domain.as_bytes());
+            if NAME_FOLD_CASE {
+                v.make_ascii_lowercase();
+            }
+            Cow::Owned(v)
+        },
+        _ => name,
+    }
+}
+
+// rename replaces a name sent by the directory with its host form, unless it is in that form
+fn rename(name: &mut Text) {
+    let renamed = {
+        let bytes = name.as_bytes();
+        match host(&bytes) {
+            Cow::Owned(v) if v.as_slice() != &*bytes => Some(v),
+            _ => None,
+        }
+    };
+    if let Some(v) = renamed {
+        *name = Text::Bytes(v);
+    }
+}
+
+pub fn passwd(mut entry: AlexandriaPassword) -> AlexandriaPassword {
+    rename(&mut entry.pw_name);
+    entry
+}
+
+pub fn group(mut entry: AlexandriaGroup) -> AlexandriaGroup {
+    rename(&mut entry.gr_name);
+    for member in entry.gr_mem.iter_mut() {
+        rename(member);
+    }
+    entry
+}
+
+pub fn shadow(mut entry: AlexandriaShadow) -> AlexandriaShadow {
+    rename(&mut entry.sp_namp);
+    entry
+}
@@ -15,10 +15,188 @@
 // You should have received a copy of the GNU General Public License
 // along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
 
+use libc::uid_t;
+use libc::gid_t;
+use types::NamePolicy;
+use types::MetricsSink;
+use types::IdMap;
+use types::Domain;
+use types::DomainDown;
+use types::Enumerate;
+use types::GenerationSource;
+
 pub const SOCKET_PATH: &'static str = "/var/lib/alexandria/nss.sock";
 pub const SOCKET_PATH_PRIV: &'static str = "/var/lib/alexandria/nss_priv.sock";
+// further directories, e.g. of another organization, each with an Alexandria service of its own:
+// &[Domain { name: "other.example", socket: "/var/lib/alexandria/other.sock",
+//            socket_priv: "/var/lib/alexandria/other_priv.sock",
+//            uids: &[(200000, 299999)], gids: &[(200000, 299999)] }]
+// A name user@other.example is looked up as user in that domain, and its users and groups are
+// served as user@other.example. Ids in its ranges are looked up there too; they are the ids as the
+// directories send them, before UID_MAP and GID_MAP. Everything else goes to the default directory
+// at SOCKET_PATH. Enumerations merge the entries of all directories, and with DOMAIN_DOWN Skip they
+// go on without a directory which is down. Lookups for a domain which is down always fail with
+// NSS_STATUS_TRYAGAIN.
+pub const DOMAINS: &'static [Domain] = &[];
+pub const DOMAIN_DOWN: DomainDown = DomainDown::Fail;
 pub const PASSWD_URL: &'static str = "/passwd";
 pub const GROUP_URL: &'static str = "/group";
 pub const SHADOW_URL: &'static str = "/shadow";
+pub const AUTOMOUNT_URL: &'static str = "/automount";
+pub const SUBID_URL: &'static str = "/subid";
+pub const METRICS_URL: &'static str = "/metrics";
+pub const GENERATION_URL: &'static str = "/generation";
+// callers which may read the shadow database, by effective uid, or by effective gid or
+// supplementary group. Groups can be given by name as well, which are looked up in /etc/group.
+// The privileged socket must be accessible to them too, e.g. add "shadow" on distributions whose
+// setgid helpers like unix_chkpwd run with the shadow group.
+pub const SHADOW_ALLOW_UIDS: &'static [uid_t] = &[0];
+pub const SHADOW_ALLOW_GIDS: &'static [gid_t] = &[];
+pub const SHADOW_ALLOW_GROUPS: &'static [&'static str] = &[];
+// the directory may only serve uids and gids in these ranges (inclusive), and no names of
+// DENY_NAMES, so that a misconfigured entry can't shadow root or a system account. The default
+// ranges only leave out 0 and nobody (65534), e.g. &[(1000, 65533), (65535, 4294967294)] leaves out
+// all ids below 1000 as well. With REJECT_LOCAL_CONFLICTS, entries whose name or id is in
+// /etc/passwd or /etc/group are not served either. The entries of the overlay are not filtered.
+pub const ALLOW_UIDS: &'static [(uid_t, uid_t)] = &[(1, 65533), (65535, 4294967294)];
+pub const ALLOW_GIDS: &'static [(gid_t, gid_t)] = &[(1, 65533), (65535, 4294967294)];
+// the primary group of a user is often a system group like users (100), so ALLOW_GIDS only applies
+// to the pw_gid of passwd entries with FILTER_PRIMARY_GID
+pub const FILTER_PRIMARY_GID: bool = false;
+// names of the system accounts, which are denied for users and groups alike, as every one of them
+// has a group of the same name on some distribution
+pub const DENY_NAMES: &'static [&'static str] = &[
+    "root", "bin", "daemon", "adm", "lp", "sync", "shutdown", "halt", "mail", "news", "uucp",
+    "operator", "games", "ftp", "man", "proxy", "www-data", "backup", "list", "nobody", "sshd",
+    "systemd-network", "systemd-resolve", "messagebus", "polkitd",
+];
+// names which are only denied for groups. Directories often have groups like users or staff, so
+// there are none by default, e.g. &["wheel", "sudo", "admin", "shadow", "disk", "kmem", "tty"] keeps
+// the directory from granting the privileges of these groups.
+pub const DENY_GROUP_NAMES: &'static [&'static str] = &[];
+pub const REJECT_LOCAL_CONFLICTS: bool = false;
+// uids and gids of the directory can be translated into other ones on this host, e.g.
+// &[IdMap::Offset(100000)] for a user-namespaced container, or
+// &[IdMap::Range { directory: 5000, host: 20000, count: 1000 }] for a legacy NFS id space.
+// Lookups by id are translated back before they are sent. Once there are rules, ids which none of
+// them covers don't exist on this host. Rules must not overlap, neither on the side of the
+// directory nor on the side of this host, so that every id translates back to where it came from.
+// The filters and the overlay work with the ids of this host.
+pub const UID_MAP: &'static [IdMap] = &[];
+pub const GID_MAP: &'static [IdMap] = &[];
+// templates for the home directory, shell and gecos of the users of the directory, whatever it
+// says, e.g. Some("/scratch/%u"). None keeps the directory's value. A template can contain
+// %u  the user name
+// %U  the uid
+// %d  the domain, which is the part of the user name after an '@', if any
+// %l  the first letter of the user name
+// %o  the directory's value
+// %%  a '%'
+// The rewrites are (regex, replacement) pairs which are applied in order afterwards, e.g.
+// ("^/home/", "/nfs/home/"). The replacement can refer to groups of the regex as $1, $2 etc.
+// The entries of the overlay are served as they are.
+pub const HOME_TEMPLATE: Option<&'static str> = None;
+pub const HOME_REWRITES: &'static [(&'static str, &'static str)] = &[];
+pub const SHELL_TEMPLATE: Option<&'static str> = None;
+pub const SHELL_REWRITES: &'static [(&'static str, &'static str)] = &[];
+pub const GECOS_TEMPLATE: Option<&'static str> = None;
+pub const GECOS_REWRITES: &'static [(&'static str, &'static str)] = &[];
+// the shell of users whose shell is not in /etc/shells, e.g. Some("/bin/bash") for a template
+// like "/usr/local/bin/rbash" which is not installed everywhere. None serves any shell.
+pub const FALLBACK_SHELL: Option<&'static str> = None;
+// groups of the directory can list groups in gr_groups whose members are members too, e.g. a team
+// which consists of smaller teams. With NESTED_GROUPS, getgrnam, getgrgid, getgrent and initgroups
+// serve them as members, and the members of their groups, down to NESTED_GROUPS_DEPTH levels. A
+// group which turns up again, e.g. in a cycle, only counts once. Without it, gr_groups is ignored.
+// The groups of the overlay are served as they are, but the overlay applies to nested groups.
+pub const NESTED_GROUPS: bool = false;
+pub const NESTED_GROUPS_DEPTH: usize = 8;
 pub const HTTP_READ_TIMEOUT_MS: u64 = 100;
 pub const HTTP_WRITE_TIMEOUT_MS: u64 = 100;
+// how long a list fetched for an enumeration stays fresh. A set*ent within that time rewinds the
+// list instead of fetching it again, which end*ent keeps if the enumeration was opened with
+// stayopen, together with the connection to the Alexandria service.
+pub const STAYOPEN_TTL_S: u64 = 30;
+// a cache file which lookups try before they ask the Alexandria service, so that short-lived
+// processes like ls, id and ps don't need a request for every user. alexandria-cache writes it,
+// with the passwd and group entries of the default directory, which are not found in it anymore
+// once alexandria-cache didn't replace it for MCACHE_TTL_S. Entries missing from it are requested
+// as usual. E.g. Some("/var/lib/alexandria/nss.cache")
+pub const MCACHE_PATH: Option<&'static str> = None;
+pub const MCACHE_TTL_S: u64 = 300;
+// the Alexandria service counts up a generation whenever a user or group of its directory changes.
+// With GENERATION, the enumeration lists kept by STAYOPEN_TTL_S and the cache file of MCACHE_PATH
+// are only used while the generation is the one they were fetched at, so a deleted user or a
+// changed membership shows within GENERATION_CHECK_MS. The generation is GET from GENERATION_URL,
+// or read from GENERATION_PATH, which is cheaper. The one of a domain of DOMAINS is read from
+// GENERATION_PATH.<domain>. While it can't be read, caches are used until they expire as without
+// it.
+pub const GENERATION: GenerationSource = GenerationSource::Off;
+pub const GENERATION_PATH: &'static str = "/run/alexandria/generation";
+// how long a generation which was read is used before it is read again, so that lookups in the
+// cache file and enumerations don't each cost a request or a read. A change shows this much later.
+// With 0 it is read for every check, so that a change shows right away.
+pub const GENERATION_CHECK_MS: u64 = 1000;
+// how much of each database an enumeration (setpwent/getpwent etc.) serves. With tens of
+// thousands of users, every process which enumerates would fetch the whole directory, so it can be
+// Enumerate::Disabled, or Enumerate::Capped(n) for at most n entries of the directory, which the
+// service is asked for with a limit parameter as well. The entries added by the overlay are always
+// served. If ENUMERATE_ALLOW_COMM is not empty, only processes of these names (see
+// /proc/self/comm) enumerate at all. initgroups is not an enumeration, it asks the service for the
+// groups of the user with a member parameter, and with NESTED_GROUPS for the groups of those.
+pub const PASSWD_ENUMERATE: Enumerate = Enumerate::Full;
+pub const GROUP_ENUMERATE: Enumerate = Enumerate::Full;
+pub const SHADOW_ENUMERATE: Enumerate = Enumerate::Full;
+pub const ENUMERATE_ALLOW_COMM: &'static [&'static str] = &[];
+
+// names which are not valid UTF-8 (e.g. Latin-1) are not found by default. With
+// NamePolicy::PercentEncode they are forwarded to the Alexandria service percent-encoded instead,
+// which then has to send them back percent-encoded as well.
+pub const NAME_POLICY: NamePolicy = NamePolicy::NotFound;
+
+// names are canonicalized before they are looked up, and the names of the directory's entries
+// are served canonicalized, so that PAM and sshd always see the same name for a user:
+// - with NAME_FOLD_CASE, ASCII letters are folded to lower case: Alice is alice
+// - alice@CORP and CORP\alice are alice if CORP is the DEFAULT_DOMAIN, which is compared
+//   case-insensitively. Other domains are looked up as alice@OTHER.
+// - with NAME_QUALIFY, names of the DEFAULT_DOMAIN are served as alice@CORP instead, but still
+//   looked up as alice
+// The overlay uses the names as they are served, the filters the ones the directory knows.
+pub const NAME_FOLD_CASE: bool = false;
+pub const DEFAULT_DOMAIN: Option<&'static str> = None;
+pub const NAME_QUALIFY: bool = false;
+
+// user and group names may only consist of these ASCII characters, and of non-ASCII bytes if
+// NAME_ALLOW_NON_ASCII is set. Lookups of any other name are not found without asking the
+// Alexandria service.
+pub const NAME_CHARS: &'static str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789._-@$";
+pub const NAME_ALLOW_NON_ASCII: bool = true;
+
+// headers sent along with every request, so that the Alexandria service can correlate its log
+// with ours and audit or rate-limit lookups per program. Turn off the ones which reveal more about
+// the calling process than the service should know.
+pub const HEADER_REQUEST_ID: bool = true;
+pub const HEADER_PID: bool = true;
+pub const HEADER_COMM: bool = true;
+pub const HEADER_EXE: bool = true;
+pub const HEADER_DATABASE: bool = true;
+pub const HEADER_ENTRY_POINT: bool = true;
+
+// every process counts its lookups, results and latencies. They are flushed every
+// METRICS_FLUSH_INTERVAL_S to the metrics route of the Alexandria service, or to
+// METRICS_DIR/<pid>.json. The directory must exist and be writable by all users with the sticky
+// bit set (mode 1777, like /tmp), so that nobody can replace the files of other users' processes.
+// Without the sticky bit, no metrics are written.
+// The flush runs in the lookup which is due, after it has its result but before it returns, so
+// that lookup takes longer: by a file write, or by up to METRICS_TIMEOUT_MS for sending and again
+// for the response of a daemon which is slow. A daemon whose socket doesn't accept connections
+// anymore can hold it up for longer still, so MetricsSink::File is the safer choice.
+pub const METRICS_SINK: MetricsSink = MetricsSink::Off;
+pub const METRICS_FLUSH_INTERVAL_S: u64 = 60;
+pub const METRICS_TIMEOUT_MS: u64 = 20;
+pub const METRICS_DIR: &'static str = "/run/nss_alexandria";
+
+// local exceptions from the directory, see overlay.rs. Changes to the files are picked up after at
+// most OVERLAY_CHECK_INTERVAL_S.
+pub const OVERLAY_DIR: &'static str = "/etc/nss_alexandria.d";
+pub const OVERLAY_CHECK_INTERVAL_S: u64 = 1;
@@ -0,0 +1,164 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// The context of a request tells the Alexandria service who is asking and why: every request
+// carries an ID, which also appears in our log, the pid, name and executable of the calling
+// process, the database and the exported function which was called. Each of these headers can be
+// turned off in config.rs.
+
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::io::Read;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use libc::getpid;
+use hyper::header::Headers;
+use config::{HEADER_REQUEST_ID, HEADER_PID, HEADER_COMM, HEADER_EXE, HEADER_DATABASE, HEADER_ENTRY_POINT};
+use names;
+use util;
+
+// the exported function the current thread is in, set by util::catch
+thread_local!(static ENTRY_POINT: Cell<&'static str> = Cell::new(""));
+
+static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+// enter records that the current thread entered func, and returns what it was in before
+pub fn enter(func: &'static str) -> &'static str {
+    ENTRY_POINT.with(|e| e.replace(func.trim_start_matches("_nss_alexandria_")))
+}
+
+// leave restores what enter returned
+pub fn leave(previous: &'static str) {
+    ENTRY_POINT.with(|e| e.set(previous));
+}
+
+// entry_point returns the exported function the current thread is in, "" outside of them
+pub fn entry_point() -> &'static str {
+    ENTRY_POINT.with(|e| e.get())
+}
+
+// request_id returns a new ID which is unique across processes: pid, time and a counter
+pub fn request_id() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{:x}-{:x}{:08x}-{:x}", unsafe { getpid() }, now.as_secs(), now.subsec_nanos(), REQUESTS.fetch_add(1, Ordering::Relaxed))
+}
+
+// Process is the name and executable of the calling process, which are read once instead of for
+// every request. A forked child reads them again, it may rename itself before it asks.
+struct Process {
+    comm: Option<Vec<u8>>,
+    exe: Option<String>,
+}
+
+static PROCESS: Mutex<Option<Arc<Process>>> = Mutex::new(None);
+
+fn lock() -> MutexGuard<'static, Option<Arc<Process>>> {
+    util::atfork_once();
+    PROCESS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn process() -> Arc<Process> {
+    let mut process = lock();
+    if let Some(ref p) = *process {
+        return p.clone();
+    }
+    let mut comm = vec![];
+    let comm = match fs::File::open("/proc/self/comm").and_then(|mut f| f.read_to_end(&mut comm)) {
+        Ok(_) => {
+            if comm.last() == Some(&b'\n') {
+                comm.pop();
+            }
+            Some(comm)
+        },
+        Err(_) => None,
+    };
+    let p = Arc::new(Process {
+        comm: comm,
+        exe: fs::read_link("/proc/self/exe").ok().map(|exe| exe.to_string_lossy().into_owned()),
+    });
+    *process = Some(p.clone());
+    p
+}
+
+// comm returns the name of the calling process
+pub fn comm() -> Option<Vec<u8>> {
+    process().comm.clone()
+}
+
+// headers returns the headers for a request with id to database
+pub fn headers(id: &str, database: &str) -> Headers {
+    let mut headers = Headers::new();
+    if HEADER_REQUEST_ID {
+        set(&mut headers, "X-Alexandria-Request-Id", id.as_bytes());
+    }
+    if HEADER_PID {
+        set(&mut headers, "X-Alexandria-Pid", unsafe { getpid() }.to_string().as_bytes());
+    }
+    if HEADER_COMM {
+        if let Some(ref comm) = process().comm {
+            set(&mut headers, "X-Alexandria-Comm", comm);
+        }
+    }
+    if HEADER_EXE {
+        if let Some(ref exe) = process().exe {
+            set(&mut headers, "X-Alexandria-Exe", exe.as_bytes());
+        }
+    }
+    if HEADER_DATABASE {
+        set(&mut headers, "X-Alexandria-Database", database.as_bytes());
+    }
+    if HEADER_ENTRY_POINT {
+        let func = entry_point();
+        if !func.is_empty() {
+            set(&mut headers, "X-Alexandria-Entry-Point", func.as_bytes());
+        }
+    }
+    headers
+}
+
+// set adds a header. Process names and paths can contain anything, so every byte which is not
+// printable ASCII, and every '%', is percent-encoded.
+fn set(headers: &mut Headers, name: &'static str, value: &[u8]) {
+    let mut v = String::with_capacity(value.len());
+    for &b in value {
+        match b {
+            0x20..=0x7e if b != b'%' => v.push(b as char),
+            _ => names::push_escaped(&mut v, b),
+        }
+    }
+    headers.set_raw(name, vec![v.into_bytes()]);
+}
+
+// The lock is taken before a fork like the ones in lib.rs, and the child forgets the process.
+thread_local!(static FORK_GUARD: RefCell<Option<MutexGuard<'static, Option<Arc<Process>>>>> = RefCell::new(None));
+
+pub fn atfork_prepare() {
+    FORK_GUARD.with(|guard| *guard.borrow_mut() = Some(lock()));
+}
+
+pub fn atfork_parent() {
+    FORK_GUARD.with(|guard| guard.borrow_mut().take());
+}
+
+pub fn atfork_child() {
+    FORK_GUARD.with(|guard| {
+        if let Some(mut process) = guard.borrow_mut().take() {
+            *process = None;
+        }
+    });
+}
@@ -0,0 +1,786 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// Decoding of the JSON payloads of the Alexandria service.
+//
+// The derived RustcDecodable implementations reject a whole payload as soon as a single field is
+// missing or does not fit the exact Rust type, which ties every release of this library to a
+// release of alexandriad. Instead, the types implement FromJson on top of the Parser below:
+// - unknown fields are ignored
+// - optional fields fall back to a default when they are missing or null
+// - numbers are accepted in any JSON representation as long as they fit the target type
+// - everything else is rejected with a PayloadError naming the type, the field and the reason
+//
+// The parser never allocates: strings are handed out as Text borrowing from the response body,
+// still escaped, and are only unescaped when they are written into the caller's buffer. The only
+// exception are percent-encoded names, see names.rs.
+
+use std::error;
+use std::fmt;
+use std::char;
+use std::str;
+use names;
+use types::Text;
+use types::AlexandriaSvcError;
+
+// nesting limit for skipping unknown values, so that a hostile payload can't overflow the stack
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug)]
+pub struct PayloadError {
+    // the name of the type which was decoded, e.g. AlexandriaPassword
+    pub type_name: &'static str,
+    // the path to the offending value, e.g. [3].pw_uid
+    pub path: String,
+    pub reason: String,
+}
+
+impl PayloadError {
+    fn new(type_name: &'static str, path: &str, reason: String) -> PayloadError {
+        PayloadError {
+            type_name: type_name,
+            path: path.to_string(),
+            reason: reason,
+        }
+    }
+
+    // in_element prefixes the path with the index of the array element the error occured in
+    fn in_element(mut self, index: usize) -> PayloadError {
+        self.path = if self.path.is_empty() {
+            format!("[{}]", index)
+        } else {
+            format!("[{}].{}", index, self.path)
+        };
+        self
+    }
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}: {}", self.type_name, self.reason)
+        } else {
+            write!(f, "{}{}{}: {}", self.type_name, if self.path.starts_with('[') { "" } else { "." }, self.path, self.reason)
+        }
+    }
+}
+
+impl error::Error for PayloadError {
+    fn description(&self) -> &str {
+        "invalid payload from Alexandria service"
+    }
+}
+
+pub trait FromJson<'a>: Sized {
+    fn from_json(p: &mut Parser<'a>) -> Result<Self, PayloadError>;
+}
+
+// from_str decodes a response body holding a single T
+pub fn from_str<'a, T: FromJson<'a>>(src: &'a str) -> Result<T, AlexandriaSvcError> {
+    let mut p = Parser::new(src);
+    let entry = try!(T::from_json(&mut p));
+    try!(p.end());
+    Ok(entry)
+}
+
+// list decodes a response body holding an array of T. Prefer List for enumerations.
+pub fn list<'a, T: FromJson<'a>>(src: &'a str) -> Result<Vec<T>, AlexandriaSvcError> {
+    let mut entries = vec![];
+    let mut p = Parser::new(src);
+    if !p.null() {
+        try!(p.array(|p, _| {
+            entries.push(try!(T::from_json(p)));
+            Ok(())
+        }));
+    }
+    try!(p.end());
+    Ok(entries)
+}
+
+// count checks that every element of the array in src decodes as a T and counts them
+pub fn count<'a, T: FromJson<'a>>(src: &'a str) -> Result<usize, PayloadError> {
+    let mut n = 0;
+    let mut p = Parser::new(src);
+    if !p.null() {
+        try!(p.array(|p, _| {
+            try!(T::from_json(p));
+            n += 1;
+            Ok(())
+        }));
+    }
+    try!(p.end());
+    Ok(n)
+}
+
+// List is a JSON array received from the Alexandria service. It owns the response body and
+// decodes one element at a time, so that an enumeration never copies or clones the whole list.
+pub struct List {
+    body: String,
+    // offset of the first element, None if there are none
+    first: Option<usize>,
+    // offset of the current element, None if there are no more
+    current: Option<usize>,
+    index: usize,
+}
+
+impl List {
+    pub fn empty() -> List {
+        List {
+            body: String::new(),
+            first: None,
+            current: None,
+            index: 0,
+        }
+    }
+
+    // new takes over a response body, after check made sure that all of it decodes, e.g.
+    // List::new(body, |b| decode::count::<AlexandriaPassword>(b))
+    pub fn new<F>(body: String, check: F) -> Result<List, PayloadError> where F: FnOnce(&str) -> Result<usize, PayloadError> {
+        if try!(check(body.as_str())) == 0 {
+            return Ok(List::empty());
+        }
+
+        let first = {
+            let mut p = Parser::new(body.as_str());
+            try!(p.expect(b'['));
+            p.ws();
+            p.pos
+        };
+        Ok(List {
+            body: body,
+            first: Some(first),
+            current: Some(first),
+            index: 0,
+        })
+    }
+
+    // current decodes the current element, borrowing its strings from the list
+    pub fn current<'s, T: FromJson<'s>>(&'s self) -> Result<Option<T>, PayloadError> {
+        match self.current {
+            None => Ok(None),
+            Some(pos) => {
+                let mut p = Parser::at(self.body.as_str(), pos);
+                T::from_json(&mut p).map(Some).map_err(|e| e.in_element(self.index))
+            },
+        }
+    }
+
+    // raw returns the JSON of the current element as it was received
+    pub fn raw(&self) -> Option<&str> {
+        self.current.map(|pos| {
+            let mut p = Parser::at(self.body.as_str(), pos);
+            // the body was checked in new(), so this can't fail
+            let _ = p.skip(0);
+            &self.body[pos..p.pos]
+        })
+    }
+
+    // entries decodes all elements at once, from the first one no matter where the list is. An
+    // empty list has no body to decode, so this is what the tools use instead of decode::list.
+    pub fn entries<'s, T: FromJson<'s>>(&'s self) -> Result<Vec<T>, PayloadError> {
+        let mut entries = vec![];
+        let mut next = self.first;
+        while let Some(pos) = next {
+            let mut p = Parser::at(self.body.as_str(), pos);
+            entries.push(try!(T::from_json(&mut p).map_err(|e| e.in_element(entries.len()))));
+            next = self.after(pos);
+        }
+        Ok(entries)
+    }
+
+    // body returns the response body the list was decoded from
+    pub fn body(&self) -> &str {
+        self.body.as_str()
+    }
+
+    // done reports if there are no more elements
+    pub fn done(&self) -> bool {
+        self.current.is_none()
+    }
+
+    // rewind moves back to the first element
+    pub fn rewind(&mut self) {
+        self.current = self.first;
+        self.index = 0;
+    }
+
+    // advance moves on to the next element
+    pub fn advance(&mut self) {
+        let next = match self.current {
+            None => return,
+            Some(pos) => self.after(pos),
+        };
+        self.current = next;
+        self.index += 1;
+    }
+
+    // after returns the offset of the element following the one at pos, None if it is the last
+    fn after(&self, pos: usize) -> Option<usize> {
+        let mut p = Parser::at(self.body.as_str(), pos);
+        // the body was checked in new(), so this can't fail
+        let _ = p.skip(0);
+        p.ws();
+        if p.eat(b',') {
+            p.ws();
+            Some(p.pos)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Parser<'a> {
+    src: &'a str,
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(src: &'a str) -> Parser<'a> {
+        Parser::at(src, 0)
+    }
+
+    fn at(src: &'a str, pos: usize) -> Parser<'a> {
+        Parser {
+            src: src,
+            bytes: src.as_bytes(),
+            pos: pos,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).cloned()
+    }
+
+    fn ws(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        if self.peek() == Some(b) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_literal(&mut self, lit: &str) -> bool {
+        if self.bytes[self.pos..].starts_with(lit.as_bytes()) {
+            self.pos += lit.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn syntax(&self, expected: &str) -> String {
+        match self.peek() {
+            Some(b) => format!("expected {} at byte {}, got '{}'", expected, self.pos, (b as char).escape_default()),
+            None => format!("expected {} at byte {}, got end of payload", expected, self.pos),
+        }
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), PayloadError> {
+        self.ws();
+        if self.eat(b) {
+            Ok(())
+        } else {
+            Err(PayloadError::new("response", "", self.syntax(format!("'{}'", b as char).as_str())))
+        }
+    }
+
+    // end makes sure nothing but whitespace follows the decoded value
+    fn end(&mut self) -> Result<(), PayloadError> {
+        self.ws();
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(PayloadError::new("response", "", self.syntax("end of payload"))),
+        }
+    }
+
+    // null consumes a null and reports if there was one, which is how a missing value is treated
+    pub fn null(&mut self) -> bool {
+        self.ws();
+        self.eat_literal("null")
+    }
+
+    // array calls f for every element of an array
+    fn array<F>(&mut self, mut f: F) -> Result<(), PayloadError> where F: FnMut(&mut Parser<'a>, usize) -> Result<(), PayloadError> {
+        try!(self.expect(b'['));
+        self.ws();
+        if self.eat(b']') {
+            return Ok(());
+        }
+        let mut i = 0;
+        loop {
+            self.ws();
+            try!(f(self, i).map_err(|e| e.in_element(i)));
+            self.ws();
+            if self.eat(b',') {
+                i += 1;
+                continue;
+            }
+            if self.eat(b']') {
+                return Ok(());
+            }
+            return Err(PayloadError::new("response", "", self.syntax("',' or ']'")).in_element(i));
+        }
+    }
+
+    // object calls f with the name of every field of an object. f either decodes the field's
+    // value or skips it, and returns the reason if the value is not acceptable.
+    pub fn object<F>(&mut self, type_name: &'static str, mut f: F) -> Result<(), PayloadError> where F: FnMut(&mut Parser<'a>, &'a str) -> Result<(), String> {
+        self.ws();
+        if !self.eat(b'{') {
+            return Err(PayloadError::new(type_name, "", self.syntax("an object")));
+        }
+        self.ws();
+        if self.eat(b'}') {
+            return Ok(());
+        }
+        loop {
+            self.ws();
+            // field names are compared as they appear in the source, none of ours need escaping
+            let name = match self.raw_string() {
+                Ok((name, _)) => name,
+                Err(reason) => return Err(PayloadError::new(type_name, "", reason)),
+            };
+            self.ws();
+            if !self.eat(b':') {
+                return Err(PayloadError::new(type_name, name, self.syntax("':'")));
+            }
+            self.ws();
+            try!(f(self, name).map_err(|reason| PayloadError::new(type_name, name, reason)));
+            self.ws();
+            if self.eat(b',') {
+                continue;
+            }
+            if self.eat(b'}') {
+                return Ok(());
+            }
+            return Err(PayloadError::new(type_name, name, self.syntax("',' or '}'")));
+        }
+    }
+
+    // raw_string scans a string and returns its source without the quotes, and whether it
+    // contains escapes. All escapes are validated here, so unescaping later can't fail.
+    fn raw_string(&mut self) -> Result<(&'a str, bool), String> {
+        if !self.eat(b'"') {
+            return Err(self.syntax("a string"));
+        }
+        let start = self.pos;
+        let mut escaped = false;
+        loop {
+            match self.peek() {
+                None => return Err(self.syntax("'\"'")),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    escaped = true;
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(b'"') | Some(b'\\') | Some(b'/') | Some(b'b') | Some(b'f') | Some(b'n') | Some(b'r') | Some(b't') => self.pos += 1,
+                        Some(b'u') => {
+                            self.pos += 1;
+                            let u = try!(self.hex4());
+                            if u == 0 {
+                                return Err("strings must not contain NUL characters".to_string());
+                            }
+                            if 0xDC00 <= u && u <= 0xDFFF {
+                                return Err(format!("unpaired surrogate in string at byte {}", self.pos));
+                            }
+                            if 0xD800 <= u && u <= 0xDBFF {
+                                if !self.eat_literal("\\u") {
+                                    return Err(format!("unpaired surrogate in string at byte {}", self.pos));
+                                }
+                                let low = try!(self.hex4());
+                                if low < 0xDC00 || 0xDFFF < low {
+                                    return Err(format!("unpaired surrogate in string at byte {}", self.pos));
+                                }
+                            }
+                        },
+                        _ => return Err(self.syntax("an escape sequence")),
+                    }
+                },
+                Some(b) if b < 0x20 => return Err(self.syntax("a printable character")),
+                Some(_) => self.pos += 1,
+            }
+        }
+        let s = &self.src[start..self.pos];
+        self.pos += 1;
+        Ok((s, escaped))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = match self.src.get(self.pos..self.pos + 4) {
+            Some(digits) => digits,
+            None => return Err(self.syntax("4 hex digits")),
+        };
+        match u32::from_str_radix(digits, 16) {
+            Ok(u) if digits.bytes().all(|b| (b as char).is_digit(16)) => {
+                self.pos += 4;
+                Ok(u)
+            },
+            _ => Err(self.syntax("4 hex digits")),
+        }
+    }
+
+    // number scans a number and returns its source
+    fn number(&mut self) -> Result<&'a str, String> {
+        let start = self.pos;
+        self.eat(b'-');
+        let digits = |p: &mut Parser<'a>| {
+            let s = p.pos;
+            while let Some(b'0'..=b'9') = p.peek() {
+                p.pos += 1;
+            }
+            p.pos > s
+        };
+        if !digits(self) {
+            return Err(self.syntax("a number"));
+        }
+        if self.eat(b'.') && !digits(self) {
+            return Err(self.syntax("a digit"));
+        }
+        if self.eat(b'e') || self.eat(b'E') {
+            if !self.eat(b'+') {
+                self.eat(b'-');
+            }
+            if !digits(self) {
+                return Err(self.syntax("a digit"));
+            }
+        }
+        Ok(&self.src[start..self.pos])
+    }
+
+    // skip skips any value, e.g. one of a field this library doesn't know
+    pub fn skip(&mut self, depth: usize) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("values nested deeper than {} levels", MAX_DEPTH));
+        }
+        self.ws();
+        match self.peek() {
+            Some(b'"') => self.raw_string().map(|_| ()),
+            Some(b'{') => {
+                self.pos += 1;
+                self.ws();
+                if self.eat(b'}') {
+                    return Ok(());
+                }
+                loop {
+                    self.ws();
+                    try!(self.raw_string());
+                    self.ws();
+                    if !self.eat(b':') {
+                        return Err(self.syntax("':'"));
+                    }
+                    try!(self.skip(depth + 1));
+                    self.ws();
+                    if self.eat(b',') {
+                        continue;
+                    }
+                    if self.eat(b'}') {
+                        return Ok(());
+                    }
+                    return Err(self.syntax("',' or '}'"));
+                }
+            },
+            Some(b'[') => {
+                self.pos += 1;
+                self.ws();
+                if self.eat(b']') {
+                    return Ok(());
+                }
+                loop {
+                    try!(self.skip(depth + 1));
+                    self.ws();
+                    if self.eat(b',') {
+                        continue;
+                    }
+                    if self.eat(b']') {
+                        return Ok(());
+                    }
+                    return Err(self.syntax("',' or ']'"));
+                }
+            },
+            Some(b't') if self.eat_literal("true") => Ok(()),
+            Some(b'f') if self.eat_literal("false") => Ok(()),
+            Some(b'n') if self.eat_literal("null") => Ok(()),
+            _ => self.number().map(|_| ()),
+        }
+    }
+
+    // string decodes a string value, None if it is null
+    pub fn string(&mut self) -> Result<Option<Text<'a>>, String> {
+        if self.null() {
+            return Ok(None);
+        }
+        match self.peek() {
+            Some(b'"') => {
+                let (s, escaped) = try!(self.raw_string());
+                Ok(Some(if escaped { Text::Json(s) } else { Text::Str(s) }))
+            },
+            _ => Err(self.syntax("a string")),
+        }
+    }
+
+    // name decodes a user or group name, see names::decode
+    pub fn name(&mut self) -> Result<Option<Text<'a>>, String> {
+        match try!(self.string()) {
+            None => Ok(None),
+            Some(name) => names::decode(name).map(Some),
+        }
+    }
+
+    // names decodes an array of user or group names, None if it is null
+    pub fn names(&mut self) -> Result<Option<Vec<Text<'a>>>, String> {
+        match try!(self.strings()) {
+            None => Ok(None),
+            Some(v) => {
+                let mut names = Vec::with_capacity(v.len());
+                for (i, name) in v.into_iter().enumerate() {
+                    names.push(try!(names::decode(name).map_err(|reason| format!("element {}: {}", i, reason))));
+                }
+                Ok(Some(names))
+            },
+        }
+    }
+
+    // entries decodes an array of T, None if it is null
+    pub fn entries<T: FromJson<'a>>(&mut self) -> Result<Option<Vec<T>>, String> {
+        if self.null() {
+            return Ok(None);
+        }
+        let mut v = vec![];
+        try!(self.array(|p, _| {
+            v.push(try!(T::from_json(p)));
+            Ok(())
+        }).map_err(|e| e.to_string()));
+        Ok(Some(v))
+    }
+
+    // strings decodes an array of strings, None if it is null
+    pub fn strings(&mut self) -> Result<Option<Vec<Text<'a>>>, String> {
+        if self.null() {
+            return Ok(None);
+        }
+        if !self.eat(b'[') {
+            return Err(self.syntax("an array of strings"));
+        }
+        let mut v = vec![];
+        self.ws();
+        if self.eat(b']') {
+            return Ok(Some(v));
+        }
+        loop {
+            self.ws();
+            match self.string() {
+                Ok(Some(s)) => v.push(s),
+                Ok(None) => return Err(format!("element {}: expected a string, got null", v.len())),
+                Err(reason) => return Err(format!("element {}: {}", v.len(), reason)),
+            }
+            self.ws();
+            if self.eat(b',') {
+                continue;
+            }
+            if self.eat(b']') {
+                return Ok(Some(v));
+            }
+            return Err(self.syntax("',' or ']'"));
+        }
+    }
+
+    // integer decodes an integer in the range min..max, None if it is null. Integers are
+    // accepted no matter how they were sent: as JSON integers, as floats without a fractional
+    // part or as strings of digits.
+    pub fn integer(&mut self, min: i128, max: i128) -> Result<Option<i128>, String> {
+        if self.null() {
+            return Ok(None);
+        }
+        let s = match self.peek() {
+            Some(b'"') => try!(self.raw_string()).0.trim(),
+            _ => try!(self.number()),
+        };
+        let i = match s.parse::<i128>() {
+            Ok(i) => Some(i),
+            Err(_) => match s.parse::<f64>() {
+                Ok(f) if f.fract() == 0.0 && f.abs() < 1e30 => Some(f as i128),
+                _ => None,
+            },
+        };
+        // the value itself is left out, the error ends up in syslog and may be shadow data
+        match i {
+            Some(i) if min <= i && i <= max => Ok(Some(i)),
+            _ => Err(format!("expected an integer between {} and {}", min, max)),
+        }
+    }
+
+    pub fn unsigned(&mut self, max: u64) -> Result<Option<u64>, String> {
+        self.integer(0, max as i128).map(|i| i.map(|i| i as u64))
+    }
+
+    pub fn signed(&mut self) -> Result<Option<i64>, String> {
+        self.integer(i64::MIN as i128, i64::MAX as i128).map(|i| i.map(|i| i as i64))
+    }
+}
+
+// required returns the value of a mandatory field, or the error that it is missing
+pub fn required<T>(type_name: &'static str, field: &str, value: Option<T>) -> Result<T, PayloadError> {
+    match value {
+        Some(value) => Ok(value),
+        None => Err(PayloadError::new(type_name, field, "missing required field".to_string())),
+    }
+}
+
+// unescape resolves the escapes of a string as it appeared in the JSON source, which was
+// validated by the parser, and hands out the resulting bytes piece by piece
+pub fn unescape<F>(raw: &str, mut out: F) where F: FnMut(&[u8]) {
+    let bytes = raw.as_bytes();
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            i += 1;
+            continue;
+        }
+        out(&bytes[start..i]);
+        let esc = bytes[i + 1];
+        i += 2;
+        let c = match esc {
+            b'b' => '\x08',
+            b'f' => '\x0c',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let mut u = hex4(&raw[i..i + 4]);
+                i += 4;
+                if 0xD800 <= u && u <= 0xDBFF {
+                    let low = hex4(&raw[i + 2..i + 6]);
+                    i += 6;
+                    u = 0x10000 + ((u - 0xD800) << 10) + (low - 0xDC00);
+                }
+                char::from_u32(u).unwrap_or('\u{FFFD}')
+            },
+            // '"', '\\' and '/' stand for themselves
+            b => b as char,
+        };
+        let mut utf8 = [0u8; 4];
+        out(c.encode_utf8(&mut utf8).as_bytes());
+        start = i;
+    }
+    out(&bytes[start..]);
+}
+
+fn hex4(digits: &str) -> u32 {
+    u32::from_str_radix(digits, 16).unwrap_or(0xFFFD)
+}
+
+#[cfg(test)]
+mod tests {
+    use types::AlexandriaGroup;
+    use super::{count, List, Parser, MAX_DEPTH};
+
+    // string decodes src as a string value and unescapes it
+    fn string(src: &str) -> Result<String, String> {
+        Parser::new(src).string().map(|s| s.unwrap().as_str().into_owned())
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(string(r#""plain""#).unwrap(), "plain");
+        assert_eq!(string(r#""a\"b\\c\/d""#).unwrap(), "a\"b\\c/d");
+        assert_eq!(string(r#""\b\f\n\r\t""#).unwrap(), "\x08\x0c\n\r\t");
+        assert_eq!(string(r#""\u00e9é""#).unwrap(), "éé");
+        // a surrogate pair is one character outside of the BMP
+        assert_eq!(string(r#""\ud83d\ude00!""#).unwrap(), "\u{1F600}!");
+        assert!(string(r#""\x""#).unwrap_err().contains("an escape sequence"));
+        assert!(string(r#""\u12""#).is_err());
+        assert!(string(r#""\u12g4""#).is_err());
+        assert!(string("\"a\nb\"").is_err());
+        assert!(string(r#""open"#).is_err());
+    }
+
+    #[test]
+    fn rejected_characters() {
+        assert!(string(r#""a\u0000b""#).unwrap_err().contains("NUL"));
+        // surrogates which don't pair up
+        assert!(string(r#""\ud83d""#).unwrap_err().contains("unpaired surrogate"));
+        assert!(string(r#""\ud83dx""#).unwrap_err().contains("unpaired surrogate"));
+        assert!(string(r#""\ud83dA""#).unwrap_err().contains("unpaired surrogate"));
+        assert!(string(r#""\ude00""#).unwrap_err().contains("unpaired surrogate"));
+    }
+
+    #[test]
+    fn nesting() {
+        let nested = |levels: usize| format!("{}{}", "[".repeat(levels), "]".repeat(levels));
+        // the outermost array is at depth 0
+        assert!(Parser::new(&nested(MAX_DEPTH + 1)).skip(0).is_ok());
+        assert!(Parser::new(&nested(MAX_DEPTH + 2)).skip(0).unwrap_err().contains("nested deeper"));
+        let objects = format!("{}1{}", r#"{"a":"#.repeat(MAX_DEPTH + 2), "}".repeat(MAX_DEPTH + 2));
+        assert!(Parser::new(&objects).skip(0).unwrap_err().contains("nested deeper"));
+    }
+
+    #[test]
+    fn integers() {
+        let unsigned = |src: &str| Parser::new(src).unsigned(u32::max_value() as u64);
+        assert_eq!(unsigned("42"), Ok(Some(42)));
+        assert_eq!(unsigned("null"), Ok(None));
+        // as strings and as floats without a fractional part
+        assert_eq!(unsigned(r#""42""#), Ok(Some(42)));
+        assert_eq!(unsigned(r#"" 42 ""#), Ok(Some(42)));
+        assert_eq!(unsigned("42.0"), Ok(Some(42)));
+        assert_eq!(unsigned("1e3"), Ok(Some(1000)));
+        assert_eq!(unsigned(r#""1E3""#), Ok(Some(1000)));
+        assert!(unsigned("42.5").is_err());
+        assert!(unsigned(r#""forty-two""#).is_err());
+        assert!(unsigned("-1").is_err());
+        assert!(unsigned("4294967296").is_err());
+        assert_eq!(Parser::new("-1").signed(), Ok(Some(-1)));
+        assert!(Parser::new("1.").signed().is_err());
+    }
+
+    #[test]
+    fn list_cursor() {
+        let body = r#"[ {"gr_name":"a","gr_gid":1}, {"gr_name":"b","gr_gid":2} ,{"gr_name":"c","gr_gid":3}]"#;
+        let mut list = List::new(body.to_string(), |b| count::<AlexandriaGroup>(b)).unwrap();
+        let gid = |list: &List| list.current::<AlexandriaGroup>().unwrap().map(|g| g.gr_gid);
+        assert_eq!(gid(&list), Some(1));
+        list.advance();
+        assert_eq!(gid(&list), Some(2));
+        assert_eq!(list.raw(), Some(r#"{"gr_name":"b","gr_gid":2}"#));
+        list.advance();
+        list.advance();
+        assert!(list.done());
+        assert_eq!(gid(&list), None);
+        // advancing beyond the end stays there
+        list.advance();
+        assert!(list.done());
+        list.rewind();
+        assert_eq!(gid(&list), Some(1));
+        assert_eq!(list.entries::<AlexandriaGroup>().unwrap().len(), 3);
+
+        let mut empty = List::new("[]".to_string(), |b| count::<AlexandriaGroup>(b)).unwrap();
+        assert!(empty.done());
+        empty.advance();
+        empty.rewind();
+        assert_eq!(gid(&empty), None);
+        assert!(empty.entries::<AlexandriaGroup>().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// Routing of lookups to the directories of config::DOMAINS, and enumerations which merge them.
+//
+// The default directory is a Domain without a name, so that routes.rs doesn't have to tell it
+// apart. Entries sent by any other domain are qualified with its name as soon as they are decoded.
+
+use std::iter;
+use std::slice;
+use libc::uid_t;
+use libc::gid_t;
+use config::{SOCKET_PATH, SOCKET_PATH_PRIV, DOMAINS, DOMAIN_DOWN};
+use decode::{FromJson, List, PayloadError};
+use generation;
+use types::AlexandriaPassword;
+use types::AlexandriaGroup;
+use types::AlexandriaShadow;
+use types::AlexandriaSubid;
+use types::AlexandriaSvcError;
+use types::Domain;
+use types::DomainDown;
+use types::Text;
+use util::log;
+
+pub static DEFAULT: Domain = Domain {
+    name: "",
+    socket: SOCKET_PATH,
+    socket_priv: SOCKET_PATH_PRIV,
+    uids: &[],
+    gids: &[],
+};
+
+// all returns the default directory and the ones of DOMAINS
+pub fn all() -> iter::Chain<iter::Once<&'static Domain>, slice::Iter<'static, Domain>> {
+    iter::once(&DEFAULT).chain(DOMAINS.iter())
+}
+
+// for_name returns the domain a name is looked up in, and the name it has there
+pub fn for_name(name: &[u8]) -> (&'static Domain, &[u8]) {
+    if let Some(at) = name.iter().rposition(|&b| b == b'@') {
+        if let Some(domain) = DOMAINS.iter().find(|d| d.name.as_bytes().eq_ignore_ascii_case(&name[at + 1..])) {
+            return (domain, &name[..at]);
+        }
+    }
+    (&DEFAULT, name)
+}
+
+pub fn for_uid(uid: uid_t) -> &'static Domain {
+    DOMAINS.iter().find(|d| d.uids.iter().any(|&(min, max)| min <= uid && uid <= max)).unwrap_or(&DEFAULT)
+}
+
+pub fn for_gid(gid: gid_t) -> &'static Domain {
+    DOMAINS.iter().find(|d| d.gids.iter().any(|&(min, max)| min <= gid && gid <= max)).unwrap_or(&DEFAULT)
+}
+
+// describe names a domain in log messages
+pub fn describe(domain: &Domain) -> String {
+    if domain.name.is_empty() {
+        "the default directory".to_string()
+    } else {
+        format!("domain {}", domain.name)
+    }
+}
+
+// Qualify names the users and groups of an entry after the domain it was sent by
+pub trait Qualify {
+    fn qualify(self, domain: &Domain) -> Self;
+}
+
+// qualify_name appends "@domain" to a name, unless it is qualified already
+fn qualify_name(name: &mut Text, domain: &Domain) {
+    if domain.name.is_empty() {
+        return;
+    }
+    let qualified = {
+        let bytes = name.as_bytes();
+        if bytes.contains(&b'@') {
+            return;
+        }
+        let mut v = Vec::with_capacity(bytes.len() + 1 + domain.name.len());
+        v.extend_from_slice(&bytes);
+        v.push(b'@');
+        v.extend_from_slice(domain.name.as_bytes());
+        v
+    };
+    *name = Text::Bytes(qualified);
+}
+
+impl<'a> Qualify for AlexandriaPassword<'a> {
+    fn qualify(mut self, domain: &Domain) -> AlexandriaPassword<'a> {
+        qualify_name(&mut self.pw_name, domain);
+        self
+    }
+}
+
+impl<'a> Qualify for AlexandriaGroup<'a> {
+    fn qualify(mut self, domain: &Domain) -> AlexandriaGroup<'a> {
+        qualify_name(&mut self.gr_name, domain);
+        for member in self.gr_mem.iter_mut().chain(self.gr_groups.iter_mut()) {
+            qualify_name(member, domain);
+        }
+        self
+    }
+}
+
+impl<'a> Qualify for AlexandriaShadow<'a> {
+    fn qualify(mut self, domain: &Domain) -> AlexandriaShadow<'a> {
+        qualify_name(&mut self.sp_namp, domain);
+        self
+    }
+}
+
+impl<'a> Qualify for AlexandriaSubid<'a> {
+    fn qualify(mut self, domain: &Domain) -> AlexandriaSubid<'a> {
+        qualify_name(&mut self.owner, domain);
+        self
+    }
+}
+
+// Merged are the lists of all directories, which are enumerated one after the other
+pub struct Merged {
+    lists: Vec<(&'static Domain, List)>,
+    // the list the enumeration is in, lists before it are done
+    index: usize,
+    // the enumeration ends after limit elements, however many there are
+    limit: Option<usize>,
+    advanced: usize,
+    // the generations of the directories as their lists were fetched, see generation.rs
+    generations: Vec<Option<u64>>,
+}
+
+impl Merged {
+    pub fn empty() -> Merged {
+        Merged {
+            lists: vec![],
+            index: 0,
+            limit: None,
+            advanced: 0,
+            generations: vec![],
+        }
+    }
+
+    // position returns the index of the list holding the current element
+    fn position(&self) -> Option<usize> {
+        if self.limit.map_or(false, |limit| self.advanced >= limit) {
+            return None;
+        }
+        (self.index..self.lists.len()).find(|&i| !self.lists[i].1.done())
+    }
+
+    // current decodes the current element, see List::current
+    pub fn current<'s, T: FromJson<'s> + Qualify>(&'s self) -> Result<Option<T>, PayloadError> {
+        match self.position() {
+            None => Ok(None),
+            Some(i) => {
+                let (domain, ref list) = self.lists[i];
+                list.current::<T>().map(|entry| entry.map(|entry| entry.qualify(domain)))
+            },
+        }
+    }
+
+    // raw returns the JSON of the current element and the domain which sent it, see List::raw
+    pub fn raw(&self) -> Option<(&'static Domain, &str)> {
+        self.position().and_then(|i| {
+            let (domain, ref list) = self.lists[i];
+            list.raw().map(|raw| (domain, raw))
+        })
+    }
+
+    // entries decodes the elements of all lists at once, for the tools
+    pub fn entries<'s, T: FromJson<'s> + Qualify>(&'s self) -> Result<Vec<T>, AlexandriaSvcError> {
+        let mut entries = vec![];
+        for &(domain, ref list) in self.lists.iter() {
+            let list: Vec<T> = try!(list.entries());
+            entries.extend(list.into_iter().map(|entry| entry.qualify(domain)));
+        }
+        Ok(entries)
+    }
+
+    // bodies returns the response bodies the lists were decoded from
+    pub fn bodies(&self) -> Vec<&str> {
+        self.lists.iter().map(|&(_, ref list)| list.body()).collect()
+    }
+
+    // changed reports whether a directory changed since its list was fetched
+    pub fn changed(&self) -> bool {
+        self.lists.iter().zip(self.generations.iter()).any(|(&(domain, _), &then)| generation::changed(domain, then))
+    }
+
+    pub fn rewind(&mut self) {
+        for &mut (_, ref mut list) in self.lists.iter_mut() {
+            list.rewind();
+        }
+        self.index = 0;
+        self.advanced = 0;
+    }
+
+    pub fn advance(&mut self) {
+        if let Some(i) = self.position() {
+            self.index = i;
+            self.lists[i].1.advance();
+            self.advanced += 1;
+        }
+    }
+}
+
+// merge fetches the list of database from every directory, of which at most limit elements are
+// enumerated. One which is down fails the enumeration, unless DOMAIN_DOWN is Skip and at least one
+// directory is up.
+pub fn merge<F>(database: &str, limit: Option<usize>, mut fetch: F) -> Result<Merged, AlexandriaSvcError> where F: FnMut(&'static Domain) -> Result<List, AlexandriaSvcError> {
+    let (mut lists, mut generations) = (vec![], vec![]);
+    let mut failed = None;
+    for domain in all() {
+        // read before the fetch, so that a change during it counts as one
+        let generation = generation::current(domain);
+        match fetch(domain) {
+            Ok(list) => {
+                lists.push((domain, list));
+                generations.push(generation);
+            },
+            Err(e) => {
+                if DOMAIN_DOWN == DomainDown::Fail {
+                    return Err(e);
+                }
+                log(format!("leaving out the {} entries of {}: {}", database, describe(domain), e).as_str());
+                failed = Some(e);
+            },
+        }
+    }
+    match failed {
+        Some(e) if lists.is_empty() => Err(e),
+        _ => Ok(Merged {
+            lists: lists,
+            index: 0,
+            limit: limit,
+            advanced: 0,
+            generations: generations,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use decode;
+    use decode::List;
+    use types::AlexandriaPassword;
+    use super::merge;
+
+    fn list(body: &str) -> List {
+        List::new(body.to_string(), |b| decode::count::<AlexandriaPassword>(b)).unwrap()
+    }
+
+    #[test]
+    fn entries_of_empty_lists() {
+        for body in &["[]", "null"] {
+            let merged = merge("passwd", None, |_| Ok(list(body))).unwrap();
+            assert!(merged.entries::<AlexandriaPassword>().unwrap().is_empty());
+        }
+        // a directory which answered 404
+        let merged = merge("passwd", None, |_| Ok(List::empty())).unwrap();
+        assert!(merged.entries::<AlexandriaPassword>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn entries_start_at_the_first_element() {
+        let mut merged = merge("passwd", None, |_| {
+            Ok(list(r#"[{"pw_name":"alice","pw_uid":1000,"pw_gid":1000,"pw_dir":"/"}, {"pw_name":"bob","pw_uid":1001,"pw_gid":1000,"pw_dir":"/"}]"#))
+        }).unwrap();
+        merged.advance();
+        let entries = merged.entries::<AlexandriaPassword>().unwrap();
+        let names: Vec<String> = entries.iter().map(|e| e.pw_name.as_str().into_owned()).collect();
+        assert_eq!(names, ["alice", "bob"]);
+    }
+}
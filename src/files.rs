@@ -0,0 +1,164 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// Local files which are parsed once, and again whenever they change: /etc/passwd and /etc/group
+// for the conflict checks of filter.rs, and /etc/shells for template.rs. Changes are noticed after
+// at most CHECK_INTERVAL_S.
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::Read;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use util;
+use util::log;
+
+const CHECK_INTERVAL_S: u64 = 1;
+
+struct Loaded<T> {
+    content: Arc<T>,
+    modified: SystemTime,
+    checked: Instant,
+}
+
+pub struct Cached<T> {
+    path: &'static str,
+    parse: fn(&[u8]) -> T,
+    loaded: Mutex<Option<Loaded<T>>>,
+}
+
+impl<T: 'static> Cached<T> {
+    const fn new(path: &'static str, parse: fn(&[u8]) -> T) -> Cached<T> {
+        Cached {
+            path: path,
+            parse: parse,
+            loaded: Mutex::new(None),
+        }
+    }
+
+    fn lock(&'static self) -> MutexGuard<'static, Option<Loaded<T>>> {
+        util::atfork_once();
+        self.loaded.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // get returns the parsed content of the file, which is read again if it was modified. A file
+    // which can't be read is empty.
+    pub fn get(&'static self) -> Arc<T> {
+        let mut loaded = self.lock();
+        if let Some(ref l) = *loaded {
+            if l.checked.elapsed() < Duration::from_secs(CHECK_INTERVAL_S) {
+                return l.content.clone();
+            }
+        }
+
+        let modified = fs::metadata(self.path).and_then(|m| m.modified()).unwrap_or(UNIX_EPOCH);
+        if let Some(ref mut l) = *loaded {
+            if l.modified == modified {
+                l.checked = Instant::now();
+                return l.content.clone();
+            }
+        }
+
+        let mut data = vec![];
+        if let Err(e) = fs::File::open(self.path).and_then(|mut f| f.read_to_end(&mut data)) {
+            log(format!("can't read {}: {}", self.path, e).as_str());
+        }
+        let content = Arc::new((self.parse)(&data));
+        *loaded = Some(Loaded {
+            content: content.clone(),
+            modified: modified,
+            checked: Instant::now(),
+        });
+        content
+    }
+}
+
+// Accounts are the names and ids of /etc/passwd or /etc/group
+pub struct Accounts {
+    names: Vec<Vec<u8>>,
+    ids: Vec<u32>,
+}
+
+impl Accounts {
+    pub fn has_name(&self, name: &[u8]) -> bool {
+        self.names.iter().any(|n| n.as_slice() == name)
+    }
+
+    pub fn has_id(&self, id: u32) -> bool {
+        self.ids.contains(&id)
+    }
+}
+
+// accounts parses name:password:id:... lines, which both /etc/passwd and /etc/group consist of
+fn accounts(data: &[u8]) -> Accounts {
+    let mut accounts = Accounts {
+        names: vec![],
+        ids: vec![],
+    };
+    for line in data.split(|&b| b == b'\n') {
+        let mut fields = line.split(|&b| b == b':');
+        let name = fields.next().unwrap_or(b"");
+        // NIS compat entries like +@netgroup are not accounts
+        if name.is_empty() || name[0] == b'+' || name[0] == b'-' || name[0] == b'#' {
+            continue;
+        }
+        accounts.names.push(name.to_vec());
+        if let Some(id) = fields.nth(1).and_then(|id| String::from_utf8_lossy(id).parse::<u32>().ok()) {
+            accounts.ids.push(id);
+        }
+    }
+    accounts
+}
+
+// Shells are the login shells of /etc/shells
+pub struct Shells(Vec<Vec<u8>>);
+
+impl Shells {
+    pub fn contains(&self, shell: &[u8]) -> bool {
+        self.0.iter().any(|s| s.as_slice() == shell)
+    }
+}
+
+fn shells(data: &[u8]) -> Shells {
+    let shells = data.split(|&b| b == b'\n')
+        .map(|line| String::from_utf8_lossy(line).trim().as_bytes().to_vec())
+        .filter(|line| !line.is_empty() && line[0] != b'#')
+        .collect();
+    Shells(shells)
+}
+
+pub static PASSWD: Cached<Accounts> = Cached::new("/etc/passwd", accounts);
+pub static GROUP: Cached<Accounts> = Cached::new("/etc/group", accounts);
+pub static SHELLS: Cached<Shells> = Cached::new("/etc/shells", shells);
+
+// The locks are taken before a fork like the ones in lib.rs, so that the child doesn't inherit
+// them locked.
+type ForkGuards = (
+    MutexGuard<'static, Option<Loaded<Accounts>>>,
+    MutexGuard<'static, Option<Loaded<Accounts>>>,
+    MutexGuard<'static, Option<Loaded<Shells>>>,
+);
+
+thread_local!(static FORK_GUARDS: RefCell<Option<ForkGuards>> = RefCell::new(None));
+
+pub fn atfork_prepare() {
+    FORK_GUARDS.with(|guards| *guards.borrow_mut() = Some((PASSWD.lock(), GROUP.lock(), SHELLS.lock())));
+}
+
+pub fn atfork_release() {
+    FORK_GUARDS.with(|guards| guards.borrow_mut().take());
+}
@@ -0,0 +1,97 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// Filters which keep the directory from serving entries that would shadow or collide with the
+// local accounts, e.g. a misconfigured entry named root or with uid 0.
+//
+// Lookups of ids and names which can't pass are not found without asking the Alexandria service,
+// and every entry it sends is checked again, as it might send something else than was asked for.
+// The entries of the overlay are local anyway and are not filtered.
+
+use config::{ALLOW_UIDS, ALLOW_GIDS, DENY_NAMES, DENY_GROUP_NAMES, FILTER_PRIMARY_GID, REJECT_LOCAL_CONFLICTS};
+use files;
+use types::AlexandriaPassword;
+use types::AlexandriaGroup;
+use types::AlexandriaShadow;
+use util::debug;
+
+pub fn uid_allowed(uid: u32) -> bool {
+    ALLOW_UIDS.iter().any(|&(min, max)| min <= uid && uid <= max)
+}
+
+pub fn gid_allowed(gid: u32) -> bool {
+    ALLOW_GIDS.iter().any(|&(min, max)| min <= gid && gid <= max)
+}
+
+pub fn name_allowed(name: &[u8]) -> bool {
+    !DENY_NAMES.iter().any(|denied| denied.as_bytes() == name)
+}
+
+pub fn group_name_allowed(name: &[u8]) -> bool {
+    name_allowed(name) && !DENY_GROUP_NAMES.iter().any(|denied| denied.as_bytes() == name)
+}
+
+// passwd reports if the directory may serve entry, and logs why not. The reasons are logged at
+// debug level, as an enumeration goes through the same rejected entries every time.
+pub fn passwd(entry: &AlexandriaPassword) -> bool {
+    let reason = if !name_allowed(&entry.pw_name.as_bytes()) {
+        "the name is denied"
+    } else if !uid_allowed(entry.pw_uid) {
+        "the uid is not allowed"
+    } else if FILTER_PRIMARY_GID && !gid_allowed(entry.pw_gid) {
+        "the gid is not allowed"
+    } else if REJECT_LOCAL_CONFLICTS && conflicts(&files::PASSWD.get(), &entry.pw_name.as_bytes(), entry.pw_uid) {
+        "it conflicts with /etc/passwd"
+    } else {
+        return true;
+    };
+    debug(format!("rejecting passwd entry {} (uid {}, gid {}): {}", entry.pw_name, entry.pw_uid, entry.pw_gid, reason).as_str());
+    false
+}
+
+// group reports if the directory may serve entry, and logs why not
+pub fn group(entry: &AlexandriaGroup) -> bool {
+    let reason = if !group_name_allowed(&entry.gr_name.as_bytes()) {
+        "the name is denied"
+    } else if !gid_allowed(entry.gr_gid) {
+        "the gid is not allowed"
+    } else if REJECT_LOCAL_CONFLICTS && conflicts(&files::GROUP.get(), &entry.gr_name.as_bytes(), entry.gr_gid) {
+        "it conflicts with /etc/group"
+    } else {
+        return true;
+    };
+    debug(format!("rejecting group entry {} (gid {}): {}", entry.gr_name, entry.gr_gid, reason).as_str());
+    false
+}
+
+// shadow reports if the directory may serve entry, and logs why not. Local users have their
+// shadow entries in /etc/shadow, so a name in /etc/passwd is a conflict.
+pub fn shadow(entry: &AlexandriaShadow) -> bool {
+    let reason = if !name_allowed(&entry.sp_namp.as_bytes()) {
+        "the name is denied"
+    } else if REJECT_LOCAL_CONFLICTS && files::PASSWD.get().has_name(&entry.sp_namp.as_bytes()) {
+        "it conflicts with /etc/passwd"
+    } else {
+        return true;
+    };
+    debug(format!("rejecting shadow entry {}: {}", entry.sp_namp, reason).as_str());
+    false
+}
+
+fn conflicts(local: &files::Accounts, name: &[u8], id: u32) -> bool {
+    local.has_name(name) || local.has_id(id)
+}
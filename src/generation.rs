@@ -0,0 +1,137 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// Generations of the directories, see config::GENERATION.
+//
+// Entries of a directory are kept in two places: the lists of enumerations which set*ent rewinds,
+// and the cache file of mcache.rs. Both remember the generations they were fetched at, and are
+// dropped as soon as a directory reports another one. A generation which can't be read, then or
+// now, doesn't count as a change, so the caches still expire as they would without it.
+//
+// A generation is read at most once per GENERATION_CHECK_MS and domain in a process, or every time
+// if it is 0. One which was read a moment ago may be recorded for entries fetched after a change,
+// which only costs another fetch once the new generation is read.
+
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use config::{GENERATION, GENERATION_CHECK_MS, GENERATION_PATH};
+use decode;
+use domains;
+use routes;
+use types::AlexandriaGeneration;
+use types::AlexandriaSvcError;
+use types::Domain;
+use types::GenerationSource;
+use util;
+use util::log;
+
+// read returns the generation in the file of domain, None if there is no such file
+fn read(domain: &Domain) -> Result<Option<u64>, AlexandriaSvcError> {
+    let path = if domain.name.is_empty() {
+        GENERATION_PATH.to_string()
+    } else {
+        format!("{}.{}", GENERATION_PATH, domain.name)
+    };
+    let mut body = String::new();
+    match fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut body)) {
+        Ok(_) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(AlexandriaSvcError::from(e)),
+    }
+    let g: AlexandriaGeneration = try!(decode::from_str(&body));
+    Ok(Some(g.generation))
+}
+
+// Checked is the generation of one domain as it was read last, None if it couldn't be
+struct Checked {
+    domain: &'static str,
+    generation: Option<u64>,
+    at: Instant,
+}
+
+static CHECKED: Mutex<Vec<Checked>> = Mutex::new(vec![]);
+
+fn lock() -> MutexGuard<'static, Vec<Checked>> {
+    util::atfork_once();
+    CHECKED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// fetch reads the generation of the directory of domain from GENERATION
+fn fetch(domain: &Domain) -> Option<u64> {
+    let ret = match GENERATION {
+        GenerationSource::Off => return None,
+        GenerationSource::Route => routes::generation(domain),
+        GenerationSource::File => read(domain),
+    };
+    match ret {
+        Ok(generation) => generation,
+        Err(e) => {
+            log(format!("can't read the generation of {}: {}", domains::describe(domain), e).as_str());
+            None
+        },
+    }
+}
+
+// current returns the generation of the directory of domain, None if it is unknown. The error of
+// one which can't be read is logged once per GENERATION_CHECK_MS as well.
+pub fn current(domain: &Domain) -> Option<u64> {
+    if let GenerationSource::Off = GENERATION {
+        return None;
+    }
+    if GENERATION_CHECK_MS == 0 {
+        return fetch(domain);
+    }
+    let fresh = Duration::from_millis(GENERATION_CHECK_MS);
+    if let Some(c) = lock().iter().find(|c| c.domain == domain.name && c.at.elapsed() < fresh) {
+        return c.generation;
+    }
+    // not read under the lock, other threads look up the last one meanwhile
+    let generation = fetch(domain);
+    let mut checked = lock();
+    checked.retain(|c| c.domain != domain.name);
+    checked.push(Checked {
+        domain: domain.name,
+        generation: generation,
+        at: Instant::now(),
+    });
+    generation
+}
+
+// changed reports whether the directory of domain moved on from the generation then, which a cache
+// was fetched at
+pub fn changed(domain: &Domain, then: Option<u64>) -> bool {
+    match (then, then.and_then(|_| current(domain))) {
+        (Some(then), Some(now)) => then != now,
+        _ => false,
+    }
+}
+
+// The lock is taken before a fork like the ones in lib.rs, so that the child doesn't inherit it
+// locked. The generations read stay valid in the child.
+thread_local!(static FORK_GUARD: RefCell<Option<MutexGuard<'static, Vec<Checked>>>> = RefCell::new(None));
+
+pub fn atfork_prepare() {
+    FORK_GUARD.with(|guard| *guard.borrow_mut() = Some(lock()));
+}
+
+pub fn atfork_release() {
+    FORK_GUARD.with(|guard| guard.borrow_mut().take());
+}
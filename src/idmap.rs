@@ -0,0 +1,118 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// Translation between the uids and gids of the directory and the ones of this host, see
+// config::UID_MAP and config::GID_MAP.
+//
+// The rules of a map are tried in order, the first one which covers an id translates it. Entries
+// with an id no rule covers are not served, and lookups of such an id don't ask the Alexandria
+// service. An empty map leaves all ids as they are.
+
+use config::{UID_MAP, GID_MAP};
+use types::IdMap;
+use types::AlexandriaPassword;
+use types::AlexandriaGroup;
+use util::debug;
+
+// to_host translates id with rule, None if the rule doesn't cover it
+fn to_host(rule: &IdMap, id: u32) -> Option<u32> {
+    match *rule {
+        IdMap::Offset(offset) => shift(id, offset),
+        IdMap::Range { directory, host, count } => translate(id, directory, host, count),
+    }
+}
+
+// to_directory is the reverse of to_host
+fn to_directory(rule: &IdMap, id: u32) -> Option<u32> {
+    match *rule {
+        IdMap::Offset(offset) => shift(id, -offset),
+        IdMap::Range { directory, host, count } => translate(id, host, directory, count),
+    }
+}
+
+fn shift(id: u32, offset: i64) -> Option<u32> {
+    let id = id as i64 + offset;
+    // (uid_t)-1 is not an id but "no change" to chown(2) and friends
+    if 0 <= id && id < u32::MAX as i64 {
+        Some(id as u32)
+    } else {
+        None
+    }
+}
+
+// translate maps id from the count ids starting at from to the ones starting at to
+fn translate(id: u32, from: u32, to: u32, count: u32) -> Option<u32> {
+    if id < from || id - from >= count {
+        return None;
+    }
+    match to.checked_add(id - from) {
+        Some(id) if id < u32::MAX => Some(id),
+        _ => None,
+    }
+}
+
+fn map(rules: &[IdMap], id: u32, f: fn(&IdMap, u32) -> Option<u32>) -> Option<u32> {
+    if rules.is_empty() {
+        return Some(id);
+    }
+    rules.iter().filter_map(|rule| f(rule, id)).next()
+}
+
+pub fn uid_to_host(uid: u32) -> Option<u32> {
+    map(UID_MAP, uid, to_host)
+}
+
+pub fn uid_to_directory(uid: u32) -> Option<u32> {
+    map(UID_MAP, uid, to_directory)
+}
+
+pub fn gid_to_host(gid: u32) -> Option<u32> {
+    map(GID_MAP, gid, to_host)
+}
+
+pub fn gid_to_directory(gid: u32) -> Option<u32> {
+    map(GID_MAP, gid, to_directory)
+}
+
+// passwd translates the ids of an entry sent by the directory, None if it has none on this host
+pub fn passwd(mut entry: AlexandriaPassword) -> Option<AlexandriaPassword> {
+    match (uid_to_host(entry.pw_uid), gid_to_host(entry.pw_gid)) {
+        (Some(uid), Some(gid)) => {
+            entry.pw_uid = uid;
+            entry.pw_gid = gid;
+            Some(entry)
+        },
+        _ => {
+            debug(format!("passwd entry {} (uid {}, gid {}) has no ids on this host", entry.pw_name, entry.pw_uid, entry.pw_gid).as_str());
+            None
+        },
+    }
+}
+
+// group translates the gid of an entry sent by the directory, None if it has none on this host
+pub fn group(mut entry: AlexandriaGroup) -> Option<AlexandriaGroup> {
+    match gid_to_host(entry.gr_gid) {
+        Some(gid) => {
+            entry.gr_gid = gid;
+            Some(entry)
+        },
+        None => {
+            debug(format!("group entry {} (gid {}) has no gid on this host", entry.gr_name, entry.gr_gid).as_str());
+            None
+        },
+    }
+}
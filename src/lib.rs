@@ -15,28 +15,60 @@
 // You should have received a copy of the GNU General Public License
 // along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
 
-#![feature(static_mutex)]
-#[macro_use]
-
-extern crate rustc_serialize;
 extern crate hyper;
 extern crate hyperlocal;
 extern crate libc;
-
-mod types;
-mod config;
-mod util;
-mod routes;
-
+extern crate regex;
+
+// types, decode, config, util, metrics and routes are only public for the binaries of this crate in
+// src/bin, the library is meant to be loaded by glibc through the functions below
+#[doc(hidden)]
+pub mod types;
+mod access;
+mod canonical;
+mod context;
+#[doc(hidden)]
+pub mod decode;
+#[doc(hidden)]
+pub mod domains;
+mod files;
+mod filter;
+#[doc(hidden)]
+pub mod generation;
+mod idmap;
+#[doc(hidden)]
+pub mod mcache;
+mod names;
+mod nested;
+mod overlay;
+mod query;
+mod template;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod util;
+#[doc(hidden)]
+pub mod metrics;
+#[doc(hidden)]
+pub mod routes;
+
+use std::collections::HashSet;
 use std::ffi::{CStr};
-use std::sync::{StaticMutex, MUTEX_INIT};
+use std::str;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 use libc::c_char;
+use libc::c_void;
 use libc::c_int;
+use libc::c_long;
+use libc::c_ulong;
 use libc::size_t;
 use libc::uid_t;
 use libc::gid_t;
 use libc::ENOENT;
 use libc::EAGAIN;
+use libc::EACCES;
 use libc::passwd;
 use types::group;
 use types::spwd;
@@ -45,51 +77,220 @@ use types::nss_status::NSS_STATUS_UNAVAIL;
 use types::nss_status::NSS_STATUS_NOTFOUND;
 use types::nss_status::NSS_STATUS_SUCCESS;
 use types::nss_status::NSS_STATUS_TRYAGAIN;
+use types::subid_range;
+use types::subid_status;
+use types::subid_status::SUBID_STATUS_SUCCESS;
+use types::subid_status::SUBID_STATUS_ERROR;
+use types::subid_status::SUBID_STATUS_ERROR_CONN;
+use types::ID_TYPE_UID;
+use types::ID_TYPE_GID;
 use types::AlexandriaPassword;
 use types::AlexandriaGroup;
 use types::AlexandriaShadow;
+use types::AlexandriaAutomount;
+use types::AlexandriaSubid;
+use types::AlexandriaSvcError;
+use types::Enumerate;
+use decode::List;
+use domains::Merged;
+use config::STAYOPEN_TTL_S;
+use config::{NESTED_GROUPS, NESTED_GROUPS_DEPTH};
+use config::{PASSWD_ENUMERATE, GROUP_ENUMERATE, SHADOW_ENUMERATE};
+use overlay::Overlay;
+use overlay::Served;
+use nested::Node;
 use util::log;
 
-// This struct keeps the state for the _nss_alexandria_getpwent_r function
-// It stores/caches the previously retrieved list and then increments the index here
-struct DbList<T> {
-    list: Vec<T>,
-    index: usize,
-}
-impl<T> DbList<T> {
-    fn get_current_entry(&self) -> Option<&T> {
-        let i = self.get_index();
-        if self.list.is_empty() || i >= self.list.len() {
-            None
-        } else {
-            Some(&self.list[i])
+// This is the state for one automount map. autofs keeps one of these per map it reads, so unlike
+// the other databases it is handed back to the caller as an opaque context instead of being global
+struct AutomountContext {
+    map: String,
+    entries: List,
+}
+
+// Enumeration is the state of one database between set*ent and end*ent. The lists of the
+// directories keep track of the current entry.
+struct Enumeration {
+    entries: Merged,
+    // the overlay as of set*ent, whose added entries are served before the list
+    overlay: Arc<Overlay>,
+    // how many of them were served
+    added: usize,
+    // when entries were fetched, a fresh list is rewound by set*ent instead of fetched again
+    fetched: Instant,
+    // set*ent was called with stayopen, so end*ent keeps the list for the next set*ent
+    stayopen: bool,
+}
+
+impl Enumeration {
+    fn new(entries: Merged, overlay: Arc<Overlay>, stayopen: c_int) -> Enumeration {
+        if stayopen != 0 {
+            routes::stay_open();
         }
+        Enumeration {
+            entries: entries,
+            overlay: overlay,
+            added: 0,
+            fetched: Instant::now(),
+            stayopen: stayopen != 0,
+        }
+    }
+}
+
+// rewind starts the enumeration over on the list fetched by a previous set*ent, as long as that is
+// still fresh and the directories didn't change since. Otherwise the list is dropped, so that
+// get*ent_r doesn't serve it if fetching it again fails, and rewind returns false.
+fn rewind(state: &mut Option<Enumeration>, overlay: Arc<Overlay>, stayopen: c_int) -> bool {
+    let reused = match *state {
+        Some(ref mut e) if e.fetched.elapsed() < Duration::from_secs(STAYOPEN_TTL_S) && !e.entries.changed() => {
+            e.entries.rewind();
+            e.overlay = overlay;
+            e.added = 0;
+            e.stayopen = stayopen != 0;
+            if e.stayopen {
+                routes::stay_open();
+            }
+            true
+        },
+        _ => false,
+    };
+    if !reused {
+        *state = None;
     }
+    reused
+}
+
+// enumerate fetches the entries of an enumeration, as far as the calling process may enumerate a
+// database of mode, see access::enumerate
+fn enumerate<F>(mode: Enumerate, fetch: F) -> Result<Merged, AlexandriaSvcError> where F: FnOnce(Option<usize>) -> Result<Merged, AlexandriaSvcError> {
+    match access::enumerate(mode) {
+        Enumerate::Full => fetch(None),
+        Enumerate::Capped(limit) => fetch(Some(limit)),
+        Enumerate::Disabled => Ok(Merged::empty()),
+    }
+}
+
+// close ends the enumeration, the list is only kept if it was opened with stayopen
+fn close(state: &mut Option<Enumeration>) {
+    let keep = match *state {
+        Some(ref mut e) if e.stayopen => {
+            e.entries.rewind();
+            e.added = 0;
+            true
+        },
+        _ => false,
+    };
+    if !keep {
+        *state = None;
+    }
+}
+
+// This is global C-style library state for the _nss_alexandria_get*ent_r functions
+// The mutex is held for the whole of every set*ent, end*ent and get*ent_r call, so concurrent
+// callers wait for each other instead of failing with NSS_STATUS_TRYAGAIN
+static PWD_LIST: Mutex<Option<Enumeration>> = Mutex::new(None);
+static GRP_LIST: Mutex<Option<Enumeration>> = Mutex::new(None);
+static SPWD_LIST: Mutex<Option<Enumeration>> = Mutex::new(None);
+
+// lock acquires the state of one database, waiting for other threads if necessary
+fn lock(state: &'static Mutex<Option<Enumeration>>) -> MutexGuard<'static, Option<Enumeration>> {
+    util::atfork_once();
+
+    // a thread which panicked while holding the lock can't leave a half-updated list behind,
+    // as the list is only ever replaced as a whole, so the poisoning can be ignored
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
 
-    fn get_index(&self) -> usize {
-        self.index
+// This library lives inside forking servers (sshd, crond, shells). If one thread forks while
+// another one holds one of the locks above, the child gets a copy of the locked mutex which
+// nobody will ever unlock. So all locks are taken right before a fork, which also waits for
+// running enumeration calls to finish, and are released again in both processes afterwards.
+// The child forgets the enumeration state as well, it must not continue the parent's cursor.
+// The handlers are registered by util::atfork_once, which the locks of all modules call.
+thread_local!(static FORK_GUARDS: RefCell<Vec<MutexGuard<'static, Option<Enumeration>>>> = RefCell::new(vec![]));
+
+unsafe extern "C" fn atfork_prepare() {
+    FORK_GUARDS.with(|guards| {
+        let mut guards = guards.borrow_mut();
+        guards.push(lock(&PWD_LIST));
+        guards.push(lock(&GRP_LIST));
+        guards.push(lock(&SPWD_LIST));
+    });
+    routes::atfork_prepare();
+    overlay::atfork_prepare();
+    files::atfork_prepare();
+    mcache::atfork_prepare();
+    generation::atfork_prepare();
+    context::atfork_prepare();
+}
+
+unsafe extern "C" fn atfork_parent() {
+    FORK_GUARDS.with(|guards| guards.borrow_mut().clear());
+    routes::atfork_parent();
+    overlay::atfork_release();
+    files::atfork_release();
+    mcache::atfork_release();
+    generation::atfork_release();
+    context::atfork_parent();
+}
+
+unsafe extern "C" fn atfork_child() {
+    FORK_GUARDS.with(|guards| {
+        for mut state in guards.borrow_mut().drain(..) {
+            *state = None;
+        }
+    });
+    routes::atfork_child();
+    overlay::atfork_release();
+    files::atfork_release();
+    mcache::atfork_release();
+    generation::atfork_release();
+    context::atfork_child();
+    metrics::reset();
+}
+
+// passwd_exists reports if the directory has a user named name, which it may serve on this host
+fn passwd_exists(name: &[u8]) -> Result<bool, AlexandriaSvcError> {
+    let mut body = String::new();
+    match routes::passwd_name(&canonical::lookup(name), &mut body) {
+        Ok(entry) => Ok(entry.and_then(idmap::passwd).map_or(false, |entry| filter::passwd(&entry))),
+        Err(AlexandriaSvcError::InvalidName(_)) => Ok(false),
+        Err(e) => Err(e),
     }
+}
 
-    fn increment_index(&mut self) {
-        self.index = self.get_index() + 1;
+// group_exists reports if the directory has a group named name, which it may serve on this host
+fn group_exists(name: &[u8]) -> Result<bool, AlexandriaSvcError> {
+    let mut body = String::new();
+    match routes::group_name(&canonical::lookup(name), &mut body) {
+        Ok(entry) => Ok(entry.and_then(idmap::group).map_or(false, |entry| filter::group(&entry))),
+        Err(AlexandriaSvcError::InvalidName(_)) => Ok(false),
+        Err(e) => Err(e),
     }
 }
 
-// This is global C-style library state for the _nss_alexandria_getpwent_r function
-// We use a StaticMutex to lock the library
-static PWD_LIB_LOCK: StaticMutex = MUTEX_INIT;
-static mut PWD_LIST: *mut DbList<AlexandriaPassword> = 0 as *mut DbList<AlexandriaPassword>;
-static GRP_LIB_LOCK: StaticMutex = MUTEX_INIT;
-static mut GRP_LIST: *mut DbList<AlexandriaGroup> = 0 as *mut DbList<AlexandriaGroup>;
-static SPWD_LIB_LOCK: StaticMutex = MUTEX_INIT;
-static mut SPWD_LIST: *mut DbList<AlexandriaShadow> = 0 as *mut DbList<AlexandriaShadow>;
+// Every exported function is a thin wrapper which runs the actual implementation in util::guard
+// (or util::catch), so that a panic is logged and turned into an error instead of unwinding into
+// glibc, which would take down the calling process.
 
 // Called to open the passwd file
 #[no_mangle]
-pub extern "C" fn _nss_alexandria_setpwent(_stayopen: c_int) -> nss_status {
+pub extern "C" fn _nss_alexandria_setpwent(stayopen: c_int) -> nss_status {
+    util::guard("_nss_alexandria_setpwent", std::ptr::null_mut(), || setpwent(stayopen))
+}
+
+fn setpwent(stayopen: c_int) -> nss_status {
     log("_nss_alexandria_setpwent()");
 
-    let entries = match routes::passwd() {
+    let overlay = overlay::get();
+    let mut state = lock(&PWD_LIST);
+    let reused = rewind(&mut state, overlay.clone(), stayopen);
+    metrics::cache("passwd", reused);
+    if reused {
+        return NSS_STATUS_SUCCESS;
+    }
+
+    let entries = match enumerate(PASSWD_ENUMERATE, routes::passwd) {
         Ok(entries) => entries,
         Err(e) => {
             log(format!("_nss_alexandria_setpwent(): error retrieving passwd list from Alexandria service: {}", e).as_str());
@@ -97,23 +298,7 @@ pub extern "C" fn _nss_alexandria_setpwent(_stayopen: c_int) -> nss_status {
         },
     };
 
-    unsafe {
-        let _locked = match PWD_LIB_LOCK.try_lock() {
-            Ok(s) => s,
-            Err(_) => {
-                return NSS_STATUS_TRYAGAIN;
-            }
-        };
-
-        let b: Box<DbList<AlexandriaPassword>> = Box::new(
-            DbList {
-                index: 0,
-                list: entries.clone(),
-            }
-        );
-
-        PWD_LIST = Box::into_raw(b);
-    }
+    *state = Some(Enumeration::new(entries, overlay, stayopen));
 
     NSS_STATUS_SUCCESS
 }
@@ -121,125 +306,202 @@ pub extern "C" fn _nss_alexandria_setpwent(_stayopen: c_int) -> nss_status {
 // Called to close the passwd file
 #[no_mangle]
 pub extern "C" fn _nss_alexandria_endpwent() -> nss_status {
-    log("_nss_alexandria_endpwent");
-    unsafe {
-        let _locked = match PWD_LIB_LOCK.try_lock() {
-            Ok(s) => s,
-            Err(_) => {
-                return NSS_STATUS_TRYAGAIN;
-            }
-        };
+    util::guard("_nss_alexandria_endpwent", std::ptr::null_mut(), endpwent)
+}
 
-        if !PWD_LIST.is_null() {
-            drop(Box::from_raw(PWD_LIST));
-            PWD_LIST = std::ptr::null_mut::<DbList<AlexandriaPassword>>();
-        }
-    }
+fn endpwent() -> nss_status {
+    log("_nss_alexandria_endpwent");
+    close(&mut lock(&PWD_LIST));
 
     NSS_STATUS_SUCCESS
 }
 
 // Called to look up next entry in passwd file
 #[no_mangle]
-pub extern "C" fn _nss_alexandria_getpwent_r(result: *mut passwd, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
+pub extern "C" fn _nss_alexandria_getpwent_r(result: *mut passwd, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    util::guard("_nss_alexandria_getpwent_r", errnop, || getpwent_r(result, buffer, buflen, errnop))
+}
+
+fn getpwent_r(result: *mut passwd, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
     log("_nss_alexandria_getpwent_r");
 
+    let mut state = lock(&PWD_LIST);
+
     // ensure the global library state is there
-    unsafe {
-        // unfortunately this double check is necessary because glibc might call endpwent and then
-        // another getpwent without hesitating
-        if PWD_LIST.is_null() {
-            // initialize PWD_LIST again
-            _nss_alexandria_setpwent(0);
-
-            // now it should be there
-            if PWD_LIST.is_null() {
-                *errnop = ENOENT;
-                return NSS_STATUS_UNAVAIL;
-            }
+    // unfortunately this double check is necessary because glibc might call endpwent and then
+    // another getpwent without hesitating
+    if state.is_none() {
+        match enumerate(PASSWD_ENUMERATE, routes::passwd) {
+            Ok(entries) => *state = Some(Enumeration::new(entries, overlay::get(), 0)),
+            Err(e) => {
+                log(format!("_nss_alexandria_getpwent_r(): error retrieving passwd list from Alexandria service: {}", e).as_str());
+                unsafe { *errnop = EAGAIN; }
+                return NSS_STATUS_TRYAGAIN;
+            },
         }
     }
-
-    // Acquire PWD_LIB_LOCK, or fail utterly, but don't block
-    let _locked = match PWD_LIB_LOCK.try_lock() {
-        Ok(s) => s,
-        Err(_) => {
-            unsafe { *errnop = EAGAIN; }
-            return NSS_STATUS_TRYAGAIN;
-        }
-    };
-
-    let mut pwl = unsafe { &mut *PWD_LIST };
-
-    // cloning is the only reasonable way to go here
-    let e = match pwl.get_current_entry() {
-        Some(e) => e.clone(),
+    let en = match *state {
+        Some(ref mut en) => en,
         None => {
             unsafe { *errnop = ENOENT; }
-            return NSS_STATUS_NOTFOUND;
+            return NSS_STATUS_UNAVAIL;
         },
     };
 
-    // on successful write_passwd, increment index first
-    match util::write_passwd(e, result, buffer, buflen, errnop) {
-        NSS_STATUS_SUCCESS => {
-            pwl.increment_index();
-            NSS_STATUS_SUCCESS
-        },
-        status => status
+    // the entries added by the overlay come first
+    if let Some(e) = en.overlay.passwd.adds().get(en.added) {
+        let status = util::write_passwd(e, result, buffer, buflen, errnop);
+        if let NSS_STATUS_SUCCESS = status {
+            en.added += 1;
+        }
+        return status;
+    }
+
+    loop {
+        // the entry borrows its strings from the list, nothing is copied until it is written
+        let status = match en.entries.current::<AlexandriaPassword>() {
+            Ok(Some(e)) => match idmap::passwd(e).filter(|e| filter::passwd(e)).map(canonical::passwd).map(template::passwd).map(|e| en.overlay.passwd.serve_next(e)) {
+                None | Some(Served::Hidden) => None,
+                Some(Served::Directory(e)) => Some(util::write_passwd(&e, result, buffer, buflen, errnop)),
+                Some(Served::Overlay(e)) => Some(util::write_passwd(e, result, buffer, buflen, errnop)),
+            },
+            Ok(None) => {
+                unsafe { *errnop = ENOENT; }
+                return NSS_STATUS_NOTFOUND;
+            },
+            Err(e) => {
+                log(format!("_nss_alexandria_getpwent_r(): error decoding entry: {}", e).as_str());
+                unsafe { *errnop = ENOENT; }
+                return NSS_STATUS_UNAVAIL;
+            },
+        };
+
+        // skip hidden entries, and on successful write_passwd, move on to the next entry
+        match status {
+            None => en.entries.advance(),
+            Some(NSS_STATUS_SUCCESS) => {
+                en.entries.advance();
+                return NSS_STATUS_SUCCESS;
+            },
+            Some(status) => return status,
+        }
     }
 }
 
 // Find a passwd by uid
 #[no_mangle]
-pub extern "C" fn _nss_alexandria_getpwuid_r(uid: uid_t, result: *mut passwd, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
+pub extern "C" fn _nss_alexandria_getpwuid_r(uid: uid_t, result: *mut passwd, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    util::guard("_nss_alexandria_getpwuid_r", errnop, || getpwuid_r(uid, result, buffer, buflen, errnop))
+}
+
+fn getpwuid_r(uid: uid_t, result: *mut passwd, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
     log("_nss_alexandria_getpwuid_r");
 
-    match routes::passwd_uid(uid) {
+    let overlay = overlay::get();
+    if let Some(entry) = overlay.passwd.added_id(uid) {
+        return util::write_passwd(entry, result, buffer, buflen, errnop);
+    }
+
+    // the directory may not serve a uid outside of the allowed ranges, so it isn't even asked. It
+    // knows the uid by its own id, see idmap.rs.
+    let mut body = String::new();
+    let possible_entry = match idmap::uid_to_directory(uid) {
+        Some(id) if filter::uid_allowed(uid) => routes::passwd_uid(id, &mut body),
+        _ => Ok(None),
+    };
+    match possible_entry {
         Err(e) => {
             log(format!("_nss_alexandria_getpwuid_r(): error retrieving passwd entry from Alexandria service: {}", e).as_str());
             unsafe { *errnop = EAGAIN; }
-            NSS_STATUS_TRYAGAIN
+            return NSS_STATUS_TRYAGAIN;
         },
-        Ok(possible_entry) => match possible_entry {
-            None => {
-                unsafe { *errnop = ENOENT; }
-                NSS_STATUS_NOTFOUND
-            },
-            Some(entry) => util::write_passwd(entry, result, buffer, buflen, errnop),
+        Ok(possible_entry) => match possible_entry.and_then(idmap::passwd).filter(|entry| filter::passwd(entry)).map(canonical::passwd).map(template::passwd).map(|entry| overlay.passwd.serve(entry)) {
+            Some(Served::Directory(entry)) => return util::write_passwd(&entry, result, buffer, buflen, errnop),
+            Some(Served::Overlay(entry)) if entry.pw_uid == uid => return util::write_passwd(entry, result, buffer, buflen, errnop),
+            _ => {},
         },
     }
 
+    // a replaced entry can have a uid of its own, which the directory doesn't know
+    match overlay.passwd.replaced_id(uid, passwd_exists) {
+        Ok(Some(entry)) => util::write_passwd(entry, result, buffer, buflen, errnop),
+        Ok(None) => {
+            unsafe { *errnop = ENOENT; }
+            NSS_STATUS_NOTFOUND
+        },
+        Err(e) => {
+            log(format!("_nss_alexandria_getpwuid_r(): error retrieving passwd entry from Alexandria service: {}", e).as_str());
+            unsafe { *errnop = EAGAIN; }
+            NSS_STATUS_TRYAGAIN
+        },
+    }
 }
 
 // Find a passwd by name
 #[no_mangle]
-pub extern "C" fn _nss_alexandria_getpwnam_r(name: *const c_char, result: *mut passwd, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
+pub extern "C" fn _nss_alexandria_getpwnam_r(name: *const c_char, result: *mut passwd, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    util::guard("_nss_alexandria_getpwnam_r", errnop, || getpwnam_r(name, result, buffer, buflen, errnop))
+}
+
+fn getpwnam_r(name: *const c_char, result: *mut passwd, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
     log("_nss_alexandria_getpwnam_r");
 
     let cname = unsafe { CStr::from_ptr(name) };
+    // the directory is asked for the name as it knows it, the overlay uses the one that is served
+    let dir_name = canonical::lookup(cname.to_bytes());
+    let host_name = canonical::host(&dir_name);
+
+    let overlay = overlay::get();
+    if overlay.passwd.hides(&host_name) {
+        unsafe { *errnop = ENOENT; }
+        return NSS_STATUS_NOTFOUND;
+    }
+    if let Some(entry) = overlay.passwd.added(&host_name) {
+        return util::write_passwd(entry, result, buffer, buflen, errnop);
+    }
 
-    match routes::passwd_name(cname.to_str().unwrap()) {
+    // the directory may not serve a denied name, so it isn't even asked
+    let mut body = String::new();
+    let possible_entry = if filter::name_allowed(&dir_name) { routes::passwd_name(&dir_name, &mut body) } else { Ok(None) };
+    match possible_entry {
+        Err(AlexandriaSvcError::InvalidName(reason)) => {
+            log(format!("_nss_alexandria_getpwnam_r(): {}", reason).as_str());
+            unsafe { *errnop = ENOENT; }
+            NSS_STATUS_NOTFOUND
+        },
         Err(e) => {
             log(format!("_nss_alexandria_getpwnam_r(): error retrieving passwd entry from Alexandria service: {}", e).as_str());
             unsafe { *errnop = EAGAIN; }
             NSS_STATUS_TRYAGAIN
         },
-        Ok(possible_entry) => match possible_entry {
-            None => {
+        Ok(possible_entry) => match possible_entry.and_then(idmap::passwd).filter(|entry| filter::passwd(entry)).map(canonical::passwd).map(template::passwd).map(|entry| overlay.passwd.serve(entry)) {
+            None | Some(Served::Hidden) => {
                 unsafe { *errnop = ENOENT; }
                 NSS_STATUS_NOTFOUND
             },
-            Some(entry) => util::write_passwd(entry, result, buffer, buflen, errnop),
+            Some(Served::Directory(entry)) => util::write_passwd(&entry, result, buffer, buflen, errnop),
+            Some(Served::Overlay(entry)) => util::write_passwd(entry, result, buffer, buflen, errnop),
         },
     }
 }
 
 #[no_mangle]
-pub extern "C" fn _nss_alexandria_setgrent(_stayopen: c_int) -> nss_status {
+pub extern "C" fn _nss_alexandria_setgrent(stayopen: c_int) -> nss_status {
+    util::guard("_nss_alexandria_setgrent", std::ptr::null_mut(), || setgrent(stayopen))
+}
+
+fn setgrent(stayopen: c_int) -> nss_status {
     log("_nss_alexandria_setgrent()");
 
-    let entries = match routes::group() {
+    let overlay = overlay::get();
+    let mut state = lock(&GRP_LIST);
+    let reused = rewind(&mut state, overlay.clone(), stayopen);
+    metrics::cache("group", reused);
+    if reused {
+        return NSS_STATUS_SUCCESS;
+    }
+
+    let entries = match enumerate(GROUP_ENUMERATE, routes::group) {
         Ok(entries) => entries,
         Err(e) => {
             log(format!("_nss_alexandria_setgrent(): error retrieving group list from Alexandria service: {}", e).as_str());
@@ -247,23 +509,7 @@ pub extern "C" fn _nss_alexandria_setgrent(_stayopen: c_int) -> nss_status {
         },
     };
 
-    unsafe {
-        let _locked = match GRP_LIB_LOCK.try_lock() {
-            Ok(s) => s,
-            Err(_) => {
-                return NSS_STATUS_TRYAGAIN;
-            }
-        };
-
-        let b: Box<DbList<AlexandriaGroup>> = Box::new(
-            DbList {
-                index: 0,
-                list: entries.clone(),
-            }
-        );
-
-        GRP_LIST = Box::into_raw(b);
-    }
+    *state = Some(Enumeration::new(entries, overlay, stayopen));
 
     NSS_STATUS_SUCCESS
 }
@@ -271,147 +517,319 @@ pub extern "C" fn _nss_alexandria_setgrent(_stayopen: c_int) -> nss_status {
 // Called to close the passwd file
 #[no_mangle]
 pub extern "C" fn _nss_alexandria_endgrent() -> nss_status {
-    log("_nss_alexandria_endgrent");
-    unsafe {
-        let _locked = match GRP_LIB_LOCK.try_lock() {
-            Ok(s) => s,
-            Err(_) => {
-                return NSS_STATUS_TRYAGAIN;
-            }
-        };
+    util::guard("_nss_alexandria_endgrent", std::ptr::null_mut(), endgrent)
+}
 
-        if !GRP_LIST.is_null() {
-            drop(Box::from_raw(GRP_LIST));
-            GRP_LIST = std::ptr::null_mut::<DbList<AlexandriaGroup>>();
-        }
-    }
+fn endgrent() -> nss_status {
+    log("_nss_alexandria_endgrent");
+    close(&mut lock(&GRP_LIST));
 
     NSS_STATUS_SUCCESS
 }
 
 #[no_mangle]
-pub extern "C" fn _nss_alexandria_getgrent_r(result: *mut group, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
+pub extern "C" fn _nss_alexandria_getgrent_r(result: *mut group, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    util::guard("_nss_alexandria_getgrent_r", errnop, || getgrent_r(result, buffer, buflen, errnop))
+}
+
+fn getgrent_r(result: *mut group, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
     log("_nss_alexandria_getgrent_r");
 
+    let mut state = lock(&GRP_LIST);
+
     // ensure the global library state is there
-    unsafe {
-        // unfortunately this double check is necessary because glibc might call endpwent and then
-        // another getpwent without hesitating
-        if GRP_LIST.is_null() {
-            // initialize GRP_LIST again
-            _nss_alexandria_setgrent(0);
-
-            // now it should be there
-            if GRP_LIST.is_null() {
-                *errnop = ENOENT;
-                return NSS_STATUS_UNAVAIL;
-            }
+    // unfortunately this double check is necessary because glibc might call endgrent and then
+    // another getgrent without hesitating
+    if state.is_none() {
+        match enumerate(GROUP_ENUMERATE, routes::group) {
+            Ok(entries) => *state = Some(Enumeration::new(entries, overlay::get(), 0)),
+            Err(e) => {
+                log(format!("_nss_alexandria_getgrent_r(): error retrieving group list from Alexandria service: {}", e).as_str());
+                unsafe { *errnop = EAGAIN; }
+                return NSS_STATUS_TRYAGAIN;
+            },
         }
     }
-
-    // Acquire GRP_LIB_LOCK, or fail utterly, but don't block
-    let _locked = match GRP_LIB_LOCK.try_lock() {
-        Ok(s) => s,
-        Err(_) => {
-            unsafe { *errnop = EAGAIN; }
-            return NSS_STATUS_TRYAGAIN;
-        }
-    };
-
-    let mut grpl = unsafe { &mut *GRP_LIST };
-
-    // cloning is the only reasonable way to go here
-    let e = match grpl.get_current_entry() {
-        Some(e) => e.clone(),
+    let en = match *state {
+        Some(ref mut en) => en,
         None => {
             unsafe { *errnop = ENOENT; }
-            return NSS_STATUS_NOTFOUND;
+            return NSS_STATUS_UNAVAIL;
         },
     };
 
-    // on successful write_passwd, increment index first
-    match util::write_group(e, result, buffer, buflen, errnop) {
-        NSS_STATUS_SUCCESS => {
-            grpl.increment_index();
-            NSS_STATUS_SUCCESS
-        },
-        status => status
+    // the entries added by the overlay come first
+    if let Some(e) = en.overlay.group.adds().get(en.added) {
+        let status = util::write_group(e, result, buffer, buflen, errnop);
+        if let NSS_STATUS_SUCCESS = status {
+            en.added += 1;
+        }
+        return status;
+    }
+
+    loop {
+        // the entry borrows its strings from the list, nothing is copied until it is written
+        let status = match en.entries.current::<AlexandriaGroup>() {
+            Ok(Some(e)) => match idmap::group(e).filter(|e| filter::group(e)).map(|e| nested::group(e, &en.overlay.group)).transpose() {
+                Err(e) => {
+                    log(format!("_nss_alexandria_getgrent_r(): error retrieving nested groups from Alexandria service: {}", e).as_str());
+                    unsafe { *errnop = EAGAIN; }
+                    return NSS_STATUS_TRYAGAIN;
+                },
+                Ok(e) => match e.map(canonical::group).map(|e| en.overlay.group.serve_next(e)) {
+                    None | Some(Served::Hidden) => None,
+                    Some(Served::Directory(e)) => Some(util::write_group(&e, result, buffer, buflen, errnop)),
+                    Some(Served::Overlay(e)) => Some(util::write_group(e, result, buffer, buflen, errnop)),
+                },
+            },
+            Ok(None) => {
+                unsafe { *errnop = ENOENT; }
+                return NSS_STATUS_NOTFOUND;
+            },
+            Err(e) => {
+                log(format!("_nss_alexandria_getgrent_r(): error decoding entry: {}", e).as_str());
+                unsafe { *errnop = ENOENT; }
+                return NSS_STATUS_UNAVAIL;
+            },
+        };
+
+        // skip hidden entries, and on successful write_group, move on to the next entry
+        match status {
+            None => en.entries.advance(),
+            Some(NSS_STATUS_SUCCESS) => {
+                en.entries.advance();
+                return NSS_STATUS_SUCCESS;
+            },
+            Some(status) => return status,
+        }
     }
 }
 
 // Find a group by gid
 #[no_mangle]
-pub extern "C" fn _nss_alexandria_getgrgid_r(gid: gid_t, result: *mut group, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
+pub extern "C" fn _nss_alexandria_getgrgid_r(gid: gid_t, result: *mut group, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    util::guard("_nss_alexandria_getgrgid_r", errnop, || getgrgid_r(gid, result, buffer, buflen, errnop))
+}
+
+fn getgrgid_r(gid: gid_t, result: *mut group, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
     log("_nss_alexandria_getgrgid_r");
 
-    match routes::group_gid(gid) {
+    let overlay = overlay::get();
+    if let Some(entry) = overlay.group.added_id(gid) {
+        return util::write_group(entry, result, buffer, buflen, errnop);
+    }
+
+    // the directory may not serve a gid outside of the allowed ranges, so it isn't even asked. It
+    // knows the gid by its own id, see idmap.rs.
+    let mut body = String::new();
+    let possible_entry = match idmap::gid_to_directory(gid) {
+        Some(id) if filter::gid_allowed(gid) => routes::group_gid(id, &mut body),
+        _ => Ok(None),
+    };
+    // the nested groups are looked up before the entry is served, see nested.rs
+    let possible_entry = possible_entry.and_then(|entry| entry.and_then(idmap::group).filter(|entry| filter::group(entry)).map(|entry| nested::group(entry, &overlay.group)).transpose());
+    match possible_entry {
         Err(e) => {
             log(format!("_nss_alexandria_getgrgid_r(): error retrieving group entry from Alexandria service: {}", e).as_str());
             unsafe { *errnop = EAGAIN; }
-            NSS_STATUS_TRYAGAIN
+            return NSS_STATUS_TRYAGAIN;
         },
-        Ok(possible_entry) => match possible_entry {
-            None => {
-                unsafe { *errnop = ENOENT; }
-                NSS_STATUS_NOTFOUND
-            },
-            Some(entry) => util::write_group(entry, result, buffer, buflen, errnop),
+        Ok(possible_entry) => match possible_entry.map(canonical::group).map(|entry| overlay.group.serve(entry)) {
+            Some(Served::Directory(entry)) => return util::write_group(&entry, result, buffer, buflen, errnop),
+            Some(Served::Overlay(entry)) if entry.gr_gid == gid => return util::write_group(entry, result, buffer, buflen, errnop),
+            _ => {},
         },
     }
 
+    // a replaced entry can have a gid of its own, which the directory doesn't know
+    match overlay.group.replaced_id(gid, group_exists) {
+        Ok(Some(entry)) => util::write_group(entry, result, buffer, buflen, errnop),
+        Ok(None) => {
+            unsafe { *errnop = ENOENT; }
+            NSS_STATUS_NOTFOUND
+        },
+        Err(e) => {
+            log(format!("_nss_alexandria_getgrgid_r(): error retrieving group entry from Alexandria service: {}", e).as_str());
+            unsafe { *errnop = EAGAIN; }
+            NSS_STATUS_TRYAGAIN
+        },
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn _nss_alexandria_getgrnam_r(name: *const c_char, result: *mut group, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
+pub extern "C" fn _nss_alexandria_getgrnam_r(name: *const c_char, result: *mut group, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    util::guard("_nss_alexandria_getgrnam_r", errnop, || getgrnam_r(name, result, buffer, buflen, errnop))
+}
+
+fn getgrnam_r(name: *const c_char, result: *mut group, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
     log("_nss_alexandria_getgrnam_r");
 
     let cname = unsafe { CStr::from_ptr(name) };
+    // the directory is asked for the name as it knows it, the overlay uses the one that is served
+    let dir_name = canonical::lookup(cname.to_bytes());
+    let host_name = canonical::host(&dir_name);
+
+    let overlay = overlay::get();
+    if overlay.group.hides(&host_name) {
+        unsafe { *errnop = ENOENT; }
+        return NSS_STATUS_NOTFOUND;
+    }
+    if let Some(entry) = overlay.group.added(&host_name) {
+        return util::write_group(entry, result, buffer, buflen, errnop);
+    }
 
-    match routes::group_name(cname.to_str().unwrap()) {
+    // the directory may not serve a denied name, so it isn't even asked
+    let mut body = String::new();
+    let possible_entry = if filter::group_name_allowed(&dir_name) { routes::group_name(&dir_name, &mut body) } else { Ok(None) };
+    // the nested groups are looked up before the entry is served, see nested.rs
+    let possible_entry = possible_entry.and_then(|entry| entry.and_then(idmap::group).filter(|entry| filter::group(entry)).map(|entry| nested::group(entry, &overlay.group)).transpose());
+    match possible_entry {
+        Err(AlexandriaSvcError::InvalidName(reason)) => {
+            log(format!("_nss_alexandria_getgrnam_r(): {}", reason).as_str());
+            unsafe { *errnop = ENOENT; }
+            NSS_STATUS_NOTFOUND
+        },
         Err(e) => {
             log(format!("_nss_alexandria_getprnam_r(): error retrieving group entry from Alexandria service: {}", e).as_str());
             unsafe { *errnop = EAGAIN; }
             NSS_STATUS_TRYAGAIN
         },
-        Ok(possible_entry) => match possible_entry {
-            None => {
+        Ok(possible_entry) => match possible_entry.map(canonical::group).map(|entry| overlay.group.serve(entry)) {
+            None | Some(Served::Hidden) => {
                 unsafe { *errnop = ENOENT; }
                 NSS_STATUS_NOTFOUND
             },
-            Some(entry) => util::write_group(entry, result, buffer, buflen, errnop),
+            Some(Served::Directory(entry)) => util::write_group(&entry, result, buffer, buflen, errnop),
+            Some(Served::Overlay(entry)) => util::write_group(entry, result, buffer, buflen, errnop),
         },
     }
 }
 
+// Called by initgroups and getgrouplist for the groups of user besides group, its primary group.
+// Without it glibc would enumerate all groups itself, which is what this does as well, but once.
 #[no_mangle]
-pub extern "C" fn _nss_alexandria_setspent(_stayopen: c_int) -> nss_status {
+pub extern "C" fn _nss_alexandria_initgroups_dyn(user: *const c_char, group: gid_t, start: *mut c_long, size: *mut c_long, groupsp: *mut *mut gid_t, limit: c_long, errnop: *mut c_int) -> nss_status {
+    util::guard("_nss_alexandria_initgroups_dyn", errnop, || initgroups_dyn(user, group, start, size, groupsp, limit, errnop))
+}
+
+fn initgroups_dyn(user: *const c_char, group: gid_t, start: *mut c_long, size: *mut c_long, groupsp: *mut *mut gid_t, limit: c_long, mut errnop: *mut c_int) -> nss_status {
+    log("_nss_alexandria_initgroups_dyn");
+
+    if user.is_null() {
+        unsafe { *errnop = ENOENT; }
+        return NSS_STATUS_NOTFOUND;
+    }
+    let cuser = unsafe { CStr::from_ptr(user) };
+    let dir_user = canonical::lookup(cuser.to_bytes()).into_owned();
+    let user = canonical::host(&dir_user).into_owned();
+
+    // the groups of the overlay are served as they are, see nested.rs. A replacement only counts
+    // while the directory still has the group it replaces.
+    let overlay = overlay::get();
+    let mut nodes: Vec<Node> = overlay.group.adds().iter().map(|e| Node::directory(e).served(e, true)).collect();
+    for e in overlay.group.replaces() {
+        let name = e.gr_name.as_bytes();
+        if overlay.group.hides(&name) || overlay.group.added(&name).is_some() || !e.gr_mem.iter().any(|m| *m.as_bytes() == *user) {
+            continue;
+        }
+        match group_exists(&name) {
+            Ok(true) => nodes.push(Node::directory(e).served(e, true)),
+            Ok(false) => {},
+            Err(e) => {
+                log(format!("_nss_alexandria_initgroups_dyn(): error retrieving group entry from Alexandria service: {}", e).as_str());
+                unsafe { *errnop = EAGAIN; }
+                return NSS_STATUS_TRYAGAIN;
+            },
+        }
+    }
+
+    // only the groups which list the user are fetched, and with NESTED_GROUPS the groups which list
+    // those, level by level like nested::group expands them
+    let mut seen: HashSet<Vec<u8>> = nodes.iter().map(|n| n.name.clone()).collect();
+    let mut level: Vec<Vec<u8>> = if NESTED_GROUPS {
+        nodes.iter().filter(|n| n.members.contains(&user)).map(|n| canonical::lookup(&n.name).into_owned()).collect()
+    } else {
+        vec![]
+    };
+    let mut members = vec![dir_user];
+    for depth in 0..NESTED_GROUPS_DEPTH + 1 {
+        let mut next = vec![];
+        for (member, nested) in members.into_iter().map(|m| (m, false)).chain(level.into_iter().map(|g| (g, true))) {
+            let mut entries = match routes::group_member(&member, nested) {
+                Ok(entries) => entries,
+                Err(AlexandriaSvcError::InvalidName(_)) => continue,
+                Err(e) => {
+                    log(format!("_nss_alexandria_initgroups_dyn(): error retrieving group list from Alexandria service: {}", e).as_str());
+                    unsafe { *errnop = EAGAIN; }
+                    return NSS_STATUS_TRYAGAIN;
+                },
+            };
+            loop {
+                match entries.current::<AlexandriaGroup>() {
+                    Ok(Some(e)) => if let Some(e) = idmap::group(e).filter(|e| filter::group(e)) {
+                        let node = Node::directory(&e);
+                        // a service which ignores the parameter sends groups which don't list member,
+                        // which must not be asked for in turn. A hidden group doesn't pass its members
+                        // on, so its groups aren't asked for either.
+                        let listed = if nested { node.groups.contains(&member) } else { e.gr_mem.iter().any(|m| *m.as_bytes() == *member) };
+                        if listed && seen.insert(node.name.clone()) {
+                            let node = match overlay.group.serve_next(canonical::group(e)) {
+                                Served::Hidden => None,
+                                Served::Directory(e) => Some(node.served(&e, false)),
+                                Served::Overlay(e) => Some(node.served(e, true)),
+                            };
+                            if let Some(node) = node {
+                                next.push(node.name.clone());
+                                nodes.push(node);
+                            }
+                        }
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        log(format!("_nss_alexandria_initgroups_dyn(): error decoding entry: {}", e).as_str());
+                        unsafe { *errnop = ENOENT; }
+                        return NSS_STATUS_UNAVAIL;
+                    },
+                }
+                entries.advance();
+            }
+        }
+        if !NESTED_GROUPS || next.is_empty() || depth == NESTED_GROUPS_DEPTH {
+            break;
+        }
+        members = vec![];
+        level = next;
+    }
+
+    util::write_gids(nested::containing(&user, &nodes), group, start, size, groupsp, limit, errnop)
+}
+
+#[no_mangle]
+pub extern "C" fn _nss_alexandria_setspent(stayopen: c_int) -> nss_status {
+    util::guard("_nss_alexandria_setspent", std::ptr::null_mut(), || setspent(stayopen))
+}
+
+fn setspent(stayopen: c_int) -> nss_status {
     log("_nss_alexandria_setspent()");
 
-    let entries = match routes::shadow() {
+    let overlay = overlay::for_shadow();
+    let mut state = lock(&SPWD_LIST);
+    let reused = rewind(&mut state, overlay.clone(), stayopen);
+    metrics::cache("shadow", reused);
+    if reused {
+        return NSS_STATUS_SUCCESS;
+    }
+
+    let entries = match enumerate(SHADOW_ENUMERATE, routes::shadow) {
         Ok(entries) => entries,
         Err(e) => {
             log(format!("_nss_alexandria_setspent(): error retrieving shadow list from Alexandria service: {}", e).as_str());
-            return NSS_STATUS_TRYAGAIN;
+            // trying again won't help if we may not access the privileged socket
+            return if e.permission_denied() { NSS_STATUS_UNAVAIL } else { NSS_STATUS_TRYAGAIN };
         },
     };
 
-    unsafe {
-        let _locked = match SPWD_LIB_LOCK.try_lock() {
-            Ok(s) => s,
-            Err(_) => {
-                return NSS_STATUS_TRYAGAIN;
-            }
-        };
-
-        let b: Box<DbList<AlexandriaShadow>> = Box::new(
-            DbList {
-                index: 0,
-                list: entries.clone(),
-            }
-        );
-
-        SPWD_LIST = Box::into_raw(b);
-    }
+    *state = Some(Enumeration::new(entries, overlay, stayopen));
 
     NSS_STATUS_SUCCESS
 }
@@ -419,83 +837,286 @@ pub extern "C" fn _nss_alexandria_setspent(_stayopen: c_int) -> nss_status {
 // Called to close the passwd file
 #[no_mangle]
 pub extern "C" fn _nss_alexandria_endspent() -> nss_status {
-    log("_nss_alexandria_endspent");
-    unsafe {
-        let _locked = match SPWD_LIB_LOCK.try_lock() {
-            Ok(s) => s,
-            Err(_) => {
-                return NSS_STATUS_TRYAGAIN;
-            }
-        };
+    util::guard("_nss_alexandria_endspent", std::ptr::null_mut(), endspent)
+}
 
-        if !SPWD_LIST.is_null() {
-            drop(Box::from_raw(SPWD_LIST));
-            SPWD_LIST = std::ptr::null_mut::<DbList<AlexandriaShadow>>();
-        }
-    }
+fn endspent() -> nss_status {
+    log("_nss_alexandria_endspent");
+    close(&mut lock(&SPWD_LIST));
 
     NSS_STATUS_SUCCESS
 }
 
 #[no_mangle]
-pub extern "C" fn _nss_alexandria_getspent_r(result: *mut spwd, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
+pub extern "C" fn _nss_alexandria_getspent_r(result: *mut spwd, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    util::guard("_nss_alexandria_getspent_r", errnop, || getspent_r(result, buffer, buflen, errnop))
+}
+
+fn getspent_r(result: *mut spwd, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
     log("_nss_alexandria_getspent_r");
 
+    let mut state = lock(&SPWD_LIST);
+
     // ensure the global library state is there
-    unsafe {
-        // unfortunately this double check is necessary because glibc might call endpwent and then
-        // another getpwent without hesitating
-        if SPWD_LIST.is_null() {
-            // initialize SPWD_LIST again
-            _nss_alexandria_setspent(0);
-
-            // now it should be there
-            if SPWD_LIST.is_null() {
-                *errnop = ENOENT;
+    // unfortunately this double check is necessary because glibc might call endspent and then
+    // another getspent without hesitating
+    if state.is_none() {
+        match enumerate(SHADOW_ENUMERATE, routes::shadow) {
+            Ok(entries) => *state = Some(Enumeration::new(entries, overlay::for_shadow(), 0)),
+            Err(e) => {
+                log(format!("_nss_alexandria_getspent_r(): error retrieving shadow list from Alexandria service: {}", e).as_str());
+                if e.permission_denied() {
+                    unsafe { *errnop = EACCES; }
+                    return NSS_STATUS_UNAVAIL;
+                }
+                unsafe { *errnop = EAGAIN; }
+                return NSS_STATUS_TRYAGAIN;
+            },
+        }
+    }
+    let en = match *state {
+        Some(ref mut en) => en,
+        None => {
+            unsafe { *errnop = ENOENT; }
+            return NSS_STATUS_UNAVAIL;
+        },
+    };
+
+    // the entries added by the overlay come first
+    if let Some(e) = en.overlay.shadow.adds().get(en.added) {
+        let status = util::write_shadow(e, result, buffer, buflen, errnop);
+        if let NSS_STATUS_SUCCESS = status {
+            en.added += 1;
+        }
+        return status;
+    }
+
+    loop {
+        // the entry borrows its strings from the list, nothing is copied until it is written
+        let status = match en.entries.current::<AlexandriaShadow>() {
+            Ok(Some(ref e)) if !filter::shadow(e) => None,
+            Ok(Some(e)) => match en.overlay.shadow.serve_next(canonical::shadow(e)) {
+                Served::Hidden => None,
+                Served::Directory(e) => Some(util::write_shadow(&e, result, buffer, buflen, errnop)),
+                Served::Overlay(e) => Some(util::write_shadow(e, result, buffer, buflen, errnop)),
+            },
+            Ok(None) => {
+                unsafe { *errnop = ENOENT; }
+                return NSS_STATUS_NOTFOUND;
+            },
+            Err(e) => {
+                log(format!("_nss_alexandria_getspent_r(): error decoding entry: {}", e).as_str());
+                unsafe { *errnop = ENOENT; }
                 return NSS_STATUS_UNAVAIL;
-            }
+            },
+        };
+
+        // skip hidden entries, and on successful write_shadow, move on to the next entry
+        match status {
+            None => en.entries.advance(),
+            Some(NSS_STATUS_SUCCESS) => {
+                en.entries.advance();
+                return NSS_STATUS_SUCCESS;
+            },
+            Some(status) => return status,
         }
     }
+}
 
-    // Acquire SPWD_LIB_LOCK, or fail utterly, but don't block
-    let _locked = match SPWD_LIB_LOCK.try_lock() {
-        Ok(s) => s,
-        Err(_) => {
+#[no_mangle]
+pub extern "C" fn _nss_alexandria_getspnam_r(name: *const c_char, result: *mut spwd, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    util::guard("_nss_alexandria_getspnam_r", errnop, || getspnam_r(name, result, buffer, buflen, errnop))
+}
+
+fn getspnam_r(name: *const c_char, result: *mut spwd, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
+    log("_nss_alexandria_getspnam_r");
+
+    let cname = unsafe { CStr::from_ptr(name) };
+    // the directory is asked for the name as it knows it, the overlay uses the one that is served
+    let dir_name = canonical::lookup(cname.to_bytes());
+    let host_name = canonical::host(&dir_name);
+
+    let overlay = overlay::for_shadow();
+    if overlay.shadow.hides(&host_name) {
+        unsafe { *errnop = ENOENT; }
+        return NSS_STATUS_NOTFOUND;
+    }
+    if let Some(entry) = overlay.shadow.added(&host_name) {
+        return util::write_shadow(entry, result, buffer, buflen, errnop);
+    }
+
+    // the directory may not serve a denied name, so it isn't even asked
+    let mut body = String::new();
+    let possible_entry = if filter::name_allowed(&dir_name) { routes::shadow_name(&dir_name, &mut body) } else { Ok(None) };
+    match possible_entry {
+        Err(AlexandriaSvcError::InvalidName(reason)) => {
+            log(format!("_nss_alexandria_getspnam_r(): {}", reason).as_str());
+            unsafe { *errnop = ENOENT; }
+            NSS_STATUS_NOTFOUND
+        },
+        Err(ref e) if e.permission_denied() => {
+            log(format!("_nss_alexandria_getspnam_r(): error retrieving shadow entry from Alexandria service: {}", e).as_str());
+            unsafe { *errnop = EACCES; }
+            NSS_STATUS_UNAVAIL
+        },
+        Err(e) => {
+            log(format!("_nss_alexandria_getspnam_r(): error retrieving shadow entry from Alexandria service: {}", e).as_str());
             unsafe { *errnop = EAGAIN; }
+            NSS_STATUS_TRYAGAIN
+        },
+        Ok(possible_entry) => match possible_entry.filter(|entry| filter::shadow(entry)).map(canonical::shadow).map(|entry| overlay.shadow.serve(entry)) {
+            None | Some(Served::Hidden) => {
+                unsafe { *errnop = ENOENT; }
+                NSS_STATUS_NOTFOUND
+            },
+            Some(Served::Directory(entry)) => util::write_shadow(&entry, result, buffer, buflen, errnop),
+            Some(Served::Overlay(entry)) => util::write_shadow(entry, result, buffer, buflen, errnop),
+        },
+    }
+}
+
+// Called by autofs to open an automount map, e.g. auto.master or auto.home
+#[no_mangle]
+pub extern "C" fn _nss_alexandria_setautomntent(mapname: *const c_char, context: *mut *mut c_void) -> nss_status {
+    util::guard("_nss_alexandria_setautomntent", std::ptr::null_mut(), || setautomntent(mapname, context))
+}
+
+fn setautomntent(mapname: *const c_char, context: *mut *mut c_void) -> nss_status {
+    log("_nss_alexandria_setautomntent()");
+
+    if mapname.is_null() || context.is_null() {
+        return NSS_STATUS_UNAVAIL;
+    }
+
+    let cmapname = unsafe { CStr::from_ptr(mapname) };
+    let map = match cmapname.to_str() {
+        Ok(map) => map,
+        Err(_) => return NSS_STATUS_NOTFOUND,
+    };
+
+    let entries = match routes::automount(map) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log(format!("_nss_alexandria_setautomntent(): error retrieving automount map {} from Alexandria service: {}", map, e).as_str());
             return NSS_STATUS_TRYAGAIN;
-        }
+        },
     };
 
-    let mut spwdl = unsafe { &mut *SPWD_LIST };
+    let b: Box<AutomountContext> = Box::new(
+        AutomountContext {
+            map: map.to_string(),
+            entries: entries,
+        }
+    );
 
-    // cloning is the only reasonable way to go here
-    let e = match spwdl.get_current_entry() {
-        Some(e) => e.clone(),
-        None => {
+    unsafe {
+        // autofs might reuse a context without closing it first
+        if !(*context).is_null() {
+            drop(Box::from_raw(*context as *mut AutomountContext));
+        }
+        *context = Box::into_raw(b) as *mut c_void;
+    }
+
+    NSS_STATUS_SUCCESS
+}
+
+// Called by autofs to close an automount map
+#[no_mangle]
+pub extern "C" fn _nss_alexandria_endautomntent(context: *mut *mut c_void) -> nss_status {
+    util::guard("_nss_alexandria_endautomntent", std::ptr::null_mut(), || endautomntent(context))
+}
+
+fn endautomntent(context: *mut *mut c_void) -> nss_status {
+    log("_nss_alexandria_endautomntent");
+
+    if context.is_null() {
+        return NSS_STATUS_SUCCESS;
+    }
+
+    unsafe {
+        if !(*context).is_null() {
+            drop(Box::from_raw(*context as *mut AutomountContext));
+            *context = std::ptr::null_mut::<c_void>();
+        }
+    }
+
+    NSS_STATUS_SUCCESS
+}
+
+// Called by autofs to look up the next key/value pair in an automount map
+#[no_mangle]
+pub extern "C" fn _nss_alexandria_getautomntent_r(context: *mut c_void, key: *mut *mut c_char, value: *mut *mut c_char, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    util::guard("_nss_alexandria_getautomntent_r", errnop, || getautomntent_r(context, key, value, buffer, buflen, errnop))
+}
+
+fn getautomntent_r(context: *mut c_void, key: *mut *mut c_char, value: *mut *mut c_char, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
+    log("_nss_alexandria_getautomntent_r");
+
+    if context.is_null() {
+        unsafe { *errnop = ENOENT; }
+        return NSS_STATUS_UNAVAIL;
+    }
+
+    let ctx = unsafe { &mut *(context as *mut AutomountContext) };
+
+    // the entry borrows its strings from the list, nothing is copied until it is written
+    let status = match ctx.entries.current::<AlexandriaAutomount>() {
+        Ok(Some(e)) => util::write_automount(&e, key, value, buffer, buflen, errnop),
+        Ok(None) => {
             unsafe { *errnop = ENOENT; }
             return NSS_STATUS_NOTFOUND;
         },
+        Err(e) => {
+            log(format!("_nss_alexandria_getautomntent_r(): error decoding entry: {}", e).as_str());
+            unsafe { *errnop = ENOENT; }
+            return NSS_STATUS_UNAVAIL;
+        },
     };
 
-    // on successful write_passwd, increment index first
-    match util::write_shadow(e, result, buffer, buflen, errnop) {
+    // on successful write_automount, move on to the next entry
+    match status {
         NSS_STATUS_SUCCESS => {
-            spwdl.increment_index();
+            ctx.entries.advance();
             NSS_STATUS_SUCCESS
         },
         status => status
     }
 }
 
+// Find the value for a key in an automount map
 #[no_mangle]
-pub extern "C" fn _nss_alexandria_getspnam_r(name: *const c_char, result: *mut spwd, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
-    log("_nss_alexandria_getspnam_r");
+pub extern "C" fn _nss_alexandria_getautomntbyname_r(context: *mut c_void, key: *const c_char, value: *mut *mut c_char, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    util::guard("_nss_alexandria_getautomntbyname_r", errnop, || getautomntbyname_r(context, key, value, buffer, buflen, errnop))
+}
 
-    let cname = unsafe { CStr::from_ptr(name) };
+fn getautomntbyname_r(context: *mut c_void, key: *const c_char, value: *mut *mut c_char, buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
+    log("_nss_alexandria_getautomntbyname_r");
+
+    if context.is_null() {
+        unsafe { *errnop = ENOENT; }
+        return NSS_STATUS_UNAVAIL;
+    }
 
-    match routes::shadow_name(cname.to_str().unwrap()) {
+    // there is no entry without a key
+    if key.is_null() {
+        unsafe { *errnop = ENOENT; }
+        return NSS_STATUS_NOTFOUND;
+    }
+
+    let ctx = unsafe { &*(context as *mut AutomountContext) };
+    let ckey = unsafe { CStr::from_ptr(key) };
+    let key_str = match ckey.to_str() {
+        Ok(k) => k,
+        Err(_) => {
+            unsafe { *errnop = ENOENT; }
+            return NSS_STATUS_NOTFOUND;
+        },
+    };
+
+    let mut body = String::new();
+    match routes::automount_key(ctx.map.as_str(), key_str, &mut body) {
         Err(e) => {
-            log(format!("_nss_alexandria_getspnam_r(): error retrieving shadow entry from Alexandria service: {}", e).as_str());
+            log(format!("_nss_alexandria_getautomntbyname_r(): error retrieving automount entry from Alexandria service: {}", e).as_str());
             unsafe { *errnop = EAGAIN; }
             NSS_STATUS_TRYAGAIN
         },
@@ -504,7 +1125,270 @@ pub extern "C" fn _nss_alexandria_getspnam_r(name: *const c_char, result: *mut s
                 unsafe { *errnop = ENOENT; }
                 NSS_STATUS_NOTFOUND
             },
-            Some(entry) => util::write_shadow(entry, result, buffer, buflen, errnop),
+            Some(entry) => util::write_automount(&entry, std::ptr::null_mut::<*mut c_char>(), value, buffer, buflen, errnop),
+        },
+    }
+}
+
+// The shadow_subid_* functions below are not part of glibc NSS, but the plugin interface of
+// shadow-utils' libsubid. It loads libsubid_alexandria.so for "subid: alexandria" in
+// /etc/nsswitch.conf, which install.sh links to this library.
+
+// subid_kind maps the libsubid id type to the kind used by the subid route
+fn subid_kind(idtype: c_int) -> Option<&'static str> {
+    match idtype {
+        ID_TYPE_UID => Some("uid"),
+        ID_TYPE_GID => Some("gid"),
+        _ => None,
+    }
+}
+
+// subid_ranges retrieves the ranges of owner and logs on failure
+fn subid_ranges<'b>(func: &str, owner: *const c_char, idtype: c_int, body: &'b mut String) -> Result<Vec<AlexandriaSubid<'b>>, subid_status> {
+    let kind = match subid_kind(idtype) {
+        Some(kind) => kind,
+        None => return Err(SUBID_STATUS_ERROR),
+    };
+
+    if owner.is_null() {
+        return Err(SUBID_STATUS_ERROR);
+    }
+    let cowner = unsafe { CStr::from_ptr(owner) };
+
+    match routes::subid_owner(kind, &canonical::lookup(cowner.to_bytes()), body) {
+        Ok(entries) => Ok(entries),
+        Err(AlexandriaSvcError::InvalidName(_)) => Ok(vec![]),
+        Err(e) => {
+            log(format!("{}(): error retrieving subid ranges from Alexandria service: {}", func, e).as_str());
+            Err(SUBID_STATUS_ERROR_CONN)
+        },
+    }
+}
+
+// Does owner have the whole range [start, start + count) delegated to them?
+#[no_mangle]
+pub extern "C" fn shadow_subid_has_range(owner: *const c_char, start: c_ulong, count: c_ulong, idtype: c_int, result: *mut bool) -> subid_status {
+    util::catch("shadow_subid_has_range", SUBID_STATUS_ERROR, || subid_has_range(owner, start, count, idtype, result))
+}
+
+fn subid_has_range(owner: *const c_char, start: c_ulong, count: c_ulong, idtype: c_int, result: *mut bool) -> subid_status {
+    log("shadow_subid_has_range");
+
+    let mut body = String::new();
+    let entries = match subid_ranges("shadow_subid_has_range", owner, idtype, &mut body) {
+        Ok(entries) => entries,
+        Err(status) => return status,
+    };
+
+    let start = start as u64;
+    let end = start.saturating_add(count as u64);
+    let found = entries.iter().any(|e| e.start <= start && end <= e.start.saturating_add(e.count));
+    unsafe { *result = found; }
+    SUBID_STATUS_SUCCESS
+}
+
+// Does owner have any range delegated to them?
+#[no_mangle]
+pub extern "C" fn shadow_subid_has_any_range(owner: *const c_char, idtype: c_int, result: *mut bool) -> subid_status {
+    util::catch("shadow_subid_has_any_range", SUBID_STATUS_ERROR, || subid_has_any_range(owner, idtype, result))
+}
+
+fn subid_has_any_range(owner: *const c_char, idtype: c_int, result: *mut bool) -> subid_status {
+    log("shadow_subid_has_any_range");
+
+    let mut body = String::new();
+    let entries = match subid_ranges("shadow_subid_has_any_range", owner, idtype, &mut body) {
+        Ok(entries) => entries,
+        Err(status) => return status,
+    };
+
+    unsafe { *result = !entries.is_empty(); }
+    SUBID_STATUS_SUCCESS
+}
+
+// List all ranges delegated to owner
+#[no_mangle]
+pub extern "C" fn shadow_subid_list_owner_ranges(owner: *const c_char, idtype: c_int, ranges: *mut *mut subid_range, count: *mut c_int) -> subid_status {
+    util::catch("shadow_subid_list_owner_ranges", SUBID_STATUS_ERROR, || subid_list_owner_ranges(owner, idtype, ranges, count))
+}
+
+fn subid_list_owner_ranges(owner: *const c_char, idtype: c_int, ranges: *mut *mut subid_range, count: *mut c_int) -> subid_status {
+    log("shadow_subid_list_owner_ranges");
+
+    let mut body = String::new();
+    let entries = match subid_ranges("shadow_subid_list_owner_ranges", owner, idtype, &mut body) {
+        Ok(entries) => entries,
+        Err(status) => return status,
+    };
+
+    util::write_subid_ranges(&entries, ranges, count)
+}
+
+// Find the uids of all owners which have id delegated to them
+#[no_mangle]
+pub extern "C" fn shadow_subid_find_subid_owners(id: c_ulong, idtype: c_int, uids: *mut *mut uid_t, count: *mut c_int) -> subid_status {
+    util::catch("shadow_subid_find_subid_owners", SUBID_STATUS_ERROR, || subid_find_subid_owners(id, idtype, uids, count))
+}
+
+fn subid_find_subid_owners(id: c_ulong, idtype: c_int, uids: *mut *mut uid_t, count: *mut c_int) -> subid_status {
+    log("shadow_subid_find_subid_owners");
+
+    let kind = match subid_kind(idtype) {
+        Some(kind) => kind,
+        None => return SUBID_STATUS_ERROR,
+    };
+
+    let mut body = String::new();
+    let entries = match routes::subid_id(kind, id as u64, &mut body) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log(format!("shadow_subid_find_subid_owners(): error retrieving subid ranges from Alexandria service: {}", e).as_str());
+            return SUBID_STATUS_ERROR_CONN;
         },
+    };
+
+    // like in /etc/subuid an owner is either a user name or a uid
+    let mut owners: Vec<uid_t> = Vec::with_capacity(entries.len());
+    for e in entries {
+        let owner = e.owner.as_bytes();
+        let mut pwd_body = String::new();
+        let uid = match str::from_utf8(&owner).ok().and_then(|o| o.parse::<uid_t>().ok()) {
+            Some(uid) => match idmap::uid_to_host(uid) {
+                Some(uid) => uid,
+                None => continue,
+            },
+            None => match routes::passwd_name(&owner, &mut pwd_body) {
+                Ok(Some(pwd)) => match idmap::passwd(pwd) {
+                    Some(pwd) => pwd.pw_uid,
+                    None => continue,
+                },
+                Ok(None) | Err(AlexandriaSvcError::InvalidName(_)) => continue,
+                Err(err) => {
+                    log(format!("shadow_subid_find_subid_owners(): error retrieving passwd entry from Alexandria service: {}", err).as_str());
+                    return SUBID_STATUS_ERROR_CONN;
+                },
+            },
+        };
+        if !owners.contains(&uid) {
+            owners.push(uid);
+        }
+    }
+
+    util::write_subid_owners(owners, uids, count)
+}
+
+// Free memory which was handed to libsubid by one of the functions above
+#[no_mangle]
+pub extern "C" fn shadow_subid_free(ptr: *mut c_void) {
+    unsafe { libc::free(ptr); }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use std::mem;
+    use std::panic;
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use libc;
+    use libc::c_char;
+    use context;
+    use decode;
+    use decode::List;
+    use domains;
+    use overlay::Overlay;
+    use routes;
+    use types::AlexandriaPassword;
+    use types::nss_status;
+    use super::{Enumeration, PWD_LIST, lock};
+
+    const USERS: &'static str = r#"[{"pw_name":"alice","pw_uid":1000,"pw_gid":1000,"pw_dir":"/home/alice"},
+                                    {"pw_name":"bob","pw_uid":1001,"pw_gid":1000,"pw_dir":"/home/bob"}]"#;
+
+    fn enumeration() -> Enumeration {
+        let entries = domains::merge("passwd", None, |_| {
+            List::new(USERS.to_string(), |b| decode::count::<AlexandriaPassword>(b)).map_err(From::from)
+        }).unwrap();
+        // an empty overlay, so that no files on this host add entries before the list's
+        Enumeration::new(entries, Arc::new(Overlay::default()), 0)
+    }
+
+    // child runs in the forked process, it must not panic into the test harness of the copy
+    fn child() -> bool {
+        // the list of the parent is gone, getpwent starts over
+        if lock(&PWD_LIST).is_some() {
+            return false;
+        }
+        // the name of the parent is forgotten as well
+        unsafe { libc::prctl(libc::PR_SET_NAME, b"alx-fork-child\0".as_ptr()); }
+        if context::comm() != Some(b"alx-fork-child".to_vec()) {
+            return false;
+        }
+        // there is no service to answer, but the locks must be free
+        super::_nss_alexandria_setpwent(0);
+        super::_nss_alexandria_endpwent();
+
+        *lock(&PWD_LIST) = Some(enumeration());
+        let mut pw: libc::passwd = unsafe { mem::zeroed() };
+        let mut buf = [0 as c_char; 1024];
+        let mut err = 0;
+        match super::_nss_alexandria_getpwent_r(&mut pw, buf.as_mut_ptr(), buf.len(), &mut err) {
+            nss_status::NSS_STATUS_SUCCESS => unsafe { CStr::from_ptr(pw.pw_name) }.to_bytes() == b"alice",
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn fork_during_enumeration() {
+        let mut parent = enumeration();
+        parent.entries.advance();
+        assert!(context::comm().is_some());
+        *lock(&PWD_LIST) = Some(parent);
+
+        // another thread is in the middle of an enumeration call while this one forks
+        let (locked, wait) = mpsc::channel();
+        let holder = thread::spawn(move || {
+            let _state = lock(&PWD_LIST);
+            locked.send(()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+        });
+        wait.recv().unwrap();
+
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            unsafe {
+                // a deadlock ends with SIGALRM instead of hanging the tests
+                libc::alarm(10);
+                let ok = panic::catch_unwind(child).unwrap_or(false);
+                libc::_exit(if ok { 0 } else { 1 });
+            }
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        let exited = unsafe { libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 };
+        assert!(exited, "child failed with status {}", status);
+        holder.join().unwrap();
+
+        // the parent keeps its list where it was
+        let state = lock(&PWD_LIST);
+        let (_, json) = state.as_ref().unwrap().entries.raw().unwrap();
+        assert!(json.contains("\"bob\""));
+    }
+
+    // a panic while the route is asked can't unwind into the caller of getpwnam
+    #[test]
+    fn panicking_route() {
+        let name = CString::new("alice").unwrap();
+        let mut pw: libc::passwd = unsafe { mem::zeroed() };
+        let mut buf = [0 as c_char; 1024];
+        let mut err = 0;
+        routes::PANIC.with(|p| p.set(true));
+        let status = super::_nss_alexandria_getpwnam_r(name.as_ptr(), &mut pw, buf.as_mut_ptr(), buf.len(), &mut err);
+        routes::PANIC.with(|p| p.set(false));
+        assert!(match status { nss_status::NSS_STATUS_UNAVAIL => true, _ => false });
+        assert_eq!(err, libc::ENOENT);
     }
 }
@@ -0,0 +1,512 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// The cache file of config::MCACHE_PATH, which every process maps read-only and looks entries up
+// in before it asks the Alexandria service. alexandria-cache writes it with write().
+//
+// The file holds the passwd and group entries of the default directory as the service sent them,
+// so that they go through the same idmap, filters and overlay as the ones of a request. It is
+// never changed in place: a new file is written next to it and renamed over it, so a process sees
+// either the old or the new one as a whole, and maps the new one once it notices the rename.
+//
+// Layout, all numbers in native byte order:
+//   header   magic, version, expiry (seconds since the epoch), then per table the number of
+//            buckets and records and the offsets of its name and id index, then the generation
+//            of the directory the entries are of, u64::MAX if it is unknown
+//   indexes  per table, two arrays of bucket heads: the offset of the first record whose name
+//            or id hashes into the bucket, 0 if there is none
+//   records  next record by name, next record by id, id, length of the name, length of the
+//            JSON, the name and the JSON, padded to 4 bytes
+
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::null_mut;
+use std::slice;
+use std::str;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use libc::c_void;
+use libc::{mmap, munmap, MAP_FAILED, MAP_SHARED, PROT_READ};
+use libc::O_NOFOLLOW;
+use config::{MCACHE_PATH, MCACHE_TTL_S};
+use domains::DEFAULT;
+use generation;
+use metrics;
+use types::Domain;
+use util;
+use util::log;
+
+// try! for the Options of the checked reads below
+macro_rules! try_opt {
+    ($e:expr) => (match $e { Some(v) => v, None => return None })
+}
+
+const MAGIC: &'static [u8; 8] = b"ALXCACHE";
+const VERSION: u32 = 2;
+const HEADER_SIZE: usize = 64;
+// where the table headers start, each of them is 4 u32
+const TABLES_AT: usize = 24;
+const GENERATION_AT: usize = 56;
+const RECORD_SIZE: usize = 20;
+
+#[derive(Clone, Copy)]
+pub enum Table {
+    Passwd = 0,
+    Group = 1,
+}
+
+pub enum Key<'k> {
+    Name(&'k [u8]),
+    Id(u32),
+}
+
+// Record is an entry for write()
+pub struct Record {
+    pub name: Vec<u8>,
+    pub id: u32,
+    pub json: String,
+}
+
+// FNV-1a, which is good enough for names and cheap to compute
+fn hash(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5u32, |h, &b| (h ^ b as u32).wrapping_mul(0x01000193))
+}
+
+fn hash_id(id: u32) -> u32 {
+    hash(&id.to_ne_bytes())
+}
+
+fn u32_at(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..at + 4).map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn u64_at(data: &[u8], at: usize) -> Option<u64> {
+    data.get(at..at + 8).map(|b| u64::from_ne_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+}
+
+fn put_u32(data: &mut [u8], at: usize, v: u32) {
+    data[at..at + 4].copy_from_slice(&v.to_ne_bytes());
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// trusted reports if only root can have written the file or directory of meta
+fn trusted(meta: &fs::Metadata) -> bool {
+    meta.uid() == 0 && meta.mode() & 0o022 == 0
+}
+
+// Mapping is the cache file mapped into memory, which stays valid until it is dropped even if
+// the file is replaced in the meantime
+struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+    // the file which was mapped, a different one at MCACHE_PATH is mapped instead
+    dev: u64,
+    ino: u64,
+}
+
+// the mapping is read-only
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr, self.len); }
+    }
+}
+
+impl Mapping {
+    fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    // open maps the cache file at path. Its entries are served to every process, including setuid
+    // ones like su and sudo, so it and its directory must be owned by root and writable by nobody
+    // else.
+    fn open(path: &str) -> io::Result<Mapping> {
+        let f = try!(fs::File::open(path));
+        let meta = try!(f.metadata());
+        let dir = try!(fs::metadata(Path::new(path).parent().unwrap_or(Path::new("/"))));
+        if !trusted(&meta) || !trusted(&dir) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "the file or its directory is not owned by root or writable by others"));
+        }
+        Mapping::map(&f, &meta)
+    }
+
+    fn map(f: &fs::File, meta: &fs::Metadata) -> io::Result<Mapping> {
+        let len = meta.len() as usize;
+        if len < HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "too short"));
+        }
+        let ptr = unsafe { mmap(null_mut(), len, PROT_READ, MAP_SHARED, f.as_raw_fd(), 0) };
+        if ptr == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let mapping = Mapping {
+            ptr: ptr,
+            len: len,
+            dev: meta.dev(),
+            ino: meta.ino(),
+        };
+        if &mapping.data()[..8] != MAGIC || u32_at(mapping.data(), 8) != Some(VERSION) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a cache file of this version"));
+        }
+        Ok(mapping)
+    }
+
+    fn expires(&self) -> u64 {
+        u64_at(self.data(), 16).unwrap_or(0)
+    }
+
+    fn generation(&self) -> Option<u64> {
+        u64_at(self.data(), GENERATION_AT).and_then(|g| if g == u64::max_value() { None } else { Some(g) })
+    }
+
+    // find returns the JSON of the first record of table with key. Every offset is checked, so
+    // that a broken file can't make a lookup read beyond the mapping or loop forever.
+    fn find(&self, table: Table, key: &Key) -> Option<&str> {
+        let data = self.data();
+        let at = TABLES_AT + table as usize * 16;
+        let buckets = try_opt!(u32_at(data, at));
+        let count = try_opt!(u32_at(data, at + 4));
+        if buckets == 0 {
+            return None;
+        }
+        let (index, next_at, hashed) = match *key {
+            Key::Name(name) => (try_opt!(u32_at(data, at + 8)), 0, hash(name)),
+            Key::Id(id) => (try_opt!(u32_at(data, at + 12)), 4, hash_id(id)),
+        };
+        let mut record = try_opt!(u32_at(data, index as usize + (hashed % buckets) as usize * 4)) as usize;
+        for _ in 0..count {
+            if record == 0 {
+                return None;
+            }
+            let id = try_opt!(u32_at(data, record + 8));
+            let name_len = try_opt!(u32_at(data, record + 12)) as usize;
+            let json_len = try_opt!(u32_at(data, record + 16)) as usize;
+            let name_at = record + RECORD_SIZE;
+            let json_at = name_at + name_len;
+            let matches = match *key {
+                Key::Name(name) => data.get(name_at..json_at) == Some(name),
+                Key::Id(want) => id == want,
+            };
+            if matches {
+                return data.get(json_at..json_at + json_len).and_then(|json| str::from_utf8(json).ok());
+            }
+            record = try_opt!(u32_at(data, record + next_at)) as usize;
+        }
+        None
+    }
+}
+
+static MAPPED: Mutex<Option<Arc<Mapping>>> = Mutex::new(None);
+
+fn lock() -> MutexGuard<'static, Option<Arc<Mapping>>> {
+    util::atfork_once();
+    MAPPED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// mapping returns the current cache file, which is mapped again if it was replaced. A stat is
+// all that a lookup costs on top of the hash lookup itself.
+fn mapping(path: &str) -> Option<Arc<Mapping>> {
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => {
+            *lock() = None;
+            return None;
+        },
+    };
+    let mut mapped = lock();
+    if let Some(ref m) = *mapped {
+        if m.dev == meta.dev() && m.ino == meta.ino() {
+            return Some(m.clone());
+        }
+    }
+    *mapped = match Mapping::open(path) {
+        Ok(m) => Some(Arc::new(m)),
+        Err(e) => {
+            log(format!("can't map cache file {}: {}", path, e).as_str());
+            None
+        },
+    };
+    mapped.clone()
+}
+
+// find copies the JSON of the entry of table with key into body, if the cache of domain has it.
+// Only the default directory is cached, and a cache whose writer stopped updating it is ignored,
+// as is one of another generation of the directory until it is replaced.
+pub fn find(domain: &Domain, table: Table, key: Key, body: &mut String) -> bool {
+    let path = match MCACHE_PATH {
+        Some(path) if domain.name.is_empty() => path,
+        _ => return false,
+    };
+    let m = match mapping(path) {
+        Some(m) => m,
+        None => return false,
+    };
+    if m.expires() < now() {
+        return false;
+    }
+    let database = match table { Table::Passwd => "passwd", Table::Group => "group" };
+    if generation::changed(domain, m.generation()) {
+        metrics::cache(database, false);
+        return false;
+    }
+    let json = m.find(table, &key);
+    metrics::cache(database, json.is_some());
+    match json {
+        Some(json) => {
+            body.push_str(json);
+            true
+        },
+        None => false,
+    }
+}
+
+// status describes the cache file for alexandria-check
+pub fn status() -> Result<String, String> {
+    let path = match MCACHE_PATH {
+        Some(path) => path,
+        None => return Ok("not configured".to_string()),
+    };
+    let m = try!(Mapping::open(path).map_err(|e| format!("{}: {}", path, e)));
+    let count = |table: Table| u32_at(m.data(), TABLES_AT + table as usize * 16 + 4).unwrap_or(0);
+    let (expires, now) = (m.expires(), now());
+    if expires < now {
+        return Err(format!("{}: expired {}s ago, is alexandria-cache running?", path, now - expires));
+    }
+    let generation = match m.generation() {
+        Some(g) if generation::changed(&DEFAULT, Some(g)) => return Err(format!("{}: generation {} is outdated, is alexandria-cache running?", path, g)),
+        Some(g) => format!("generation {}", g),
+        None => "unknown generation".to_string(),
+    };
+    Ok(format!("{}: {} passwd and {} group entries, {}, expires in {}s", path, count(Table::Passwd), count(Table::Group), generation, expires - now))
+}
+
+// table appends the indexes and records of one table to data, and fills in its table header
+fn table(data: &mut Vec<u8>, table: Table, records: &[Record]) {
+    let buckets = (records.len() * 2).max(1);
+    let name_index = data.len();
+    let id_index = name_index + buckets * 4;
+    data.resize(id_index + buckets * 4, 0);
+
+    for r in records {
+        let at = data.len();
+        let name_head = name_index + (hash(&r.name) as usize % buckets) * 4;
+        let id_head = id_index + (hash_id(r.id) as usize % buckets) * 4;
+        let (next_name, next_id) = (u32_at(data, name_head).unwrap_or(0), u32_at(data, id_head).unwrap_or(0));
+        for v in &[next_name, next_id, r.id, r.name.len() as u32, r.json.len() as u32] {
+            data.extend_from_slice(&v.to_ne_bytes());
+        }
+        data.extend_from_slice(&r.name);
+        data.extend_from_slice(r.json.as_bytes());
+        while data.len() % 4 != 0 {
+            data.push(0);
+        }
+        // the records which come later are found first
+        put_u32(data, name_head, at as u32);
+        put_u32(data, id_head, at as u32);
+    }
+
+    let header = TABLES_AT + table as usize * 16;
+    for (i, v) in [buckets as u32, records.len() as u32, name_index as u32, id_index as u32].iter().enumerate() {
+        put_u32(data, header + i * 4, *v);
+    }
+}
+
+// write replaces the cache file at path with the given entries, which expire after MCACHE_TTL_S.
+// generation is the one of the directory read before the entries were fetched.
+pub fn write(path: &str, generation: Option<u64>, passwd: &[Record], group: &[Record]) -> io::Result<()> {
+    let mut data = vec![0u8; HEADER_SIZE];
+    data[..8].copy_from_slice(MAGIC);
+    put_u32(&mut data, 8, VERSION);
+    data[16..24].copy_from_slice(&(now() + MCACHE_TTL_S).to_ne_bytes());
+    data[GENERATION_AT..GENERATION_AT + 8].copy_from_slice(&generation.unwrap_or(u64::max_value()).to_ne_bytes());
+    table(&mut data, Table::Passwd, passwd);
+    table(&mut data, Table::Group, group);
+    if data.len() > u32::max_value() as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the entries don't fit into a cache file"));
+    }
+
+    // created anew under a random name without following symlinks, like the metrics files, so that
+    // a link in a shared directory can't make root overwrite the file it points to
+    let tmp = format!("{}.{:016x}.tmp", path, util::temp_suffix());
+    let written = fs::OpenOptions::new().write(true).create_new(true).mode(0o644).custom_flags(O_NOFOLLOW).open(&tmp)
+        .and_then(|mut f| f.write_all(&data).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+// The lock is taken before a fork like the ones in lib.rs, so that the child doesn't inherit it
+// locked. The mapping itself is inherited and stays valid.
+thread_local!(static FORK_GUARD: RefCell<Option<MutexGuard<'static, Option<Arc<Mapping>>>>> = RefCell::new(None));
+
+pub fn atfork_prepare() {
+    FORK_GUARD.with(|guard| *guard.borrow_mut() = Some(lock()));
+}
+
+pub fn atfork_release() {
+    FORK_GUARD.with(|guard| guard.borrow_mut().take());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::DirBuilderExt;
+    use std::process;
+    use super::{hash, hash_id, write, Key, Mapping, Record, Table, TABLES_AT};
+
+    fn record(name: &str, id: u32) -> Record {
+        Record {
+            name: name.as_bytes().to_vec(),
+            id: id,
+            json: format!(r#"{{"pw_name":"{}","pw_uid":{}}}"#, name, id),
+        }
+    }
+
+    // cache writes passwd and group into a file of its own, test names the file
+    fn cache(test: &str, passwd: &[Record], group: &[Record]) -> String {
+        let dir = format!("/tmp/alx-mcache-{}-{}", test, process::id());
+        let _ = fs::remove_dir_all(&dir);
+        fs::DirBuilder::new().mode(0o755).create(&dir).unwrap();
+        let path = format!("{}/nss.cache", dir);
+        write(&path, Some(7), passwd, group).unwrap();
+        path
+    }
+
+    // map maps the file at path without the ownership checks of Mapping::open, which the tests
+    // can't pass unless they run as root
+    fn map(path: &str) -> io::Result<Mapping> {
+        let f = try!(fs::File::open(path));
+        let meta = try!(f.metadata());
+        Mapping::map(&f, &meta)
+    }
+
+    fn cleanup(path: &str) {
+        fs::remove_dir_all(&path[..path.rfind('/').unwrap()]).unwrap();
+    }
+
+    // colliding returns two names and two ids which share a bucket of a table of two records
+    fn colliding() -> ((String, String), (u32, u32)) {
+        let buckets = 4;
+        let names: Vec<String> = (0..).map(|i| format!("user{}", i)).take(buckets + 1).collect();
+        let (a, b) = (0..names.len()).flat_map(|a| (a + 1..names.len()).map(move |b| (a, b)))
+            .find(|&(a, b)| hash(names[a].as_bytes()) % buckets as u32 == hash(names[b].as_bytes()) % buckets as u32)
+            .unwrap();
+        let ids: Vec<u32> = (1000..1000 + buckets as u32 + 1).collect();
+        let (c, d) = (0..ids.len()).flat_map(|c| (c + 1..ids.len()).map(move |d| (c, d)))
+            .find(|&(c, d)| hash_id(ids[c]) % buckets as u32 == hash_id(ids[d]) % buckets as u32)
+            .unwrap();
+        ((names[a].clone(), names[b].clone()), (ids[c], ids[d]))
+    }
+
+    #[test]
+    fn round_trip() {
+        let ((a, b), (ia, ib)) = colliding();
+        let path = cache("round-trip", &[record(&a, ia), record(&b, ib)], &[]);
+        let m = map(&path).unwrap();
+        assert_eq!(m.generation(), Some(7));
+
+        // both records of the shared buckets are found, by name and by id
+        for &(name, id) in &[(&a, ia), (&b, ib)] {
+            let json = record(name, id).json;
+            assert_eq!(m.find(Table::Passwd, &Key::Name(name.as_bytes())), Some(json.as_str()));
+            assert_eq!(m.find(Table::Passwd, &Key::Id(id)), Some(json.as_str()));
+        }
+        assert_eq!(m.find(Table::Passwd, &Key::Name(b"mallory")), None);
+        assert_eq!(m.find(Table::Passwd, &Key::Id(1)), None);
+
+        // the group table is empty
+        assert_eq!(m.find(Table::Group, &Key::Name(a.as_bytes())), None);
+        assert_eq!(m.find(Table::Group, &Key::Id(ia)), None);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn broken_files() {
+        let ((a, b), (ia, ib)) = colliding();
+        let path = cache("broken", &[record(&a, ia), record(&b, ib)], &[]);
+        let data = fs::read(&path).unwrap();
+        let lookups = |m: &Mapping| {
+            m.find(Table::Passwd, &Key::Name(a.as_bytes())).is_none() && m.find(Table::Passwd, &Key::Name(b.as_bytes())).is_none() &&
+                m.find(Table::Passwd, &Key::Id(ia)).is_none() && m.find(Table::Passwd, &Key::Id(ib)).is_none()
+        };
+
+        // the JSON of the last record is cut off, the one before it is still there
+        fs::write(&path, &data[..data.len() - 24]).unwrap();
+        let m = map(&path).unwrap();
+        assert_eq!(m.find(Table::Passwd, &Key::Name(b.as_bytes())), None);
+        assert_eq!(m.find(Table::Passwd, &Key::Id(ib)), None);
+        assert_eq!(m.find(Table::Passwd, &Key::Name(a.as_bytes())), Some(record(&a, ia).json.as_str()));
+        drop(m);
+        fs::write(&path, &data[..super::HEADER_SIZE]).unwrap();
+        assert!(lookups(&map(&path).unwrap()));
+
+        // the indexes point beyond the file
+        let mut broken = data.clone();
+        let index = super::u32_at(&data, TABLES_AT + 8).unwrap() as usize;
+        for at in (index..index + 32).step_by(4) {
+            super::put_u32(&mut broken, at, u32::max_value() - 2);
+        }
+        fs::write(&path, &broken).unwrap();
+        assert!(lookups(&map(&path).unwrap()));
+
+        // every record points to itself, the lookups end anyway
+        let mut broken = data.clone();
+        let records = index + 32;
+        let mut at = records;
+        while at + super::RECORD_SIZE <= data.len() {
+            super::put_u32(&mut broken, at, at as u32);
+            super::put_u32(&mut broken, at + 4, at as u32);
+            let len = super::RECORD_SIZE + super::u32_at(&data, at + 12).unwrap() as usize + super::u32_at(&data, at + 16).unwrap() as usize;
+            at += (len + 3) / 4 * 4;
+        }
+        fs::write(&path, &broken).unwrap();
+        let m = map(&path).unwrap();
+        assert_eq!(m.find(Table::Passwd, &Key::Name(b"mallory")), None);
+        assert_eq!(m.find(Table::Passwd, &Key::Id(1)), None);
+
+        // not a cache file, or too short for one
+        fs::write(&path, vec![0u8; 128]).unwrap();
+        assert!(map(&path).is_err());
+        fs::write(&path, &data[..8]).unwrap();
+        assert!(map(&path).is_err());
+        cleanup(&path);
+    }
+
+    #[test]
+    fn untrusted_files() {
+        let path = cache("untrusted", &[record("alice", 1000)], &[]);
+        // a file in a directory which others may write to
+        let shared = format!("/tmp/alx-mcache-untrusted-{}.cache", process::id());
+        fs::copy(&path, &shared).unwrap();
+        assert_eq!(Mapping::open(&shared).err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+        fs::remove_file(&shared).unwrap();
+        cleanup(&path);
+    }
+}
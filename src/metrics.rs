@@ -0,0 +1,282 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// Metrics of the lookups made by this process.
+//
+// util::catch records every call of an exported function: what was looked up, how it ended and
+// how long it took. The counters live in atomics, so recording never blocks. There is no thread
+// of our own in the caller's process, so the counters are flushed by the first call which ends
+// METRICS_FLUSH_INTERVAL_S after the previous flush, either to the Alexandria service or to a
+// stats file per process, see config::METRICS_SINK. Flushes always carry the totals since the
+// process started (or forked).
+
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use libc::getpid;
+use libc::{O_NOFOLLOW, S_ISVTX};
+use config::{METRICS_SINK, METRICS_FLUSH_INTERVAL_S, METRICS_DIR};
+use routes;
+use types::MetricsSink;
+use types::nss_status;
+use types::subid_status;
+use util;
+use util::debug;
+
+const DATABASES: [&'static str; 5] = ["passwd", "group", "shadow", "automount", "subid"];
+const KEYS: [&'static str; 3] = ["id", "name", "enum"];
+const OUTCOMES: [&'static str; 5] = ["success", "notfound", "tryagain", "unavail", "error"];
+// upper bounds of the latency buckets in microseconds, the last bucket takes everything above
+const BUCKETS_US: [u64; 11] = [100, 250, 500, 1000, 2500, 5000, 10000, 25000, 50000, 100000, 250000];
+
+#[derive(Clone, Copy)]
+pub enum Outcome {
+    Success,
+    NotFound,
+    TryAgain,
+    Unavail,
+    Error,
+}
+
+// Status is implemented by the return types of the exported functions
+pub trait Status {
+    fn outcome(&self) -> Outcome;
+}
+
+impl Status for nss_status {
+    fn outcome(&self) -> Outcome {
+        match *self {
+            nss_status::NSS_STATUS_SUCCESS | nss_status::NSS_STATUS_RETURN => Outcome::Success,
+            nss_status::NSS_STATUS_NOTFOUND => Outcome::NotFound,
+            nss_status::NSS_STATUS_TRYAGAIN => Outcome::TryAgain,
+            nss_status::NSS_STATUS_UNAVAIL => Outcome::Unavail,
+        }
+    }
+}
+
+impl Status for subid_status {
+    fn outcome(&self) -> Outcome {
+        match *self {
+            subid_status::SUBID_STATUS_SUCCESS => Outcome::Success,
+            subid_status::SUBID_STATUS_UNKNOWN_USER => Outcome::NotFound,
+            subid_status::SUBID_STATUS_ERROR_CONN => Outcome::Unavail,
+            subid_status::SUBID_STATUS_ERROR => Outcome::Error,
+        }
+    }
+}
+
+struct Counters {
+    lookups: [AtomicUsize; 3],
+    outcomes: [AtomicUsize; 5],
+    // NSS_STATUS_TRYAGAIN with ERANGE, the caller retries with a bigger buffer
+    erange: AtomicUsize,
+    // enumerations which rewound a fresh list instead of fetching it again, see config::STAYOPEN_TTL_S,
+    // and lookups which were found in the cache file, see config::MCACHE_PATH
+    cache_hits: AtomicUsize,
+    cache_misses: AtomicUsize,
+    latency: [AtomicUsize; 12],
+}
+
+macro_rules! zeros {
+    ($($n:expr),*) => { [$(AtomicUsize::new($n)),*] }
+}
+
+impl Counters {
+    const fn new() -> Counters {
+        Counters {
+            lookups: zeros!(0, 0, 0),
+            outcomes: zeros!(0, 0, 0, 0, 0),
+            erange: AtomicUsize::new(0),
+            cache_hits: AtomicUsize::new(0),
+            cache_misses: AtomicUsize::new(0),
+            latency: zeros!(0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0),
+        }
+    }
+
+    fn reset(&self) {
+        for c in self.lookups.iter().chain(self.outcomes.iter()).chain(self.latency.iter()) {
+            c.store(0, Ordering::Relaxed);
+        }
+        self.erange.store(0, Ordering::Relaxed);
+        self.cache_hits.store(0, Ordering::Relaxed);
+        self.cache_misses.store(0, Ordering::Relaxed);
+    }
+}
+
+static COUNTERS: [Counters; 5] = [Counters::new(), Counters::new(), Counters::new(), Counters::new(), Counters::new()];
+// seconds since the epoch of the last flush, 0 before the first call
+static LAST_FLUSH: AtomicUsize = AtomicUsize::new(0);
+// seconds since the epoch when counting started
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+// lookup returns the database and the key type of the lookup made by the exported function func,
+// None for functions which don't look anything up like set*ent and end*ent
+fn lookup(func: &str) -> Option<(usize, usize)> {
+    let (db, key) = match func.trim_start_matches("_nss_alexandria_") {
+        "getpwuid_r" => (0, 0),
+        "getpwnam_r" => (0, 1),
+        "getpwent_r" => (0, 2),
+        "getgrgid_r" => (1, 0),
+        "getgrnam_r" => (1, 1),
+        "getgrent_r" => (1, 2),
+        "getspnam_r" => (2, 1),
+        "getspent_r" => (2, 2),
+        "getautomntbyname_r" => (3, 1),
+        "getautomntent_r" => (3, 2),
+        "shadow_subid_has_range" | "shadow_subid_has_any_range" | "shadow_subid_list_owner_ranges" => (4, 1),
+        "shadow_subid_find_subid_owners" => (4, 0),
+        _ => return None,
+    };
+    Some((db, key))
+}
+
+fn now_s() -> usize {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as usize
+}
+
+// record counts a call of the exported function func
+pub fn record(func: &str, outcome: Outcome, elapsed: Duration) {
+    if let Some((db, key)) = lookup(func) {
+        let c = &COUNTERS[db];
+        c.lookups[key].fetch_add(1, Ordering::Relaxed);
+        c.outcomes[outcome as usize].fetch_add(1, Ordering::Relaxed);
+        let us = elapsed.as_secs().saturating_mul(1_000_000).saturating_add(elapsed.subsec_nanos() as u64 / 1000);
+        let bucket = BUCKETS_US.iter().position(|&b| us <= b).unwrap_or(BUCKETS_US.len());
+        c.latency[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+    if METRICS_SINK != MetricsSink::Off {
+        maybe_flush();
+    }
+}
+
+// erange counts a call of func which asked the caller for a bigger buffer
+pub fn erange(func: &str) {
+    if let Some((db, _)) = lookup(func) {
+        COUNTERS[db].erange.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// cache counts whether an enumeration of database could reuse its list, or whether a lookup was
+// found in the cache file of mcache.rs
+pub fn cache(database: &str, hit: bool) {
+    if let Some(db) = DATABASES.iter().position(|&d| d == database) {
+        if hit {
+            COUNTERS[db].cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            COUNTERS[db].cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// reset starts counting over, a forked child must not report the lookups of its parent
+pub fn reset() {
+    for c in COUNTERS.iter() {
+        c.reset();
+    }
+    LAST_FLUSH.store(0, Ordering::Relaxed);
+    STARTED.store(0, Ordering::Relaxed);
+}
+
+fn maybe_flush() {
+    let now = now_s();
+    let last = LAST_FLUSH.load(Ordering::Relaxed);
+    if last == 0 {
+        // counting starts with the first call, so that every process flushes after the interval
+        if LAST_FLUSH.compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            STARTED.store(now, Ordering::Relaxed);
+        }
+        return;
+    }
+    if now < last + METRICS_FLUSH_INTERVAL_S as usize {
+        return;
+    }
+    // only the thread which moves LAST_FLUSH forward flushes
+    if LAST_FLUSH.compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+        return;
+    }
+    // this runs after the caller's panic was caught, so it must not panic itself
+    let _ = panic::catch_unwind(flush);
+}
+
+fn flush() {
+    let pid = unsafe { getpid() };
+    let json = to_json(pid);
+    let ret = match METRICS_SINK {
+        MetricsSink::Off => return,
+        MetricsSink::Route => routes::metrics(json.as_str()).map_err(|e| e.to_string()),
+        MetricsSink::File => write_file(pid, json.as_str()).map_err(|e| e.to_string()),
+    };
+    if let Err(e) = ret {
+        debug(format!("flushing metrics failed: {}", e).as_str());
+    }
+}
+
+// write_file replaces METRICS_DIR/<pid>.json, readers never see a partially written file.
+// Everybody may write to METRICS_DIR, so it must be sticky, and the temporary file is created
+// anew under a random name without following symlinks. A link which another user put in its
+// place can't make a root process overwrite the file it points to.
+fn write_file(pid: i32, json: &str) -> io::Result<()> {
+    let meta = try!(fs::metadata(METRICS_DIR));
+    if !meta.is_dir() || meta.mode() & S_ISVTX as u32 == 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is not a sticky directory", METRICS_DIR)));
+    }
+    let path = format!("{}/{}.json", METRICS_DIR, pid);
+    let tmp = format!("{}.{:016x}.tmp", path, util::temp_suffix());
+    let written = fs::OpenOptions::new().write(true).create_new(true).mode(0o644).custom_flags(O_NOFOLLOW).open(&tmp)
+        .and_then(|mut f| f.write_all(json.as_bytes()))
+        .and_then(|_| fs::rename(&tmp, &path));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+// to_json renders all counters, e.g.
+// {"pid":42,"started":1476000000,"databases":{"passwd":{"lookups":{"id":1,...},
+// "results":{"success":1,...,"erange":0},"cache":{"hits":0,"misses":1},
+// "latency_us":{"100":0,...,"inf":0}},...}}
+fn to_json(pid: i32) -> String {
+    let mut s = String::new();
+    let _ = write!(s, "{{\"pid\":{},\"started\":{},\"databases\":{{", pid, STARTED.load(Ordering::Relaxed));
+    for (i, db) in DATABASES.iter().enumerate() {
+        let c = &COUNTERS[i];
+        if i > 0 {
+            s.push(',');
+        }
+        let _ = write!(s, "\"{}\":{{\"lookups\":{{", db);
+        for (k, key) in KEYS.iter().enumerate() {
+            let _ = write!(s, "{}\"{}\":{}", if k > 0 { "," } else { "" }, key, c.lookups[k].load(Ordering::Relaxed));
+        }
+        s.push_str("},\"results\":{");
+        for (o, outcome) in OUTCOMES.iter().enumerate() {
+            let _ = write!(s, "\"{}\":{},", outcome, c.outcomes[o].load(Ordering::Relaxed));
+        }
+        let _ = write!(s, "\"erange\":{}}},\"cache\":{{\"hits\":{},\"misses\":{}}},\"latency_us\":{{",
+                       c.erange.load(Ordering::Relaxed), c.cache_hits.load(Ordering::Relaxed), c.cache_misses.load(Ordering::Relaxed));
+        for (b, bound) in BUCKETS_US.iter().enumerate() {
+            let _ = write!(s, "\"{}\":{},", bound, c.latency[b].load(Ordering::Relaxed));
+        }
+        let _ = write!(s, "\"inf\":{}}}}}", c.latency[BUCKETS_US.len()].load(Ordering::Relaxed));
+    }
+    s.push_str("}}");
+    s
+}
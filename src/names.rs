@@ -0,0 +1,127 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// User and group names are bytes to glibc, but strings to the Alexandria service.
+//
+// Names which are valid UTF-8 are used as they are. What happens to all other names depends on
+// config::NAME_POLICY:
+// - NamePolicy::NotFound: they can't exist at the service, lookups don't even ask for them
+// - NamePolicy::PercentEncode: every byte which is not part of a valid UTF-8 sequence, and every
+//   '%', is sent as %XX. The service sends names back in the same form, and they are decoded
+//   before they are written into the caller's buffer.
+
+use std::borrow::Cow;
+use std::str;
+use config::NAME_POLICY;
+use types::NamePolicy;
+use types::Text;
+
+const HEX: &'static [u8] = b"0123456789ABCDEF";
+
+// encode returns name in the form it is sent to the Alexandria service under policy, which is
+// NAME_POLICY outside of the tests, None if it can't exist
+pub fn encode(policy: NamePolicy, name: &[u8]) -> Option<Cow<str>> {
+    match policy {
+        NamePolicy::NotFound => str::from_utf8(name).ok().map(Cow::Borrowed),
+        NamePolicy::PercentEncode => {
+            if let Ok(s) = str::from_utf8(name) {
+                if !s.contains('%') {
+                    return Some(Cow::Borrowed(s));
+                }
+            }
+            let mut out = String::with_capacity(name.len() * 3);
+            let mut rest = name;
+            while !rest.is_empty() {
+                let (valid, invalid) = match str::from_utf8(rest) {
+                    Ok(s) => (s, 0),
+                    Err(e) => {
+                        // the valid prefix can't fail to convert
+                        let valid = str::from_utf8(&rest[..e.valid_up_to()]).unwrap_or("");
+                        (valid, e.error_len().unwrap_or(rest.len() - e.valid_up_to()))
+                    },
+                };
+                for c in valid.chars() {
+                    if c == '%' {
+                        out.push_str("%25");
+                    } else {
+                        out.push(c);
+                    }
+                }
+                for &b in &rest[valid.len()..valid.len() + invalid] {
+                    push_escaped(&mut out, b);
+                }
+                rest = &rest[valid.len() + invalid..];
+            }
+            Some(Cow::Owned(out))
+        },
+    }
+}
+
+// push_escaped appends b as %XX
+pub fn push_escaped(out: &mut String, b: u8) {
+    out.push('%');
+    out.push(HEX[(b >> 4) as usize] as char);
+    out.push(HEX[(b & 0xf) as usize] as char);
+}
+
+// decode turns a name sent by the Alexandria service back into its bytes. It fails if the name
+// is not properly encoded, or if it would contain a NUL.
+pub fn decode<'a>(name: Text<'a>) -> Result<Text<'a>, String> {
+    if NAME_POLICY != NamePolicy::PercentEncode {
+        return Ok(name);
+    }
+    let bytes = {
+        let s = name.as_str();
+        if !s.contains('%') {
+            None
+        } else {
+            let src = s.as_bytes();
+            let mut out = Vec::with_capacity(src.len());
+            let mut i = 0;
+            while i < src.len() {
+                if src[i] != b'%' {
+                    out.push(src[i]);
+                    i += 1;
+                    continue;
+                }
+                let b = match (src.get(i + 1).and_then(|&h| hex(h)), src.get(i + 2).and_then(|&l| hex(l))) {
+                    (Some(h), Some(l)) => h << 4 | l,
+                    _ => return Err(format!("invalid percent-encoding in name \"{}\"", s.escape_default())),
+                };
+                if b == 0 {
+                    return Err(format!("name \"{}\" contains an encoded NUL", s.escape_default()));
+                }
+                out.push(b);
+                i += 3;
+            }
+            Some(out)
+        }
+    };
+    match bytes {
+        None => Ok(name),
+        Some(bytes) => Ok(Text::Bytes(bytes)),
+    }
+}
+
+fn hex(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
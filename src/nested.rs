@@ -0,0 +1,227 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// Nested groups, see config::NESTED_GROUPS.
+//
+// A group lookup expands its gr_groups by looking them up too, which goes through the same idmap,
+// filters, canonical names and overlay as any other group. initgroups works the other way round: it
+// asks for the groups which list the user, then for the groups which list those, one request per
+// group and level, and puts the memberships together from these.
+
+use std::collections::HashSet;
+use libc::gid_t;
+use config::{NESTED_GROUPS, NESTED_GROUPS_DEPTH};
+use canonical;
+use filter;
+use idmap;
+use overlay::{Section, Served};
+use routes;
+use types::AlexandriaGroup;
+use types::AlexandriaSvcError;
+use types::Text;
+use util::log;
+
+fn members(entry: &AlexandriaGroup) -> Vec<Vec<u8>> {
+    entry.gr_mem.iter().map(|m| m.as_bytes().into_owned()).collect()
+}
+
+fn groups(entry: &AlexandriaGroup) -> Vec<Vec<u8>> {
+    entry.gr_groups.iter().map(|g| g.as_bytes().into_owned()).collect()
+}
+
+// Nested are the members of one group which group() found
+struct Nested {
+    members: Vec<Vec<u8>>,
+    groups: Vec<Vec<u8>>,
+}
+
+// lookup returns the members and groups of the group the directory knows as name, as it is
+// served on this host. Groups of the overlay are served as they are, so they have no groups.
+fn lookup(name: &[u8], overlay: &Section<AlexandriaGroup<'static>>) -> Result<Option<Nested>, AlexandriaSvcError> {
+    if let Some(entry) = overlay.added(&canonical::host(name)) {
+        return Ok(Some(Nested { members: members(entry), groups: vec![] }));
+    }
+    let mut body = String::new();
+    let entry = match routes::group_name(name, &mut body) {
+        Ok(entry) => entry,
+        Err(AlexandriaSvcError::InvalidName(_)) => None,
+        Err(e) => return Err(e),
+    };
+    let nested = match entry.and_then(idmap::group).filter(|entry| filter::group(entry)).map(canonical::group).map(|entry| overlay.serve(entry)) {
+        None | Some(Served::Hidden) => None,
+        Some(Served::Directory(entry)) => Some(Nested {
+            members: members(&entry),
+            groups: groups(&entry),
+        }),
+        Some(Served::Overlay(entry)) => Some(Nested { members: members(entry), groups: vec![] }),
+    };
+    Ok(nested)
+}
+
+// group adds the members of the groups in gr_groups to the members of entry, and the members of
+// their groups, down to NESTED_GROUPS_DEPTH levels. Every group is looked up once, so a cycle ends
+// where it started.
+pub fn group<'a>(mut entry: AlexandriaGroup<'a>, overlay: &Section<AlexandriaGroup<'static>>) -> Result<AlexandriaGroup<'a>, AlexandriaSvcError> {
+    if !NESTED_GROUPS || entry.gr_groups.is_empty() {
+        return Ok(entry);
+    }
+
+    let mut seen: HashSet<Vec<u8>> = HashSet::new();
+    seen.insert(entry.gr_name.as_bytes().into_owned());
+    let mut members: HashSet<Vec<u8>> = entry.gr_mem.iter().map(|m| m.as_bytes().into_owned()).collect();
+    let mut level = groups(&entry);
+
+    for depth in 1.. {
+        if level.is_empty() {
+            break;
+        }
+        if depth > NESTED_GROUPS_DEPTH {
+            log(format!("group {} is nested deeper than {} levels, leaving out the members of the deeper groups", entry.gr_name, NESTED_GROUPS_DEPTH).as_str());
+            break;
+        }
+        let mut next = vec![];
+        for name in level {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            match try!(lookup(&name, overlay)) {
+                None => log(format!("group {}: nested group {} not found", entry.gr_name, String::from_utf8_lossy(&name)).as_str()),
+                Some(nested) => {
+                    for member in nested.members {
+                        if members.insert(member.clone()) {
+                            entry.gr_mem.push(Text::Bytes(member));
+                        }
+                    }
+                    next.extend(nested.groups);
+                },
+            }
+        }
+        level = next;
+    }
+    Ok(entry)
+}
+
+// Node is a group as initgroups sees it: named as the directory knows it, with its members as they
+// are served on this host
+pub struct Node {
+    pub name: Vec<u8>,
+    pub gid: gid_t,
+    pub members: Vec<Vec<u8>>,
+    pub groups: Vec<Vec<u8>>,
+}
+
+impl Node {
+    // directory is a group as the directory sent it, before the canonical names
+    pub fn directory(entry: &AlexandriaGroup) -> Node {
+        Node {
+            name: entry.gr_name.as_bytes().into_owned(),
+            gid: entry.gr_gid,
+            members: vec![],
+            groups: groups(entry),
+        }
+    }
+
+    // served completes a node with the group which is served for it, which has no groups of its
+    // own if it comes from the overlay
+    pub fn served(self, entry: &AlexandriaGroup, overlay: bool) -> Node {
+        Node {
+            gid: entry.gr_gid,
+            members: members(entry),
+            groups: if overlay { vec![] } else { self.groups },
+            ..self
+        }
+    }
+}
+
+// containing returns the gids of the groups user is a member of, directly or, with NESTED_GROUPS,
+// through the groups of at most NESTED_GROUPS_DEPTH levels, like group() would expand them
+pub fn containing(user: &[u8], nodes: &[Node]) -> Vec<gid_t> {
+    containing_within(user, nodes, if NESTED_GROUPS { NESTED_GROUPS_DEPTH } else { 0 })
+}
+
+// containing_within is containing with at most max_depth levels of nested groups
+fn containing_within(user: &[u8], nodes: &[Node], max_depth: usize) -> Vec<gid_t> {
+    let mut found: Vec<bool> = nodes.iter().map(|n| n.members.iter().any(|m| m.as_slice() == user)).collect();
+    let mut level: Vec<&[u8]> = nodes.iter().zip(found.iter()).filter(|&(_, &f)| f).map(|(n, _)| n.name.as_slice()).collect();
+    let mut depth = 0;
+    while !level.is_empty() && depth < max_depth {
+        depth += 1;
+        let mut next = vec![];
+        for (i, n) in nodes.iter().enumerate() {
+            // a group which was found already is not found again, so a cycle ends
+            if !found[i] && n.groups.iter().any(|g| level.contains(&g.as_slice())) {
+                found[i] = true;
+                next.push(n.name.as_slice());
+            }
+        }
+        level = next;
+    }
+    nodes.iter().zip(found).filter(|&(_, f)| f).map(|(n, _)| n.gid).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{containing_within, Node};
+
+    // node is a group named name whose gr_groups are groups
+    fn node(name: &str, gid: u32, members: &[&str], groups: &[&str]) -> Node {
+        Node {
+            name: name.as_bytes().to_vec(),
+            gid: gid,
+            members: members.iter().map(|m| m.as_bytes().to_vec()).collect(),
+            groups: groups.iter().map(|g| g.as_bytes().to_vec()).collect(),
+        }
+    }
+
+    #[test]
+    fn cycles_end() {
+        // a lists b in gr_groups and b lists a, alice is a member of a
+        let nodes = vec![node("a", 1, &["alice"], &["b"]), node("b", 2, &[], &["a"])];
+        let mut gids = containing_within(b"alice", &nodes, 8);
+        gids.sort();
+        assert_eq!(gids, vec![1, 2]);
+        assert_eq!(containing_within(b"bob", &nodes, 8), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn chains_end_at_the_depth() {
+        // g0 has alice, and every g<i> lists g<i-1> in gr_groups
+        let names: Vec<String> = (0..6).map(|i| format!("g{}", i)).collect();
+        let nodes: Vec<Node> = (0..6).map(|i| {
+            let members: &[&str] = if i == 0 { &["alice"] } else { &[] };
+            let groups: Vec<&str> = if i == 0 { vec![] } else { vec![names[i - 1].as_str()] };
+            node(&names[i], i as u32, members, &groups)
+        }).collect();
+        assert_eq!(containing_within(b"alice", &nodes, 0), vec![0]);
+        assert_eq!(containing_within(b"alice", &nodes, 3), vec![0, 1, 2, 3]);
+        assert_eq!(containing_within(b"alice", &nodes, 8), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn two_paths_count_once() {
+        // alice reaches top through a and through b, and a directly as well as through b
+        let nodes = vec![
+            node("a", 1, &["alice"], &["b"]),
+            node("b", 2, &["alice"], &[]),
+            node("top", 3, &[], &["a", "b"]),
+        ];
+        assert_eq!(containing_within(b"alice", &nodes, 8), vec![1, 2, 3]);
+        // top is one level away on either path
+        assert_eq!(containing_within(b"alice", &nodes, 1), vec![1, 2, 3]);
+        assert_eq!(containing_within(b"alice", &nodes, 0), vec![1, 2]);
+    }
+}
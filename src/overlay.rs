@@ -0,0 +1,442 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// Local exceptions from the directory.
+//
+// The files OVERLAY_DIR/*.json make host-specific exceptions without changing the directory, e.g.
+// {
+//   "passwd": {
+//     "add": [{"pw_name": "backup", "pw_uid": 990, "pw_gid": 990, "pw_dir": "/var/backups"}],
+//     "hide": ["mallory"]
+//   },
+//   "group": {
+//     "replace": [{"gr_name": "developers", "gr_gid": 5000, "gr_mem": ["alice", "backup"]}]
+//   }
+// }
+// Each of passwd, group and shadow can have:
+// - add: entries which exist on this host in addition to the directory. They win over a directory
+//   entry of the same name, and come first in an enumeration.
+// - replace: complete entries which replace the directory entry of the same name, as long as the
+//   directory has one.
+// - hide: names which don't exist on this host, whatever the directory says.
+// The files are read in the order of their names, and a later file wins over an earlier one for
+// the same name. They are reloaded as soon as one of them changes, which is checked at most every
+// OVERLAY_CHECK_INTERVAL_S.
+//
+// Shadow entries belong in a file of their own which only root can read. Files the caller can't
+// read are skipped, and the shadow section is only served to callers of the access policy.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use config::{OVERLAY_DIR, OVERLAY_CHECK_INTERVAL_S};
+use access;
+use decode;
+use decode::FromJson;
+use decode::Parser;
+use decode::PayloadError;
+use types::AlexandriaPassword;
+use types::AlexandriaGroup;
+use types::AlexandriaShadow;
+use types::AlexandriaSvcError;
+use util;
+use util::{log, debug};
+
+// Entry is implemented by the entries an overlay can hold
+pub trait Entry {
+    type Owned: Entry;
+    fn name(&self) -> Cow<[u8]>;
+    // id returns the uid or gid, None for databases which are only looked up by name
+    fn id(&self) -> Option<u32>;
+    fn into_owned(self) -> Self::Owned;
+}
+
+impl<'a> Entry for AlexandriaPassword<'a> {
+    type Owned = AlexandriaPassword<'static>;
+
+    fn name(&self) -> Cow<[u8]> {
+        self.pw_name.as_bytes()
+    }
+
+    fn id(&self) -> Option<u32> {
+        Some(self.pw_uid)
+    }
+
+    fn into_owned(self) -> AlexandriaPassword<'static> {
+        AlexandriaPassword::into_owned(self)
+    }
+}
+
+impl<'a> Entry for AlexandriaGroup<'a> {
+    type Owned = AlexandriaGroup<'static>;
+
+    fn name(&self) -> Cow<[u8]> {
+        self.gr_name.as_bytes()
+    }
+
+    fn id(&self) -> Option<u32> {
+        Some(self.gr_gid)
+    }
+
+    fn into_owned(self) -> AlexandriaGroup<'static> {
+        AlexandriaGroup::into_owned(self)
+    }
+}
+
+impl<'a> Entry for AlexandriaShadow<'a> {
+    type Owned = AlexandriaShadow<'static>;
+
+    fn name(&self) -> Cow<[u8]> {
+        self.sp_namp.as_bytes()
+    }
+
+    fn id(&self) -> Option<u32> {
+        None
+    }
+
+    fn into_owned(self) -> AlexandriaShadow<'static> {
+        AlexandriaShadow::into_owned(self)
+    }
+}
+
+// Served is what becomes of an entry sent by the directory
+pub enum Served<'o, T: 'o, E> {
+    // the entry doesn't exist on this host, or was served already
+    Hidden,
+    // the entry is served as the directory sent it
+    Directory(E),
+    // the overlay entry is served instead
+    Overlay(&'o T),
+}
+
+// Section is the overlay of one database
+pub struct Section<T> {
+    add: Vec<T>,
+    replace: Vec<T>,
+    hide: Vec<Vec<u8>>,
+}
+
+impl<T> Default for Section<T> {
+    fn default() -> Section<T> {
+        Section {
+            add: vec![],
+            replace: vec![],
+            hide: vec![],
+        }
+    }
+}
+
+impl<T: Entry> Section<T> {
+    pub fn hides(&self, name: &[u8]) -> bool {
+        self.hide.iter().any(|h| h.as_slice() == name)
+    }
+
+    // added returns the added entry named name
+    pub fn added(&self, name: &[u8]) -> Option<&T> {
+        self.add.iter().find(|e| &*e.name() == name)
+    }
+
+    // added_id returns the added entry with the uid or gid id
+    pub fn added_id(&self, id: u32) -> Option<&T> {
+        self.add.iter().find(|e| e.id() == Some(id))
+    }
+
+    // adds returns all added entries, which an enumeration serves before the directory's
+    pub fn adds(&self) -> &[T] {
+        self.add.as_slice()
+    }
+
+    // replaces returns all replacements, whether the directory has the entries they replace or not
+    pub fn replaces(&self) -> &[T] {
+        self.replace.as_slice()
+    }
+
+    fn replacement(&self, name: &[u8]) -> Option<&T> {
+        self.replace.iter().find(|e| &*e.name() == name)
+    }
+
+    // serve returns what becomes of an entry the directory sent for a lookup
+    pub fn serve<E: Entry>(&self, entry: E) -> Served<T, E> {
+        let local = {
+            let name = entry.name();
+            if self.hides(&name) {
+                return Served::Hidden;
+            }
+            self.added(&name).or_else(|| self.replacement(&name))
+        };
+        match local {
+            Some(local) => Served::Overlay(local),
+            None => Served::Directory(entry),
+        }
+    }
+
+    // serve_next is serve() for an enumeration, which served the added entries already
+    pub fn serve_next<E: Entry>(&self, entry: E) -> Served<T, E> {
+        if self.added(&entry.name()).is_some() {
+            return Served::Hidden;
+        }
+        self.serve(entry)
+    }
+
+    // replaced_id returns the replacement with the uid or gid id, which the directory doesn't know
+    // by that id. exists reports if the directory still has the entry it replaces.
+    pub fn replaced_id<F>(&self, id: u32, exists: F) -> Result<Option<&T>, AlexandriaSvcError> where F: Fn(&[u8]) -> Result<bool, AlexandriaSvcError> {
+        for e in self.replace.iter().filter(|e| e.id() == Some(id)) {
+            let name = e.name();
+            if !self.hides(&name) && self.added(&name).is_none() && try!(exists(&name)) {
+                return Ok(Some(e));
+            }
+        }
+        Ok(None)
+    }
+
+    fn into_owned(self) -> Section<T::Owned> {
+        Section {
+            add: self.add.into_iter().map(|e| e.into_owned()).collect(),
+            replace: self.replace.into_iter().map(|e| e.into_owned()).collect(),
+            hide: self.hide,
+        }
+    }
+
+    // merge takes over the section of a later file, whose entries win over the ones of the same name
+    fn merge(&mut self, later: Section<T>) {
+        merge_entries(&mut self.add, later.add);
+        merge_entries(&mut self.replace, later.replace);
+        for name in later.hide {
+            if !self.hides(&name) {
+                self.hide.push(name);
+            }
+        }
+    }
+}
+
+fn merge_entries<T: Entry>(entries: &mut Vec<T>, later: Vec<T>) {
+    for e in later {
+        let name = e.name().into_owned();
+        entries.retain(|other| *other.name() != *name);
+        entries.push(e);
+    }
+}
+
+impl<'a, T: FromJson<'a>> FromJson<'a> for Section<T> {
+    fn from_json(p: &mut Parser<'a>) -> Result<Section<T>, PayloadError> {
+        let mut section = Section::default();
+        try!(p.object("OverlaySection", |p, field| {
+            match field {
+                "add" => section.add = try!(p.entries()).unwrap_or_default(),
+                "replace" => section.replace = try!(p.entries()).unwrap_or_default(),
+                "hide" => section.hide = try!(p.names()).unwrap_or_default().iter().map(|name| name.as_bytes().into_owned()).collect(),
+                _ => try!(p.skip(0)),
+            }
+            Ok(())
+        }));
+        Ok(section)
+    }
+}
+
+// OverlayFile is one of the files in OVERLAY_DIR, borrowing from its content
+struct OverlayFile<'a> {
+    passwd: Section<AlexandriaPassword<'a>>,
+    group: Section<AlexandriaGroup<'a>>,
+    shadow: Section<AlexandriaShadow<'a>>,
+}
+
+impl<'a> FromJson<'a> for OverlayFile<'a> {
+    fn from_json(p: &mut Parser<'a>) -> Result<OverlayFile<'a>, PayloadError> {
+        let mut file = OverlayFile {
+            passwd: Section::default(),
+            group: Section::default(),
+            shadow: Section::default(),
+        };
+        try!(p.object("Overlay", |p, field| {
+            match field {
+                "passwd" => file.passwd = try!(Section::from_json(p).map_err(|e| e.to_string())),
+                "group" => file.group = try!(Section::from_json(p).map_err(|e| e.to_string())),
+                "shadow" => file.shadow = try!(Section::from_json(p).map_err(|e| e.to_string())),
+                _ => try!(p.skip(0)),
+            }
+            Ok(())
+        }));
+        Ok(file)
+    }
+}
+
+// Overlay is the merged content of all files in OVERLAY_DIR
+#[derive(Default)]
+pub struct Overlay {
+    pub passwd: Section<AlexandriaPassword<'static>>,
+    pub group: Section<AlexandriaGroup<'static>>,
+    pub shadow: Section<AlexandriaShadow<'static>>,
+}
+
+// path, modification time and size of a file, a change of any of them reloads the overlay
+type Stamp = (PathBuf, SystemTime, u64);
+
+struct Loaded {
+    overlay: Arc<Overlay>,
+    files: Vec<Stamp>,
+    checked: Instant,
+}
+
+static LOADED: Mutex<Option<Loaded>> = Mutex::new(None);
+
+fn lock() -> MutexGuard<'static, Option<Loaded>> {
+    util::atfork_once();
+    LOADED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// get returns the current overlay, which is reloaded if the files changed
+pub fn get() -> Arc<Overlay> {
+    let mut loaded = lock();
+    if let Some(ref mut l) = *loaded {
+        if l.checked.elapsed() < Duration::from_secs(OVERLAY_CHECK_INTERVAL_S) {
+            return l.overlay.clone();
+        }
+    }
+
+    let files = stamps();
+    if let Some(ref mut l) = *loaded {
+        if l.files == files {
+            l.checked = Instant::now();
+            return l.overlay.clone();
+        }
+    }
+
+    let overlay = Arc::new(load(&files));
+    *loaded = Some(Loaded {
+        overlay: overlay.clone(),
+        files: files,
+        checked: Instant::now(),
+    });
+    overlay
+}
+
+// for_shadow returns the overlay for the shadow database, which is empty for callers the access
+// policy doesn't allow
+pub fn for_shadow() -> Arc<Overlay> {
+    if access::shadow() {
+        get()
+    } else {
+        Arc::new(Overlay::default())
+    }
+}
+
+fn stamps() -> Vec<Stamp> {
+    let dir = match fs::read_dir(OVERLAY_DIR) {
+        Ok(dir) => dir,
+        Err(_) => return vec![],
+    };
+    let mut files = vec![];
+    for entry in dir {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(_) => continue,
+        };
+        if path.extension().map_or(true, |ext| ext != "json") {
+            continue;
+        }
+        if let Ok(meta) = fs::metadata(&path) {
+            if meta.is_file() {
+                files.push((path, meta.modified().unwrap_or(UNIX_EPOCH), meta.len()));
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+fn load(files: &[Stamp]) -> Overlay {
+    let mut overlay = Overlay::default();
+    for &(ref path, _, _) in files {
+        let mut src = String::new();
+        match fs::File::open(path).and_then(|mut f| f.read_to_string(&mut src)) {
+            Ok(_) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                // e.g. the shadow entries, which only root may read
+                debug(format!("skipping overlay {}: {}", path.display(), e).as_str());
+                continue;
+            },
+            Err(e) => {
+                log(format!("ignoring overlay {}: {}", path.display(), e).as_str());
+                continue;
+            },
+        }
+        match decode::from_str::<OverlayFile>(src.as_str()) {
+            Ok(file) => {
+                overlay.passwd.merge(file.passwd.into_owned());
+                overlay.group.merge(file.group.into_owned());
+                overlay.shadow.merge(file.shadow.into_owned());
+            },
+            Err(e) => log(format!("ignoring overlay {}: {}", path.display(), e).as_str()),
+        }
+    }
+    overlay
+}
+
+// The lock is taken before a fork like the ones in lib.rs, so that the child doesn't inherit it
+// locked. Both processes keep the overlay.
+thread_local!(static FORK_GUARD: RefCell<Option<MutexGuard<'static, Option<Loaded>>>> = RefCell::new(None));
+
+pub fn atfork_prepare() {
+    FORK_GUARD.with(|guard| *guard.borrow_mut() = Some(lock()));
+}
+
+pub fn atfork_release() {
+    FORK_GUARD.with(|guard| guard.borrow_mut().take());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use libc;
+    use super::{get, lock};
+
+    // a process which only looks up single entries forks while another thread loads the overlay
+    #[test]
+    fn fork_during_load() {
+        let (locked, wait) = mpsc::channel();
+        let holder = thread::spawn(move || {
+            let _loaded = lock();
+            locked.send(()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+        });
+        wait.recv().unwrap();
+
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            unsafe {
+                // a deadlock ends with SIGALRM instead of hanging the tests
+                libc::alarm(10);
+                get();
+                libc::_exit(0);
+            }
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        let exited = unsafe { libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 };
+        assert!(exited, "child failed with status {}", status);
+        holder.join().unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// Query builds the URLs of the routes. Every value is percent-encoded, so that a name like
+// "a&uid=0" is looked up as exactly that instead of changing the query. User and group names
+// additionally have to pass the NAME_CHARS check and are encoded according to NAME_POLICY
+// (see names.rs) before anything is sent to the Alexandria service.
+
+use config::NAME_CHARS;
+use config::NAME_ALLOW_NON_ASCII;
+use config::NAME_POLICY;
+use names;
+use types::AlexandriaSvcError;
+use types::NamePolicy;
+
+pub struct Query {
+    url: String,
+    params: usize,
+}
+
+impl Query {
+    pub fn new(path: &str) -> Query {
+        Query {
+            url: path.to_string(),
+            params: 0,
+        }
+    }
+
+    // value adds key=value to the query
+    pub fn value(mut self, key: &str, value: &str) -> Query {
+        self.url.push(if self.params == 0 { '?' } else { '&' });
+        self.params += 1;
+        push_encoded(&mut self.url, key.as_bytes());
+        self.url.push('=');
+        push_encoded(&mut self.url, value.as_bytes());
+        self
+    }
+
+    // id adds a numeric id to the query
+    pub fn id(self, key: &str, id: u64) -> Query {
+        self.value(key, id.to_string().as_str())
+    }
+
+    // limit adds the maximum number of entries of a list to the query, if there is one
+    pub fn limit(self, limit: Option<usize>) -> Query {
+        match limit {
+            Some(limit) => self.id("limit", limit as u64),
+            None => self,
+        }
+    }
+
+    // name adds a user or group name to the query. It fails with InvalidName if the name can't
+    // exist, so that the lookup can end with NSS_STATUS_NOTFOUND without asking the service.
+    pub fn name(self, key: &str, name: &[u8]) -> Result<Query, AlexandriaSvcError> {
+        self.name_as(NAME_POLICY, key, name)
+    }
+
+    // name_as is name under policy instead of NAME_POLICY
+    fn name_as(self, policy: NamePolicy, key: &str, name: &[u8]) -> Result<Query, AlexandriaSvcError> {
+        try!(check_name(name));
+        match names::encode(policy, name) {
+            Some(encoded) => Ok(self.value(key, &encoded)),
+            None => Err(AlexandriaSvcError::InvalidName(format!("\"{}\" is not valid UTF-8", String::from_utf8_lossy(name).escape_default()))),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.url.as_str()
+    }
+}
+
+// check_name makes sure name is not empty and consists of allowed characters only
+fn check_name(name: &[u8]) -> Result<(), AlexandriaSvcError> {
+    if name.is_empty() {
+        return Err(AlexandriaSvcError::InvalidName("the name is empty".to_string()));
+    }
+    for &b in name {
+        let allowed = if b < 0x80 {
+            NAME_CHARS.as_bytes().contains(&b)
+        } else {
+            NAME_ALLOW_NON_ASCII
+        };
+        if !allowed {
+            return Err(AlexandriaSvcError::InvalidName(format!("\"{}\" contains '{}'", String::from_utf8_lossy(name).escape_default(), (b as char).escape_default())));
+        }
+    }
+    Ok(())
+}
+
+// push_encoded percent-encodes everything but the unreserved characters of RFC 3986
+fn push_encoded(url: &mut String, value: &[u8]) {
+    for &b in value {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => url.push(b as char),
+            _ => names::push_escaped(url, b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::AlexandriaSvcError;
+    use types::NamePolicy;
+    use super::Query;
+
+    const POLICIES: [NamePolicy; 2] = [NamePolicy::NotFound, NamePolicy::PercentEncode];
+
+    fn name(policy: NamePolicy, name: &[u8]) -> Result<String, AlexandriaSvcError> {
+        Query::new("/passwd").name_as(policy, "name", name).map(|q| q.as_str().to_string())
+    }
+
+    fn invalid(ret: Result<String, AlexandriaSvcError>) -> bool {
+        match ret {
+            Err(AlexandriaSvcError::InvalidName(_)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn values_cant_change_the_query() {
+        let q = Query::new("/passwd").value("name", "a&uid=0%41#x?y z\0").id("uid", 7);
+        assert_eq!(q.as_str(), "/passwd?name=a%26uid%3D0%2541%23x%3Fy%20z%00&uid=7");
+    }
+
+    #[test]
+    fn hostile_names_are_invalid() {
+        for &policy in POLICIES.iter() {
+            for hostile in &[&b"a&uid=0"[..], b"a=b", b"a%41", b"a#b", b"a?b", b"a b", b"a\0b"] {
+                assert!(invalid(name(policy, hostile)), "{:?}: {:?} was sent", policy, String::from_utf8_lossy(hostile));
+            }
+        }
+    }
+
+    #[test]
+    fn names_outside_name_chars_are_invalid() {
+        for &policy in POLICIES.iter() {
+            for bad in &[&b""[..], b"a/b", b"a:b", b"a;b", b"a\nb", b"a\\b", b"a+b"] {
+                assert!(invalid(name(policy, bad)), "{:?}: {:?} was sent", policy, String::from_utf8_lossy(bad));
+            }
+        }
+    }
+
+    #[test]
+    fn non_utf8_names() {
+        let latin1 = b"j\xfcrgen";
+        assert!(invalid(name(NamePolicy::NotFound, latin1)));
+        // the byte is percent-encoded as the name, which is percent-encoded again in the query
+        assert_eq!(name(NamePolicy::PercentEncode, latin1).unwrap(), "/passwd?name=j%25FCrgen");
+        for &policy in POLICIES.iter() {
+            assert_eq!(name(policy, "jürgen".as_bytes()).unwrap(), "/passwd?name=j%C3%BCrgen");
+            assert_eq!(name(policy, b"alice@corp").unwrap(), "/passwd?name=alice%40corp");
+        }
+    }
+}
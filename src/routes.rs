@@ -16,187 +16,362 @@
 // along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::io::Read;
-use std::time::Duration;
+use std::mem;
+use std::cell::{Cell, RefCell};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 use libc::uid_t;
 use libc::gid_t;
-use libc::geteuid;
 use hyper::{Client};
+use hyper::client::pool::{Config, Pool};
+use hyper::header::Headers;
 use hyper::status::StatusCode;
 use hyperlocal::{DomainUrl, UnixSocketConnector};
-use rustc_serialize::json;
 use config::PASSWD_URL;
 use config::GROUP_URL;
 use config::SHADOW_URL;
+use config::AUTOMOUNT_URL;
+use config::SUBID_URL;
+use config::METRICS_URL;
+use config::METRICS_TIMEOUT_MS;
+use config::GENERATION_URL;
 use config::HTTP_READ_TIMEOUT_MS;
 use config::HTTP_WRITE_TIMEOUT_MS;
-use config::{SOCKET_PATH, SOCKET_PATH_PRIV};
+use config::STAYOPEN_TTL_S;
+use config::SOCKET_PATH;
+use access;
+use context;
+use decode;
+use decode::List;
+use domains;
+use domains::{Merged, Qualify};
+use mcache;
+use mcache::{Key, Table};
+use query::Query;
 use types::AlexandriaGroup;
 use types::AlexandriaPassword;
 use types::AlexandriaShadow;
+use types::AlexandriaAutomount;
+use types::AlexandriaSubid;
+use types::AlexandriaGeneration;
 use types::AlexandriaSvcError;
+use types::Domain;
+use util;
+use util::log;
 
 
-pub fn passwd() -> Result<Vec<AlexandriaPassword>, AlexandriaSvcError> {
-    let client = {
-        let mut c = Client::with_connector(UnixSocketConnector);
-        c.set_read_timeout(Some(Duration::from_millis(HTTP_READ_TIMEOUT_MS)));
-        c.set_write_timeout(Some(Duration::from_millis(HTTP_WRITE_TIMEOUT_MS)));
-        c
-    };
+// Connection is a client which keeps its connections to the Alexandria service open. It is only
+// used for a while after an enumeration was opened with stayopen, see stay_open(). All other
+// requests use a client of their own, which closes its connection right away.
+struct Connection {
+    client: Arc<Client>,
+    opened: Instant,
+}
 
-    let mut response = try!(client.get(DomainUrl::new(SOCKET_PATH, PASSWD_URL)).send());
-    if response.status == StatusCode::NotFound {
-        return Ok(vec![]);
+static CONNECTION: Mutex<Option<Connection>> = Mutex::new(None);
+
+fn lock() -> MutexGuard<'static, Option<Connection>> {
+    util::atfork_once();
+    CONNECTION.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// with_timeouts sets the timeouts of every client
+fn with_timeouts(mut client: Client) -> Client {
+    client.set_read_timeout(Some(Duration::from_millis(HTTP_READ_TIMEOUT_MS)));
+    client.set_write_timeout(Some(Duration::from_millis(HTTP_WRITE_TIMEOUT_MS)));
+    client
+}
+
+// stay_open keeps the connections to the Alexandria service open for the next STAYOPEN_TTL_S
+pub fn stay_open() {
+    let mut conn = lock();
+    if let Some(ref mut c) = *conn {
+        c.opened = Instant::now();
+        return;
+    }
+    *conn = Some(Connection {
+        client: Arc::new(with_timeouts(Client::with_connector(Pool::with_connector(Config::default(), UnixSocketConnector)))),
+        opened: Instant::now(),
+    });
+}
+
+// client returns the open connection if there is one, or a new client otherwise
+fn client() -> Arc<Client> {
+    let mut conn = lock();
+    if let Some(ref c) = *conn {
+        if c.opened.elapsed() < Duration::from_secs(STAYOPEN_TTL_S) {
+            return c.client.clone();
+        }
     }
-    let mut response_body = String::new();
-    let _num_bytes_read = try!(response.read_to_string(&mut response_body));
-    let entries: Vec<AlexandriaPassword> = try!(json::decode(&response_body));
+    // this closes a connection which has been open for too long
+    *conn = None;
+    Arc::new(with_timeouts(Client::with_connector(UnixSocketConnector)))
+}
 
-    Ok(entries)
+// A forked child must not talk to the service over the connection of its parent. The lock is
+// taken before a fork like the ones in lib.rs, and the child forgets the connection without
+// closing it, as another thread of the parent might have been in the middle of using it.
+thread_local!(static FORK_GUARD: RefCell<Option<MutexGuard<'static, Option<Connection>>>> = RefCell::new(None));
+
+pub fn atfork_prepare() {
+    FORK_GUARD.with(|guard| *guard.borrow_mut() = Some(lock()));
 }
 
-pub fn passwd_uid(uid: uid_t) -> Result<Option<AlexandriaPassword>, AlexandriaSvcError> {
-    let client = {
-        let mut c = Client::with_connector(UnixSocketConnector);
-        c.set_read_timeout(Some(Duration::from_millis(HTTP_READ_TIMEOUT_MS)));
-        c.set_write_timeout(Some(Duration::from_millis(HTTP_WRITE_TIMEOUT_MS)));
-        c
-    };
-    let url = format!("{}?uid={}", PASSWD_URL, uid);
-    let mut response = try!(client.get(DomainUrl::new(SOCKET_PATH, url.as_str())).send());
-    if response.status == StatusCode::NotFound {
-        return Ok(None)
+pub fn atfork_parent() {
+    FORK_GUARD.with(|guard| guard.borrow_mut().take());
+}
+
+pub fn atfork_child() {
+    FORK_GUARD.with(|guard| {
+        if let Some(mut conn) = guard.borrow_mut().take() {
+            if let Some(c) = conn.take() {
+                mem::forget(c);
+            }
+        }
+    });
+}
+
+// get sends a GET request for url to the service listening on socket and reads the response into
+// body. It returns false if the service doesn't know the requested entry.
+fn get(socket: &str, database: &str, url: &str, body: &mut String) -> Result<bool, AlexandriaSvcError> {
+    let id = context::request_id();
+    let ret = request(socket, url, context::headers(&id, database), body);
+    if let Err(ref e) = ret {
+        // the daemon logs the same request id
+        log(format!("request {} for {} failed: {}", id, url, e).as_str());
     }
-    let mut response_body = String::new();
-    let _num_bytes_read = try!(response.read_to_string(&mut response_body));
-    let entry: AlexandriaPassword = try!(json::decode(&response_body));
-    Ok(Some(entry))
+    ret
 }
 
-pub fn passwd_name(name: &str) -> Result<Option<AlexandriaPassword>, AlexandriaSvcError> {
-    let client = {
-        let mut c = Client::with_connector(UnixSocketConnector);
-        c.set_read_timeout(Some(Duration::from_millis(HTTP_READ_TIMEOUT_MS)));
-        c.set_write_timeout(Some(Duration::from_millis(HTTP_WRITE_TIMEOUT_MS)));
-        c
-    };
-    let url = format!("{}?name={}", PASSWD_URL, name);
-    let mut response = try!(client.get(DomainUrl::new(SOCKET_PATH, url.as_str())).send());
-    if response.status == StatusCode::NotFound {
-        return Ok(None)
+// generation returns the generation of the directory of domain, see generation.rs. A service which
+// doesn't count generations answers 404.
+pub fn generation(domain: &Domain) -> Result<Option<u64>, AlexandriaSvcError> {
+    let mut body = String::new();
+    if !try!(get(domain.socket, "generation", GENERATION_URL, &mut body)) {
+        return Ok(None);
     }
-    let mut response_body = String::new();
-    let _num_bytes_read = try!(response.read_to_string(&mut response_body));
-    let entry: AlexandriaPassword = try!(json::decode(&response_body));
-    Ok(Some(entry))
+    let g: AlexandriaGeneration = try!(decode::from_str(&body));
+    Ok(Some(g.generation))
 }
 
-pub fn group() -> Result<Vec<AlexandriaGroup>, AlexandriaSvcError> {
-    let client = {
-        let mut c = Client::with_connector(UnixSocketConnector);
-        c.set_read_timeout(Some(Duration::from_millis(HTTP_READ_TIMEOUT_MS)));
-        c.set_write_timeout(Some(Duration::from_millis(HTTP_WRITE_TIMEOUT_MS)));
-        c
-    };
+// metrics sends the metrics of this process, see metrics.rs. The caller of the lookup which is
+// due waits for it, so it has a client of its own with METRICS_TIMEOUT_MS.
+pub fn metrics(json: &str) -> Result<(), AlexandriaSvcError> {
+    let mut client = Client::with_connector(UnixSocketConnector);
+    client.set_read_timeout(Some(Duration::from_millis(METRICS_TIMEOUT_MS)));
+    client.set_write_timeout(Some(Duration::from_millis(METRICS_TIMEOUT_MS)));
+    let id = context::request_id();
+    let response = try!(client.post(DomainUrl::new(SOCKET_PATH, METRICS_URL)).headers(context::headers(&id, "metrics")).body(json).send());
+    if !response.status.is_success() {
+        log(format!("request {} for {} failed: {}", id, METRICS_URL, response.status).as_str());
+    }
+    Ok(())
+}
+
+// the HTTP status of the last response received by this thread, see last_status()
+thread_local!(static LAST_STATUS: Cell<Option<u16>> = Cell::new(None));
+
+// last_status returns the HTTP status of the last response received by this thread, which is only
+// interesting for diagnostics as all routes turn it into their result
+pub fn last_status() -> Option<u16> {
+    LAST_STATUS.with(|s| s.get())
+}
+
+// a test can make the requests of its thread panic, like a bug in the handling of a response would
+#[cfg(test)]
+thread_local!(pub static PANIC: Cell<bool> = Cell::new(false));
 
-    let mut response = try!(client.get(DomainUrl::new(SOCKET_PATH, GROUP_URL)).send());
+fn request(socket: &str, url: &str, headers: Headers, body: &mut String) -> Result<bool, AlexandriaSvcError> {
+    LAST_STATUS.with(|s| s.set(None));
+    #[cfg(test)]
+    {
+        if PANIC.with(|p| p.get()) {
+            panic!("request for {} panicked", url);
+        }
+    }
+    let client = client();
+    let mut response = try!(client.get(DomainUrl::new(socket, url)).headers(headers).send());
+    LAST_STATUS.with(|s| s.set(Some(response.status.to_u16())));
     if response.status == StatusCode::NotFound {
-        return Ok(vec![]);
+        return Ok(false);
     }
-    let mut response_body = String::new();
-    let _num_bytes_read = try!(response.read_to_string(&mut response_body));
-    let entries: Vec<AlexandriaGroup> = try!(json::decode(&response_body));
+    let _num_bytes_read = try!(response.read_to_string(body));
+    Ok(true)
+}
 
-    Ok(entries)
+// The routes returning a single entry read the response into body, which is provided by the
+// caller, and the entry borrows its strings from there. The passwd and group entries are copied
+// from the cache file instead if it has them, see mcache.rs. The routes returning all entries hand
+// over the response body in a List instead, which decodes its elements one at a time. The routes
+// of the databases which can be split across domains ask the directory of the right domain, see
+// domains.rs.
+
+// passwd returns all entries, or at most limit entries of every directory
+pub fn passwd(limit: Option<usize>) -> Result<Merged, AlexandriaSvcError> {
+    let query = Query::new(PASSWD_URL).limit(limit);
+    domains::merge("passwd", limit, |domain| {
+        let mut body = String::new();
+        if !try!(get(domain.socket, "passwd", query.as_str(), &mut body)) {
+            return Ok(List::empty());
+        }
+        let entries = try!(List::new(body, |b| decode::count::<AlexandriaPassword>(b)));
+        Ok(entries)
+    })
 }
 
-pub fn group_gid(gid: gid_t) -> Result<Option<AlexandriaGroup>, AlexandriaSvcError> {
-    let client = {
-        let mut c = Client::with_connector(UnixSocketConnector);
-        c.set_read_timeout(Some(Duration::from_millis(HTTP_READ_TIMEOUT_MS)));
-        c.set_write_timeout(Some(Duration::from_millis(HTTP_WRITE_TIMEOUT_MS)));
-        c
-    };
-    let url = format!("{}?gid={}", GROUP_URL, gid);
-    let mut response = try!(client.get(DomainUrl::new(SOCKET_PATH, url.as_str())).send());
-    if response.status == StatusCode::NotFound {
+pub fn passwd_uid<'b>(uid: uid_t, body: &'b mut String) -> Result<Option<AlexandriaPassword<'b>>, AlexandriaSvcError> {
+    let domain = domains::for_uid(uid);
+    let query = Query::new(PASSWD_URL).id("uid", uid as u64);
+    if !mcache::find(domain, Table::Passwd, Key::Id(uid), body) && !try!(get(domain.socket, "passwd", query.as_str(), body)) {
         return Ok(None)
     }
-    let mut response_body = String::new();
-    let _num_bytes_read = try!(response.read_to_string(&mut response_body));
-    let entry: AlexandriaGroup = try!(json::decode(&response_body));
-    Ok(Some(entry))
+    let entry: AlexandriaPassword = try!(decode::from_str(body));
+    Ok(Some(entry.qualify(domain)))
 }
 
-pub fn group_name(name: &str) -> Result<Option<AlexandriaGroup>, AlexandriaSvcError> {
-    let client = {
-        let mut c = Client::with_connector(UnixSocketConnector);
-        c.set_read_timeout(Some(Duration::from_millis(HTTP_READ_TIMEOUT_MS)));
-        c.set_write_timeout(Some(Duration::from_millis(HTTP_WRITE_TIMEOUT_MS)));
-        c
-    };
-    let url = format!("{}?name={}", GROUP_URL, name);
-    let mut response = try!(client.get(DomainUrl::new(SOCKET_PATH, url.as_str())).send());
-    if response.status == StatusCode::NotFound {
+pub fn passwd_name<'b>(name: &[u8], body: &'b mut String) -> Result<Option<AlexandriaPassword<'b>>, AlexandriaSvcError> {
+    let (domain, name) = domains::for_name(name);
+    let query = try!(Query::new(PASSWD_URL).name("name", name));
+    if !mcache::find(domain, Table::Passwd, Key::Name(name), body) && !try!(get(domain.socket, "passwd", query.as_str(), body)) {
         return Ok(None)
     }
-    let mut response_body = String::new();
-    let _num_bytes_read = try!(response.read_to_string(&mut response_body));
-    let entry: AlexandriaGroup = try!(json::decode(&response_body));
-    Ok(Some(entry))
+    let entry: AlexandriaPassword = try!(decode::from_str(body));
+    Ok(Some(entry.qualify(domain)))
 }
 
-pub fn shadow() -> Result<Vec<AlexandriaShadow>, AlexandriaSvcError> {
-    // shadow route is only allowed with an effective UID of 0 (root)
-    // return empty otherwise
-    // NOTE: the *real* security is implemented by using a different socket which must have the
-    //       permissions set to 700. This is just to short-circuit and not return with an error.
-    let euid = unsafe { geteuid() };
-    if euid != 0 {
-        return Ok(vec![]);
+// group returns all entries, or at most limit entries of every directory
+pub fn group(limit: Option<usize>) -> Result<Merged, AlexandriaSvcError> {
+    let query = Query::new(GROUP_URL).limit(limit);
+    domains::merge("group", limit, |domain| {
+        let mut body = String::new();
+        if !try!(get(domain.socket, "group", query.as_str(), &mut body)) {
+            return Ok(List::empty());
+        }
+        let entries = try!(List::new(body, |b| decode::count::<AlexandriaGroup>(b)));
+        Ok(entries)
+    })
+}
+
+// group_member returns the groups which list name in gr_mem, or with nested in gr_groups, for
+// initgroups. A service which doesn't know the parameter sends all groups, which only costs more.
+// Users and groups of a domain are listed by their bare name there, and as name@domain by the
+// default directory.
+pub fn group_member(name: &[u8], nested: bool) -> Result<Merged, AlexandriaSvcError> {
+    let (home, bare) = domains::for_name(name);
+    let key = if nested { "member_group" } else { "member" };
+    domains::merge("group", None, |domain| {
+        let member = if domain.name == home.name {
+            bare
+        } else if domain.name.is_empty() {
+            name
+        } else {
+            return Ok(List::empty());
+        };
+        let query = try!(Query::new(GROUP_URL).name(key, member));
+        let mut body = String::new();
+        if !try!(get(domain.socket, "group", query.as_str(), &mut body)) {
+            return Ok(List::empty());
+        }
+        let entries = try!(List::new(body, |b| decode::count::<AlexandriaGroup>(b)));
+        Ok(entries)
+    })
+}
+
+pub fn group_gid<'b>(gid: gid_t, body: &'b mut String) -> Result<Option<AlexandriaGroup<'b>>, AlexandriaSvcError> {
+    let domain = domains::for_gid(gid);
+    let query = Query::new(GROUP_URL).id("gid", gid as u64);
+    if !mcache::find(domain, Table::Group, Key::Id(gid), body) && !try!(get(domain.socket, "group", query.as_str(), body)) {
+        return Ok(None)
     }
+    let entry: AlexandriaGroup = try!(decode::from_str(body));
+    Ok(Some(entry.qualify(domain)))
+}
 
-    let client = {
-        let mut c = Client::with_connector(UnixSocketConnector);
-        c.set_read_timeout(Some(Duration::from_millis(HTTP_READ_TIMEOUT_MS)));
-        c.set_write_timeout(Some(Duration::from_millis(HTTP_WRITE_TIMEOUT_MS)));
-        c
-    };
+pub fn group_name<'b>(name: &[u8], body: &'b mut String) -> Result<Option<AlexandriaGroup<'b>>, AlexandriaSvcError> {
+    let (domain, name) = domains::for_name(name);
+    let query = try!(Query::new(GROUP_URL).name("name", name));
+    if !mcache::find(domain, Table::Group, Key::Name(name), body) && !try!(get(domain.socket, "group", query.as_str(), body)) {
+        return Ok(None)
+    }
+    let entry: AlexandriaGroup = try!(decode::from_str(body));
+    Ok(Some(entry.qualify(domain)))
+}
 
-    let mut response = try!(client.get(DomainUrl::new(SOCKET_PATH_PRIV, SHADOW_URL)).send());
-    if response.status == StatusCode::NotFound {
-        return Ok(vec![]);
+// shadow returns all entries, or at most limit entries of every directory
+pub fn shadow(limit: Option<usize>) -> Result<Merged, AlexandriaSvcError> {
+    // the shadow route is only allowed for the callers of the access policy, return empty otherwise
+    if !access::shadow() {
+        return Ok(Merged::empty());
     }
-    let mut response_body = String::new();
-    let _num_bytes_read = try!(response.read_to_string(&mut response_body));
-    let entries: Vec<AlexandriaShadow> = try!(json::decode(&response_body));
 
-    Ok(entries)
+    let query = Query::new(SHADOW_URL).limit(limit);
+    domains::merge("shadow", limit, |domain| {
+        let mut body = String::new();
+        if !try!(get(domain.socket_priv, "shadow", query.as_str(), &mut body)) {
+            return Ok(List::empty());
+        }
+        let entries = try!(List::new(body, |b| decode::count::<AlexandriaShadow>(b)));
+        Ok(entries)
+    })
 }
 
-pub fn shadow_name(name: &str) -> Result<Option<AlexandriaShadow>, AlexandriaSvcError> {
-    // shadow route is only allowed with an effective UID of 0 (root)
-    // return empty otherwise
-    // NOTE: the *real* security is implemented by using a different socket which must have the
-    //       permissions set to 700. This is just to short-circuit and not return with an error.
-    let euid = unsafe { geteuid() };
-    if  euid != 0 {
+pub fn shadow_name<'b>(name: &[u8], body: &'b mut String) -> Result<Option<AlexandriaShadow<'b>>, AlexandriaSvcError> {
+    // the shadow route is only allowed for the callers of the access policy, return empty otherwise
+    if !access::shadow() {
         return Ok(None);
     }
 
-    let client = {
-        let mut c = Client::with_connector(UnixSocketConnector);
-        c.set_read_timeout(Some(Duration::from_millis(HTTP_READ_TIMEOUT_MS)));
-        c.set_write_timeout(Some(Duration::from_millis(HTTP_WRITE_TIMEOUT_MS)));
-        c
-    };
-    let url = format!("{}?name={}", SHADOW_URL, name);
-    let mut response = try!(client.get(DomainUrl::new(SOCKET_PATH_PRIV, url.as_str())).send());
-    if response.status == StatusCode::NotFound {
+    let (domain, name) = domains::for_name(name);
+    let query = try!(Query::new(SHADOW_URL).name("name", name));
+    if !try!(get(domain.socket_priv, "shadow", query.as_str(), body)) {
+        return Ok(None)
+    }
+    let entry: AlexandriaShadow = try!(decode::from_str(body));
+    Ok(Some(entry.qualify(domain)))
+}
+
+pub fn automount(map: &str) -> Result<List, AlexandriaSvcError> {
+    let mut body = String::new();
+    let query = Query::new(AUTOMOUNT_URL).value("map", map);
+    if !try!(get(SOCKET_PATH, "automount", query.as_str(), &mut body)) {
+        return Ok(List::empty());
+    }
+    let entries = try!(List::new(body, |b| decode::count::<AlexandriaAutomount>(b)));
+    Ok(entries)
+}
+
+pub fn automount_key<'b>(map: &str, key: &str, body: &'b mut String) -> Result<Option<AlexandriaAutomount<'b>>, AlexandriaSvcError> {
+    let query = Query::new(AUTOMOUNT_URL).value("map", map).value("key", key);
+    if !try!(get(SOCKET_PATH, "automount", query.as_str(), body)) {
         return Ok(None)
     }
-    let mut response_body = String::new();
-    let _num_bytes_read = try!(response.read_to_string(&mut response_body));
-    let entry: AlexandriaShadow = try!(json::decode(&response_body));
+    let entry = try!(decode::from_str(body));
     Ok(Some(entry))
 }
+
+// subid_owner returns all subordinate id ranges of kind "uid" or "gid" delegated to owner
+pub fn subid_owner<'b>(kind: &str, owner: &[u8], body: &'b mut String) -> Result<Vec<AlexandriaSubid<'b>>, AlexandriaSvcError> {
+    let (domain, owner) = domains::for_name(owner);
+    let query = try!(Query::new(SUBID_URL).value("type", kind).name("owner", owner));
+    if !try!(get(domain.socket, "subid", query.as_str(), body)) {
+        return Ok(vec![]);
+    }
+    let entries: Vec<AlexandriaSubid> = try!(decode::list(body));
+    Ok(entries.into_iter().map(|entry| entry.qualify(domain)).collect())
+}
+
+// subid_id returns all subordinate id ranges of kind "uid" or "gid" which contain id
+pub fn subid_id<'b>(kind: &str, id: u64, body: &'b mut String) -> Result<Vec<AlexandriaSubid<'b>>, AlexandriaSvcError> {
+    // ids beyond the range of uid_t can't belong to a domain
+    let domain = match (kind, id > uid_t::max_value() as u64) {
+        (_, true) => &domains::DEFAULT,
+        ("uid", false) => domains::for_uid(id as uid_t),
+        (_, false) => domains::for_gid(id as gid_t),
+    };
+    let query = Query::new(SUBID_URL).value("type", kind).id("id", id);
+    if !try!(get(domain.socket, "subid", query.as_str(), body)) {
+        return Ok(vec![]);
+    }
+    let entries: Vec<AlexandriaSubid> = try!(decode::list(body));
+    Ok(entries.into_iter().map(|entry| entry.qualify(domain)).collect())
+}
@@ -0,0 +1,125 @@
+// Copyright (C) 2016 Marcus Heese
+//
+// This file is part of nss_alexandria.
+//
+// nss_alexandria is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// nss_alexandria is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
+
+// Per-host overrides of the home directory, shell and gecos of the users of the directory, see
+// config::HOME_TEMPLATE and friends. Fields without a template or rewrites keep borrowing from the
+// response body, all others are built as owned Text::Bytes.
+
+use std::sync::OnceLock;
+use regex::bytes::Regex;
+use config::{HOME_TEMPLATE, HOME_REWRITES};
+use config::{SHELL_TEMPLATE, SHELL_REWRITES};
+use config::{GECOS_TEMPLATE, GECOS_REWRITES};
+use config::FALLBACK_SHELL;
+use files;
+use types::AlexandriaPassword;
+use types::Text;
+use util::{log, debug};
+
+struct Field {
+    template: Option<&'static str>,
+    rewrites: &'static [(&'static str, &'static str)],
+    // the rewrites are compiled on first use, invalid ones are logged and left out
+    compiled: OnceLock<Vec<(Regex, &'static str)>>,
+}
+
+static HOME: Field = Field { template: HOME_TEMPLATE, rewrites: HOME_REWRITES, compiled: OnceLock::new() };
+static SHELL: Field = Field { template: SHELL_TEMPLATE, rewrites: SHELL_REWRITES, compiled: OnceLock::new() };
+static GECOS: Field = Field { template: GECOS_TEMPLATE, rewrites: GECOS_REWRITES, compiled: OnceLock::new() };
+
+impl Field {
+    // apply returns the new value of the field, None if it keeps the directory's
+    fn apply(&self, entry: &AlexandriaPassword, value: &Text) -> Option<Vec<u8>> {
+        if self.template.is_none() && self.rewrites.is_empty() {
+            return None;
+        }
+        let mut v = match self.template {
+            Some(template) => expand(template, entry, &value.as_bytes()),
+            None => value.as_bytes().into_owned(),
+        };
+        for &(ref re, replacement) in self.compiled.get_or_init(|| compile(self.rewrites)).iter() {
+            v = re.replace_all(&v, replacement.as_bytes()).into_owned();
+        }
+        Some(v)
+    }
+}
+
+fn compile(rewrites: &[(&'static str, &'static str)]) -> Vec<(Regex, &'static str)> {
+    let mut compiled = Vec::with_capacity(rewrites.len());
+    for &(pattern, replacement) in rewrites {
+        match Regex::new(pattern) {
+            Ok(re) => compiled.push((re, replacement)),
+            Err(e) => log(format!("ignoring rewrite {}: {}", pattern, e).as_str()),
+        }
+    }
+    compiled
+}
+
+// expand substitutes the placeholders of template, see config::HOME_TEMPLATE
+fn expand(template: &str, entry: &AlexandriaPassword, original: &[u8]) -> Vec<u8> {
+    let name = entry.pw_name.as_bytes();
+    let mut out = Vec::with_capacity(template.len() + name.len());
+    let mut bytes = template.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'u') => out.extend_from_slice(&name),
+            Some(b'U') => out.extend_from_slice(entry.pw_uid.to_string().as_bytes()),
+            Some(b'd') => {
+                if let Some(at) = name.iter().position(|&b| b == b'@') {
+                    out.extend_from_slice(&name[at + 1..]);
+                }
+            },
+            Some(b'l') => out.extend(name.iter().take(1)),
+            Some(b'o') => out.extend_from_slice(original),
+            Some(b'%') => out.push(b'%'),
+            // unknown placeholders are kept as they are
+            Some(other) => out.extend_from_slice(&[b'%', other]),
+            None => out.push(b'%'),
+        }
+    }
+    out
+}
+
+// passwd applies the templates and rewrites to an entry sent by the directory
+pub fn passwd(mut entry: AlexandriaPassword) -> AlexandriaPassword {
+    if let Some(dir) = HOME.apply(&entry, &entry.pw_dir) {
+        entry.pw_dir = Text::Bytes(dir);
+    }
+    if let Some(shell) = SHELL.apply(&entry, &entry.pw_shell) {
+        entry.pw_shell = Text::Bytes(shell);
+    }
+    if let Some(gecos) = GECOS.apply(&entry, &entry.pw_gecos) {
+        entry.pw_gecos = Text::Bytes(gecos);
+    }
+
+    if let Some(fallback) = FALLBACK_SHELL {
+        let valid = {
+            let shell = entry.pw_shell.as_bytes();
+            // an empty shell means /bin/sh, see passwd(5)
+            files::SHELLS.get().contains(if shell.is_empty() { b"/bin/sh" } else { &shell })
+        };
+        if !valid {
+            debug(format!("shell {} of {} is not in /etc/shells, using {}", entry.pw_shell, entry.pw_name, fallback).as_str());
+            entry.pw_shell = Text::Str(fallback);
+        }
+    }
+    entry
+}
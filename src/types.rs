@@ -18,12 +18,19 @@
 use std::io;
 use std::error;
 use std::fmt;
+use std::borrow::Cow;
 use libc::c_char;
+use libc::c_int;
+use libc::uid_t;
 use libc::gid_t;
 use libc::c_long;
 use libc::c_ulong;
 use hyper;
-use rustc_serialize;
+use decode;
+use decode::FromJson;
+use decode::Parser;
+use decode::PayloadError;
+use decode::required;
 
 /**
  * This is the enum from glibc with the return stati that all implemented NSS methods must use
@@ -38,6 +45,123 @@ pub enum nss_status
   NSS_STATUS_RETURN
 }
 
+/**
+ * This is the enum from shadow-utils' libsubid with the return stati that the subid plugin
+ * methods must use
+ */
+#[repr(C)]
+pub enum subid_status
+{
+  SUBID_STATUS_SUCCESS = 0,
+  SUBID_STATUS_UNKNOWN_USER = 1,
+  SUBID_STATUS_ERROR_CONN = 2,
+  SUBID_STATUS_ERROR = 3,
+}
+
+/**
+ * These are the values of enum subid_type from libsubid. They are passed in as plain ints,
+ * because an out-of-range value in a Rust enum would be undefined behaviour.
+ */
+pub const ID_TYPE_UID: c_int = 1;
+pub const ID_TYPE_GID: c_int = 2;
+
+/**
+ * NamePolicy decides what happens to user and group names which are not valid UTF-8, and which
+ * therefore can't be sent to the Alexandria service as they are. See config::NAME_POLICY.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NamePolicy {
+    // such names don't exist, lookups fail with NSS_STATUS_NOTFOUND
+    NotFound,
+    // such names are sent percent-encoded, and names sent by the service are percent-decoded
+    PercentEncode,
+}
+
+/**
+ * MetricsSink is where the metrics of a process are flushed to. See config::METRICS_SINK.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricsSink {
+    Off,
+    // POST them to the metrics route of the Alexandria service
+    Route,
+    // write them to METRICS_DIR/<pid>.json
+    File,
+}
+
+/**
+ * IdMap is a rule which translates uids or gids of the directory into the ids of this host, and
+ * back. See config::UID_MAP.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdMap {
+    // every id is shifted by the offset, as long as the result is a valid id
+    Offset(i64),
+    // the count ids starting at directory become the ones starting at host
+    Range { directory: u32, host: u32, count: u32 },
+}
+
+/**
+ * Enumerate decides how much of a database the enumeration of a process serves. See
+ * config::PASSWD_ENUMERATE.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Enumerate {
+    // all entries of the directory
+    Full,
+    // none, the enumeration doesn't even ask the directory
+    Disabled,
+    // at most this many entries of the directory
+    Capped(usize),
+}
+
+/**
+ * GenerationSource is where the generation of a directory is read from. See config::GENERATION.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GenerationSource {
+    // caches are trusted until they expire
+    Off,
+    // GET the generation route of the Alexandria service
+    Route,
+    // read the file at GENERATION_PATH, which the Alexandria service replaces
+    File,
+}
+
+/**
+ * Domain is a directory besides the default one, whose Alexandria service listens on sockets of
+ * its own. See config::DOMAINS.
+ */
+#[derive(Debug, PartialEq)]
+pub struct Domain {
+    // users and groups of the domain are named name@domain on this host
+    pub name: &'static str,
+    pub socket: &'static str,
+    pub socket_priv: &'static str,
+    // the uids and gids (inclusive) which are looked up in this domain
+    pub uids: &'static [(uid_t, uid_t)],
+    pub gids: &'static [(gid_t, gid_t)],
+}
+
+/**
+ * DomainDown decides what happens to an enumeration while the directory of a domain is down. See
+ * config::DOMAIN_DOWN.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DomainDown {
+    // the enumeration fails with NSS_STATUS_TRYAGAIN
+    Fail,
+    // the enumeration leaves out the entries of the domain, unless all directories are down
+    Skip,
+}
+
+#[repr(C)]
+pub struct subid_range
+{
+    pub start: c_ulong,
+    pub count: c_ulong,
+}
+
 #[repr(C)]
 pub struct group
 {
@@ -65,7 +189,21 @@ pub struct spwd
 pub enum AlexandriaSvcError  {
     Io(io::Error),
     Hyper(hyper::error::Error),
-    JsonDecode(rustc_serialize::json::DecoderError),
+    Payload(PayloadError),
+    // a name which can't exist and was therefore not sent to the service
+    InvalidName(String),
+}
+
+impl AlexandriaSvcError {
+    // permission_denied reports if the socket of the service could not be accessed
+    pub fn permission_denied(&self) -> bool {
+        let err = match *self {
+            AlexandriaSvcError::Io(ref err) => err,
+            AlexandriaSvcError::Hyper(hyper::error::Error::Io(ref err)) => err,
+            _ => return false,
+        };
+        err.kind() == io::ErrorKind::PermissionDenied
+    }
 }
 
 impl From<hyper::error::Error> for AlexandriaSvcError {
@@ -80,9 +218,9 @@ impl From<io::Error> for AlexandriaSvcError {
     }
 }
 
-impl From<rustc_serialize::json::DecoderError> for AlexandriaSvcError {
-    fn from(err: rustc_serialize::json::DecoderError) -> AlexandriaSvcError {
-        AlexandriaSvcError::JsonDecode(err)
+impl From<PayloadError> for AlexandriaSvcError {
+    fn from(err: PayloadError) -> AlexandriaSvcError {
+        AlexandriaSvcError::Payload(err)
     }
 }
 
@@ -93,7 +231,8 @@ impl fmt::Display for AlexandriaSvcError {
             // their implementations.
             AlexandriaSvcError::Io(ref err) => write!(f, "IO error: {}", err),
             AlexandriaSvcError::Hyper(ref err) => write!(f, "HTTP error: {}", err),
-            AlexandriaSvcError::JsonDecode(ref err) => write!(f, "JSON decode error: {}", err),
+            AlexandriaSvcError::Payload(ref err) => write!(f, "invalid payload: {}", err),
+            AlexandriaSvcError::InvalidName(ref reason) => write!(f, "invalid name: {}", reason),
         }
     }
 }
@@ -105,7 +244,8 @@ impl error::Error for AlexandriaSvcError {
         match *self {
             AlexandriaSvcError::Io(ref err) => err.description(),
             AlexandriaSvcError::Hyper(ref err) => err.description(),
-            AlexandriaSvcError::JsonDecode(ref err) => err.description(),
+            AlexandriaSvcError::Payload(ref err) => err.description(),
+            AlexandriaSvcError::InvalidName(_) => "invalid name",
         }
     }
 
@@ -117,11 +257,106 @@ impl error::Error for AlexandriaSvcError {
             // implement `Error`.
             AlexandriaSvcError::Io(ref err) => Some(err),
             AlexandriaSvcError::Hyper(ref err) => Some(err),
-            AlexandriaSvcError::JsonDecode(ref err) => Some(err),
+            AlexandriaSvcError::Payload(ref err) => Some(err),
+            AlexandriaSvcError::InvalidName(_) => None,
+        }
+    }
+}
+
+/**
+ * Text is a string field of a payload. Strings decoded from a response body borrow from it as
+ * they appear in the JSON source, and are only unescaped when they are written into the buffer
+ * of the caller.
+ */
+#[derive(Clone, Debug)]
+pub enum Text<'a> {
+    // a string without escapes
+    Str(&'a str),
+    // a string with escapes, still in its JSON representation
+    Json(&'a str),
+    // owned bytes, which need not be valid UTF-8: a name decoded by names::decode, or a string of
+    // an entry which outlives its body, see into_owned()
+    Bytes(Vec<u8>),
+}
+
+impl<'a> Text<'a> {
+    // len returns the length in bytes of the unescaped string, without a terminating NUL
+    pub fn len(&self) -> usize {
+        match *self {
+            Text::Str(s) => s.len(),
+            Text::Bytes(ref b) => b.len(),
+            Text::Json(raw) => {
+                let mut n = 0;
+                decode::unescape(raw, |piece| n += piece.len());
+                n
+            },
+        }
+    }
+
+    // copy_to unescapes the string into dst, which must have room for at least len() bytes, and
+    // returns the number of bytes written
+    pub fn copy_to(&self, dst: &mut [u8]) -> usize {
+        match *self {
+            Text::Str(s) => {
+                dst[..s.len()].copy_from_slice(s.as_bytes());
+                s.len()
+            },
+            Text::Bytes(ref b) => {
+                dst[..b.len()].copy_from_slice(b);
+                b.len()
+            },
+            Text::Json(raw) => {
+                let mut n = 0;
+                decode::unescape(raw, |piece| {
+                    dst[n..n + piece.len()].copy_from_slice(piece);
+                    n += piece.len();
+                });
+                n
+            },
+        }
+    }
+
+    // as_bytes returns the unescaped bytes, which only allocates if they contain escapes
+    pub fn as_bytes(&self) -> Cow<[u8]> {
+        match *self {
+            Text::Bytes(ref b) => Cow::Borrowed(b.as_slice()),
+            _ => match self.as_str() {
+                Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+                Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+            },
+        }
+    }
+
+    // into_owned copies the unescaped string, so that it no longer borrows from the body
+    pub fn into_owned(self) -> Text<'static> {
+        match self {
+            Text::Bytes(b) => Text::Bytes(b),
+            t => Text::Bytes(t.as_bytes().into_owned()),
+        }
+    }
+
+    // as_str returns the unescaped string, which only allocates if it contains escapes. Bytes
+    // which are not valid UTF-8 are replaced.
+    pub fn as_str(&self) -> Cow<str> {
+        match *self {
+            Text::Str(s) => Cow::Borrowed(s),
+            Text::Bytes(ref b) => String::from_utf8_lossy(b),
+            Text::Json(raw) => {
+                let mut v = Vec::with_capacity(self.len());
+                decode::unescape(raw, |piece| v.extend_from_slice(piece));
+                // unescaping valid JSON always results in valid UTF-8
+                Cow::Owned(String::from_utf8(v).unwrap_or_default())
+            },
         }
     }
 }
 
+impl<'a> fmt::Display for Text<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 /*
 {
   pw_name: "gary",
@@ -133,15 +368,59 @@ impl error::Error for AlexandriaSvcError {
   pw_shell: "/bin/bash"
 }
 */
-#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
-pub struct AlexandriaPassword {
-    pub pw_name: String,
-    pub pw_passwd: String,
+#[derive(Clone, Debug)]
+pub struct AlexandriaPassword<'a> {
+    pub pw_name: Text<'a>,
+    pub pw_passwd: Text<'a>,
     pub pw_uid: u32,
     pub pw_gid: u32,
-    pub pw_gecos: String,
-    pub pw_dir: String,
-    pub pw_shell: String,
+    pub pw_gecos: Text<'a>,
+    pub pw_dir: Text<'a>,
+    pub pw_shell: Text<'a>,
+}
+
+impl<'a> AlexandriaPassword<'a> {
+    // into_owned copies all strings, for entries which outlive the body they were decoded from
+    pub fn into_owned(self) -> AlexandriaPassword<'static> {
+        AlexandriaPassword {
+            pw_name: self.pw_name.into_owned(),
+            pw_passwd: self.pw_passwd.into_owned(),
+            pw_uid: self.pw_uid,
+            pw_gid: self.pw_gid,
+            pw_gecos: self.pw_gecos.into_owned(),
+            pw_dir: self.pw_dir.into_owned(),
+            pw_shell: self.pw_shell.into_owned(),
+        }
+    }
+}
+
+impl<'a> FromJson<'a> for AlexandriaPassword<'a> {
+    fn from_json(p: &mut Parser<'a>) -> Result<AlexandriaPassword<'a>, PayloadError> {
+        let (mut pw_name, mut pw_passwd, mut pw_uid, mut pw_gid, mut pw_gecos, mut pw_dir, mut pw_shell) = (None, None, None, None, None, None, None);
+        try!(p.object("AlexandriaPassword", |p, field| {
+            match field {
+                "pw_name" => pw_name = try!(p.name()),
+                "pw_passwd" => pw_passwd = try!(p.string()),
+                "pw_uid" => pw_uid = try!(p.unsigned(u32::MAX as u64)),
+                "pw_gid" => pw_gid = try!(p.unsigned(u32::MAX as u64)),
+                "pw_gecos" => pw_gecos = try!(p.string()),
+                "pw_dir" => pw_dir = try!(p.string()),
+                "pw_shell" => pw_shell = try!(p.string()),
+                _ => try!(p.skip(0)),
+            }
+            Ok(())
+        }));
+        Ok(AlexandriaPassword {
+            pw_name: try!(required("AlexandriaPassword", "pw_name", pw_name)),
+            pw_passwd: pw_passwd.unwrap_or(Text::Str("x")),
+            pw_uid: try!(required("AlexandriaPassword", "pw_uid", pw_uid)) as u32,
+            pw_gid: try!(required("AlexandriaPassword", "pw_gid", pw_gid)) as u32,
+            pw_gecos: pw_gecos.unwrap_or(Text::Str("")),
+            pw_dir: try!(required("AlexandriaPassword", "pw_dir", pw_dir)),
+            // an empty shell means /bin/sh, see passwd(5)
+            pw_shell: pw_shell.unwrap_or(Text::Str("")),
+        })
+    }
 }
 
 /*
@@ -152,15 +431,57 @@ pub struct AlexandriaPassword {
   "gr_mem": [
     "testuser1",
     "testuser2"
+  ],
+  "gr_groups": [
+    "testgroup2"
   ]
 }
 */
-#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
-pub struct AlexandriaGroup {
-    pub gr_name: String,
-    pub gr_passwd: String,
+#[derive(Clone, Debug)]
+pub struct AlexandriaGroup<'a> {
+    pub gr_name: Text<'a>,
+    pub gr_passwd: Text<'a>,
     pub gr_gid: u32,
-    pub gr_mem: Vec<String>,
+    pub gr_mem: Vec<Text<'a>>,
+    // the groups whose members are members too, see config::NESTED_GROUPS
+    pub gr_groups: Vec<Text<'a>>,
+}
+
+impl<'a> AlexandriaGroup<'a> {
+    // into_owned copies all strings, for entries which outlive the body they were decoded from
+    pub fn into_owned(self) -> AlexandriaGroup<'static> {
+        AlexandriaGroup {
+            gr_name: self.gr_name.into_owned(),
+            gr_passwd: self.gr_passwd.into_owned(),
+            gr_gid: self.gr_gid,
+            gr_mem: self.gr_mem.into_iter().map(Text::into_owned).collect(),
+            gr_groups: self.gr_groups.into_iter().map(Text::into_owned).collect(),
+        }
+    }
+}
+
+impl<'a> FromJson<'a> for AlexandriaGroup<'a> {
+    fn from_json(p: &mut Parser<'a>) -> Result<AlexandriaGroup<'a>, PayloadError> {
+        let (mut gr_name, mut gr_passwd, mut gr_gid, mut gr_mem, mut gr_groups) = (None, None, None, None, None);
+        try!(p.object("AlexandriaGroup", |p, field| {
+            match field {
+                "gr_name" => gr_name = try!(p.name()),
+                "gr_passwd" => gr_passwd = try!(p.string()),
+                "gr_gid" => gr_gid = try!(p.unsigned(u32::MAX as u64)),
+                "gr_mem" => gr_mem = try!(p.names()),
+                "gr_groups" => gr_groups = try!(p.names()),
+                _ => try!(p.skip(0)),
+            }
+            Ok(())
+        }));
+        Ok(AlexandriaGroup {
+            gr_name: try!(required("AlexandriaGroup", "gr_name", gr_name)),
+            gr_passwd: gr_passwd.unwrap_or(Text::Str("x")),
+            gr_gid: try!(required("AlexandriaGroup", "gr_gid", gr_gid)) as u32,
+            gr_mem: gr_mem.unwrap_or_default(),
+            gr_groups: gr_groups.unwrap_or_default(),
+        })
+    }
 }
 
 /*
@@ -176,10 +497,10 @@ pub struct AlexandriaGroup {
   "sp_namp": "testuser1"
 }
 */
-#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
-pub struct AlexandriaShadow {
-    pub sp_namp: String,
-    pub sp_pwdp: String,
+#[derive(Clone, Debug)]
+pub struct AlexandriaShadow<'a> {
+    pub sp_namp: Text<'a>,
+    pub sp_pwdp: Text<'a>,
     pub sp_lstchg: i64,
     pub sp_min: i64,
     pub sp_max: i64,
@@ -188,3 +509,144 @@ pub struct AlexandriaShadow {
     pub sp_expire: i64,
     pub sp_flag: u64,
 }
+
+impl<'a> AlexandriaShadow<'a> {
+    // into_owned copies all strings, for entries which outlive the body they were decoded from
+    pub fn into_owned(self) -> AlexandriaShadow<'static> {
+        AlexandriaShadow {
+            sp_namp: self.sp_namp.into_owned(),
+            sp_pwdp: self.sp_pwdp.into_owned(),
+            sp_lstchg: self.sp_lstchg,
+            sp_min: self.sp_min,
+            sp_max: self.sp_max,
+            sp_warn: self.sp_warn,
+            sp_inact: self.sp_inact,
+            sp_expire: self.sp_expire,
+            sp_flag: self.sp_flag,
+        }
+    }
+}
+
+impl<'a> FromJson<'a> for AlexandriaShadow<'a> {
+    fn from_json(p: &mut Parser<'a>) -> Result<AlexandriaShadow<'a>, PayloadError> {
+        let (mut sp_namp, mut sp_pwdp, mut sp_flag) = (None, None, None);
+        let (mut sp_lstchg, mut sp_min, mut sp_max, mut sp_warn, mut sp_inact, mut sp_expire) = (None, None, None, None, None, None);
+        try!(p.object("AlexandriaShadow", |p, field| {
+            match field {
+                "sp_namp" => sp_namp = try!(p.name()),
+                "sp_pwdp" => sp_pwdp = try!(p.string()),
+                "sp_lstchg" => sp_lstchg = try!(p.signed()),
+                "sp_min" => sp_min = try!(p.signed()),
+                "sp_max" => sp_max = try!(p.signed()),
+                "sp_warn" => sp_warn = try!(p.signed()),
+                "sp_inact" => sp_inact = try!(p.signed()),
+                "sp_expire" => sp_expire = try!(p.signed()),
+                "sp_flag" => sp_flag = try!(p.unsigned(u64::MAX)),
+                _ => try!(p.skip(0)),
+            }
+            Ok(())
+        }));
+        // missing aging fields are -1, which is what glibc uses for empty fields in /etc/shadow
+        Ok(AlexandriaShadow {
+            sp_namp: try!(required("AlexandriaShadow", "sp_namp", sp_namp)),
+            sp_pwdp: sp_pwdp.unwrap_or(Text::Str("!")),
+            sp_lstchg: sp_lstchg.unwrap_or(-1),
+            sp_min: sp_min.unwrap_or(-1),
+            sp_max: sp_max.unwrap_or(-1),
+            sp_warn: sp_warn.unwrap_or(-1),
+            sp_inact: sp_inact.unwrap_or(-1),
+            sp_expire: sp_expire.unwrap_or(-1),
+            sp_flag: sp_flag.unwrap_or(0),
+        })
+    }
+}
+
+/*
+{
+  "am_key": "testuser1",
+  "am_value": "-fstype=nfs4,rw fileserver:/export/home/testuser1"
+}
+*/
+#[derive(Clone, Debug)]
+pub struct AlexandriaAutomount<'a> {
+    pub am_key: Text<'a>,
+    pub am_value: Text<'a>,
+}
+
+impl<'a> FromJson<'a> for AlexandriaAutomount<'a> {
+    fn from_json(p: &mut Parser<'a>) -> Result<AlexandriaAutomount<'a>, PayloadError> {
+        let (mut am_key, mut am_value) = (None, None);
+        try!(p.object("AlexandriaAutomount", |p, field| {
+            match field {
+                "am_key" => am_key = try!(p.string()),
+                "am_value" => am_value = try!(p.string()),
+                _ => try!(p.skip(0)),
+            }
+            Ok(())
+        }));
+        Ok(AlexandriaAutomount {
+            am_key: try!(required("AlexandriaAutomount", "am_key", am_key)),
+            am_value: try!(required("AlexandriaAutomount", "am_value", am_value)),
+        })
+    }
+}
+
+/*
+{
+  "owner": "testuser1",
+  "start": 100000,
+  "count": 65536
+}
+*/
+#[derive(Clone, Debug)]
+pub struct AlexandriaSubid<'a> {
+    pub owner: Text<'a>,
+    pub start: u64,
+    pub count: u64,
+}
+
+impl<'a> FromJson<'a> for AlexandriaSubid<'a> {
+    fn from_json(p: &mut Parser<'a>) -> Result<AlexandriaSubid<'a>, PayloadError> {
+        let (mut owner, mut start, mut count) = (None, None, None);
+        try!(p.object("AlexandriaSubid", |p, field| {
+            match field {
+                "owner" => owner = try!(p.name()),
+                "start" => start = try!(p.unsigned(u64::MAX)),
+                "count" => count = try!(p.unsigned(u64::MAX)),
+                _ => try!(p.skip(0)),
+            }
+            Ok(())
+        }));
+        Ok(AlexandriaSubid {
+            owner: try!(required("AlexandriaSubid", "owner", owner)),
+            start: try!(required("AlexandriaSubid", "start", start)),
+            count: try!(required("AlexandriaSubid", "count", count)),
+        })
+    }
+}
+
+/*
+{
+  "generation": 42
+}
+*/
+#[derive(Clone, Copy, Debug)]
+pub struct AlexandriaGeneration {
+    pub generation: u64,
+}
+
+impl<'a> FromJson<'a> for AlexandriaGeneration {
+    fn from_json(p: &mut Parser<'a>) -> Result<AlexandriaGeneration, PayloadError> {
+        let mut generation = None;
+        try!(p.object("AlexandriaGeneration", |p, field| {
+            match field {
+                "generation" => generation = try!(p.unsigned(u64::MAX)),
+                _ => try!(p.skip(0)),
+            }
+            Ok(())
+        }));
+        Ok(AlexandriaGeneration {
+            generation: try!(required("AlexandriaGeneration", "generation", generation)),
+        })
+    }
+}
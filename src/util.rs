@@ -15,341 +15,461 @@
 // You should have received a copy of the GNU General Public License
 // along with nss_alexandria.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::any::Any;
 use std::ffi::{CString};
-use std::ptr::write_bytes;
+use std::fs;
+use std::io::Read;
+use std::panic;
+use std::panic::AssertUnwindSafe;
 use std::ptr::copy;
+use std::ptr::null_mut;
+use std::mem::align_of;
+use std::mem::size_of;
+use std::slice;
+use std::sync::Once;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use libc::c_void;
 use libc::c_char;
 use libc::c_int;
 use libc::size_t;
-use libc::strncpy;
-use libc::ENOMEM;
+use libc::uid_t;
+use libc::gid_t;
+use libc::c_long;
+use libc::c_ulong;
+use libc::malloc;
+use libc::realloc;
+use libc::ENOENT;
 use libc::ERANGE;
+use libc::ENOMEM;
+use libc::LOG_INFO;
+use libc::LOG_DEBUG;
 use libc::passwd;
+use context;
+use metrics;
+use metrics::Status;
 use types::group;
 use types::spwd;
 use types::nss_status;
+use types::nss_status::NSS_STATUS_UNAVAIL;
 use types::nss_status::NSS_STATUS_TRYAGAIN;
 use types::nss_status::NSS_STATUS_SUCCESS;
+use types::subid_range;
+use types::subid_status;
+use types::subid_status::SUBID_STATUS_SUCCESS;
+use types::subid_status::SUBID_STATUS_ERROR;
+use types::Text;
 use types::AlexandriaPassword;
 use types::AlexandriaGroup;
 use types::AlexandriaShadow;
+use types::AlexandriaAutomount;
+use types::AlexandriaSubid;
 
 /* A reference to the syslog method in glibc */
 extern {
-    fn syslog(pri: c_int, fmt: *const c_char) -> c_void;
+    fn syslog(pri: c_int, fmt: *const c_char, ...) -> c_void;
 }
 
 /* log will log msg on syslog with INFO priority */
 pub fn log(msg: &str) {
+    log_at(LOG_INFO, msg);
+}
+
+/* debug will log msg on syslog with DEBUG priority */
+pub fn debug(msg: &str) {
+    log_at(LOG_DEBUG, msg);
+}
+
+fn log_at(pri: c_int, msg: &str) {
+    // a NUL would end the message early, and CString::new would refuse it
+    let cmsg = match CString::new(msg) {
+        Ok(cmsg) => cmsg,
+        Err(_) => CString::new(msg.replace('\0', "\\0")).unwrap_or_default(),
+    };
     unsafe {
-        syslog(6, CString::new(msg).unwrap().as_ptr() );
+        // msg must not be used as the format, it might contain a %
+        syslog(pri, b"%s\0".as_ptr() as *const c_char, cmsg.as_ptr());
     }
 }
 
-pub fn write_passwd(e: AlexandriaPassword, result: *mut passwd, mut buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
-    let next_buf = &mut buffer;
-    let mut bufleft = buflen;
-
-    // let's carefully craft C strings here:
-    // - NSS is expecting ERANGE in errnop only if the buffer is too small
-    // - however, we still want to try again, so we do the following for error handling:
-    // - NSS_STATUS_TRYAGAIN to retry
-    // - ENOMEM for errnop
-    // TODO: a macro could make that part shorter
-
-    // pw_name
-    let pw_name_len = e.pw_name.len();
-    let cstr_pw_name = match CString::new(e.pw_name) {
-        Ok(cstr) => cstr,
-        Err(_) => {
-            unsafe { *errnop = ENOMEM; }
-            return NSS_STATUS_TRYAGAIN;
+// temp_suffix returns a random suffix for a temporary file, which other users can't guess in advance
+pub fn temp_suffix() -> u64 {
+    let mut bytes = [0u8; 8];
+    let random = fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes));
+    match random {
+        Ok(_) => u64::from_ne_bytes(bytes),
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos() as u64).unwrap_or(0),
+    }
+}
+
+// atfork_once registers the fork handlers of lib.rs. Every lock of the module calls it before it is
+// taken, as a process which only ever looks up single entries holds them as well, e.g. the overlay
+// while its files are read, and may fork meanwhile.
+static ATFORK: Once = Once::new();
+
+pub fn atfork_once() {
+    ATFORK.call_once(|| unsafe {
+        libc::pthread_atfork(Some(::atfork_prepare), Some(::atfork_parent), Some(::atfork_child));
+    });
+}
+
+// catch runs the body of an exported function. A panic must never unwind into the C code which
+// called us, as that is undefined behaviour and usually aborts the whole process. Instead it is
+// logged and the caller gets the fallback.
+// func is also recorded as the entry point for the requests made on the way, see context.rs
+// Every call is counted in the metrics as well.
+pub fn catch<T, F>(func: &'static str, fallback: T, f: F) -> T where T: Status, F: FnOnce() -> T {
+    panic_hook_once();
+    let previous = context::enter(func);
+    let start = Instant::now();
+    let ret = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(ret) => ret,
+        Err(payload) => {
+            log(format!("{}(): caught panic: {}", func, panic_message(&*payload)).as_str());
+            fallback
         },
     };
+    metrics::record(func, ret.outcome(), start.elapsed());
+    context::leave(previous);
+    ret
+}
 
-    // pw_passwd
-    let pw_passwd_len = e.pw_passwd.len();
-    let cstr_pw_passwd = match CString::new(e.pw_passwd) {
-        Ok(cstr) => cstr,
-        Err(_) => {
-            unsafe { *errnop = ENOMEM; }
-            return NSS_STATUS_TRYAGAIN;
-        }
-    };
+// The default panic hook prints the message to stderr, which in the host process may be the
+// connection of sshd, the conversation of PAM or a pipe. Panics of a thread which is inside one of
+// our functions are logged instead, all others still go to the hook which was installed before.
+static PANIC_HOOK: Once = Once::new();
+
+fn panic_hook_once() {
+    PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let func = context::entry_point();
+            if func.is_empty() {
+                previous(info);
+            } else {
+                log(format!("{}(): {}", func, info).as_str());
+            }
+        }));
+    });
+}
 
-    // pw_gecos
-    let pw_gecos_len = e.pw_gecos.len();
-    let cstr_pw_gecos = match CString::new(e.pw_gecos) {
-        Ok(cstr) => cstr,
-        Err(_) => {
-            unsafe { *errnop = ENOMEM; }
-            return NSS_STATUS_TRYAGAIN;
+// guard is catch for the NSS functions, which report a panic as NSS_STATUS_UNAVAIL and ENOENT
+// (if they have an errnop), just like when the Alexandria service isn't there at all
+pub fn guard<F>(func: &'static str, errnop: *mut c_int, f: F) -> nss_status where F: FnOnce() -> nss_status {
+    let mut panicked = true;
+    let status = catch(func, NSS_STATUS_UNAVAIL, || {
+        let status = f();
+        panicked = false;
+        status
+    });
+    if panicked && !errnop.is_null() {
+        unsafe { *errnop = ENOENT; }
+    }
+    if let NSS_STATUS_TRYAGAIN = status {
+        if !errnop.is_null() && unsafe { *errnop } == ERANGE {
+            metrics::erange(func);
         }
-    };
+    }
+    status
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(msg) => msg,
+        None => match payload.downcast_ref::<String>() {
+            Some(msg) => msg.as_str(),
+            None => "unknown cause",
+        },
+    }
+}
 
-    // pw_dir
-    let pw_dir_len = e.pw_dir.len();
-    let cstr_pw_dir = match CString::new(e.pw_dir) {
-        Ok(cstr) => cstr,
-        Err(_) => {
-            unsafe { *errnop = ENOMEM; }
-            return NSS_STATUS_TRYAGAIN;
+// Buffer hands out the space of the buffer which glibc passes to the reentrant functions.
+// The write_* functions below compute the exact size they need before they write anything, so
+// that writing can't run out of space half way through.
+struct Buffer {
+    next: *mut c_char,
+    left: usize,
+}
+
+impl Buffer {
+    fn new(buffer: *mut c_char, buflen: size_t) -> Buffer {
+        Buffer {
+            next: buffer,
+            left: buflen as usize,
         }
-    };
+    }
 
-    // pw_shell
-    let pw_shell_len = e.pw_shell.len();
-    let cstr_pw_shell = match CString::new(e.pw_shell) {
-        Ok(cstr) => cstr,
-        Err(_) => {
-            unsafe { *errnop = ENOMEM; }
-            return NSS_STATUS_TRYAGAIN;
+    // check sets errnop and returns false if needed bytes don't fit into the buffer
+    fn check(&self, needed: usize, errnop: *mut c_int) -> bool {
+        if needed > self.left {
+            // the buffer is not big enough
+            // the glibc NSS documentation demands errnop to be ERANGE
+            // and to return with NSS_STATUS_TRYAGAIN
+            // see: http://www.gnu.org/software/libc/manual/html_node/NSS-Modules-Interface.html#NSS-Modules-Interface
+            unsafe { *errnop = ERANGE; }
+            return false;
         }
-    };
+        true
+    }
 
-    // clear buffer with NUL bytes
-    unsafe { write_bytes(*next_buf, 0, buflen as usize); }
+    // padding returns the number of bytes to skip for the next write to be aligned for a pointer
+    fn padding(&self) -> usize {
+        let align = align_of::<*mut c_char>();
+        (align - (self.next as usize) % align) % align
+    }
 
-    if bufleft <= pw_name_len {
-        // the buffer is not big enough
-        // the glibc NSS documentation demands errnop to be ERANGE
-        // and to return with NSS_STATUS_TRYAGAIN
-        // see: http://www.gnu.org/software/libc/manual/html_node/NSS-Modules-Interface.html#NSS-Modules-Interface
-        unsafe { *errnop = ERANGE; }
-        return NSS_STATUS_TRYAGAIN;
+    // write_text copies t into the buffer and returns a pointer to the NUL terminated string
+    unsafe fn write_text(&mut self, t: &Text) -> *mut c_char {
+        let dst = slice::from_raw_parts_mut(self.next as *mut u8, self.left);
+        let len = t.copy_to(dst);
+        dst[len] = 0;
+        let p = self.next;
+        self.next = self.next.offset(len as isize + 1);
+        self.left -= len + 1;
+        p
     }
-    unsafe {
-        // as_ptr() *MUST* be called inside the unsafe block!
-        (*result).pw_name = strncpy(*next_buf, cstr_pw_name.as_ptr(), pw_name_len);
-        *next_buf = next_buf.offset(pw_name_len as isize + 1)
+
+    // write_ptrs reserves an aligned array of n pointers in the buffer
+    unsafe fn write_ptrs(&mut self, n: usize) -> *mut *mut c_char {
+        let pad = self.padding();
+        let p = self.next.offset(pad as isize) as *mut *mut c_char;
+        let size = pad + n * size_of::<*mut c_char>();
+        self.next = self.next.offset(size as isize);
+        self.left -= size;
+        p
     }
-    bufleft -= pw_name_len + 1;
+}
 
-    if bufleft <= pw_passwd_len {
-        // see above
-        unsafe { *errnop = ERANGE; }
+// text_size returns the space a string takes up in the buffer, including its terminating NUL
+fn text_size(t: &Text) -> usize {
+    t.len() + 1
+}
+
+pub fn write_passwd(e: &AlexandriaPassword, result: *mut passwd, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    let mut buf = Buffer::new(buffer, buflen);
+
+    let needed = text_size(&e.pw_name) + text_size(&e.pw_passwd) + text_size(&e.pw_gecos) + text_size(&e.pw_dir) + text_size(&e.pw_shell);
+    if !buf.check(needed, errnop) {
         return NSS_STATUS_TRYAGAIN;
     }
-    unsafe {
-        // as_ptr() *MUST* be called inside the unsafe block!
-        (*result).pw_passwd = strncpy(*next_buf, cstr_pw_passwd.as_ptr(), pw_passwd_len);
-        *next_buf = next_buf.offset(pw_passwd_len as isize  + 1)
-    }
-    bufleft -= pw_passwd_len + 1;
 
-    // not 100% clear why this MUST be in an unsafe block
     unsafe {
+        (*result).pw_name = buf.write_text(&e.pw_name);
+        (*result).pw_passwd = buf.write_text(&e.pw_passwd);
         (*result).pw_uid = e.pw_uid;
         (*result).pw_gid = e.pw_gid;
+        (*result).pw_gecos = buf.write_text(&e.pw_gecos);
+        (*result).pw_dir = buf.write_text(&e.pw_dir);
+        (*result).pw_shell = buf.write_text(&e.pw_shell);
     }
 
-    if bufleft <= pw_gecos_len {
-        // see above
-        unsafe { *errnop = ERANGE; }
-        return NSS_STATUS_TRYAGAIN;
-    }
-    unsafe {
-        // as_ptr() *MUST* be called inside the unsafe block!
-        (*result).pw_gecos = strncpy(*next_buf, cstr_pw_gecos.as_ptr(), pw_gecos_len);
-        *next_buf = next_buf.offset(pw_gecos_len as isize + 1)
-    }
-    bufleft -= pw_gecos_len + 1;
+    // successfully written everytying to result and buffer
+    // errnop does not need to be set
+    NSS_STATUS_SUCCESS
+}
 
-    if bufleft <= pw_dir_len {
-        // see above
-        unsafe { *errnop = ERANGE; }
+pub fn write_group(e: &AlexandriaGroup, result: *mut group, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    let mut buf = Buffer::new(buffer, buflen);
+
+    // gr_mem is a NULL terminated array of pointers, which goes first so that it is aligned
+    let gr_mem_len = e.gr_mem.len();
+    let mut needed = buf.padding() + (gr_mem_len + 1) * size_of::<*mut c_char>() + text_size(&e.gr_name) + text_size(&e.gr_passwd);
+    for mem in &e.gr_mem {
+        needed += text_size(mem);
+    }
+    if !buf.check(needed, errnop) {
         return NSS_STATUS_TRYAGAIN;
     }
+
     unsafe {
-        // as_ptr() *MUST* be called inside the unsafe block!
-        (*result).pw_dir = strncpy(*next_buf, cstr_pw_dir.as_ptr(), pw_dir_len);
-        *next_buf = next_buf.offset(pw_dir_len as isize + 1)
+        let gr_mem = buf.write_ptrs(gr_mem_len + 1);
+        (*result).gr_name = buf.write_text(&e.gr_name);
+        (*result).gr_passwd = buf.write_text(&e.gr_passwd);
+        (*result).gr_gid = e.gr_gid;
+        for (i, mem) in e.gr_mem.iter().enumerate() {
+            *gr_mem.offset(i as isize) = buf.write_text(mem);
+        }
+        *gr_mem.offset(gr_mem_len as isize) = null_mut();
+        (*result).gr_mem = gr_mem;
     }
-    bufleft -= pw_dir_len + 1;
 
-    if bufleft <= pw_shell_len {
-        // see above
-        unsafe { *errnop = ERANGE; }
+    // successfully written everytying to result and buffer
+    // errnop does not need to be set
+    NSS_STATUS_SUCCESS
+}
+
+pub fn write_shadow(e: &AlexandriaShadow, result: *mut spwd, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    let mut buf = Buffer::new(buffer, buflen);
+
+    let needed = text_size(&e.sp_namp) + text_size(&e.sp_pwdp);
+    if !buf.check(needed, errnop) {
         return NSS_STATUS_TRYAGAIN;
     }
+
     unsafe {
-        (*result).pw_shell = strncpy(*next_buf, cstr_pw_shell.as_ptr(), pw_shell_len);
+        (*result).sp_namp = buf.write_text(&e.sp_namp);
+        (*result).sp_pwdp = buf.write_text(&e.sp_pwdp);
+        (*result).sp_lstchg = e.sp_lstchg;
+        (*result).sp_min = e.sp_min;
+        (*result).sp_max = e.sp_max;
+        (*result).sp_warn = e.sp_warn;
+        (*result).sp_inact = e.sp_inact;
+        (*result).sp_expire = e.sp_expire;
+        (*result).sp_flag = e.sp_flag;
     }
 
     // successfully written everytying to result and buffer
     // errnop does not need to be set
-    return NSS_STATUS_SUCCESS;
+    NSS_STATUS_SUCCESS
 }
 
-pub fn write_group(e: AlexandriaGroup, result: *mut group, mut buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
-    let next_buf = &mut buffer;
-    let mut bufleft = buflen;
-
-    // let's carefully craft C strings here:
-    // - NSS is expecting ERANGE in errnop only if the buffer is too small
-    // - however, we still want to try again, so we do the following for error handling:
-    // - NSS_STATUS_TRYAGAIN to retry
-    // - ENOMEM for errnop
-    // TODO: a macro could make that part shorter
-
-    // gr_name
-    let gr_name_len = e.gr_name.len();
-    let cstr_gr_name = match CString::new(e.gr_name) {
-        Ok(cstr) => cstr,
-        Err(_) => {
-            unsafe { *errnop = ENOMEM; }
-            return NSS_STATUS_TRYAGAIN;
-        },
-    };
+// write_automount writes the key and value of an automount map entry into buffer
+// autofs asks for the value only on keyed lookups, in which case key is NULL and skipped
+pub fn write_automount(e: &AlexandriaAutomount, key: *mut *mut c_char, value: *mut *mut c_char, buffer: *mut c_char, buflen: size_t, errnop: *mut c_int) -> nss_status {
+    let mut buf = Buffer::new(buffer, buflen);
 
-    // gr_passwd
-    let gr_passwd_len = e.gr_passwd.len();
-    let cstr_gr_passwd = match CString::new(e.gr_passwd) {
-        Ok(cstr) => cstr,
-        Err(_) => {
-            unsafe { *errnop = ENOMEM; }
-            return NSS_STATUS_TRYAGAIN;
-        }
-    };
-
-    // gr_mem
-    let cstr_gr_mems_len = e.gr_mem.len();
-    let mut cstr_gr_mems: Vec<*mut c_char> = Vec::with_capacity(cstr_gr_mems_len);
-    for mem in e.gr_mem {
-        let cstr_gr_mem = match CString::new(mem) {
-            Ok(cstr) => cstr.into_raw(),
-            Err(_) => {
-                unsafe { *errnop = ENOMEM; }
-                return NSS_STATUS_TRYAGAIN;
-            },
-        };
-        cstr_gr_mems.push(cstr_gr_mem);
+    let mut needed = text_size(&e.am_value);
+    if !key.is_null() {
+        needed += text_size(&e.am_key);
     }
-    let cstr_gr_mems_cap = cstr_gr_mems.capacity() + 1;
-
-    // clear buffer with NUL bytes
-    unsafe { write_bytes(*next_buf, 0, buflen as usize); }
-
-    if bufleft <= gr_name_len {
-        // the buffer is not big enough
-        // the glibc NSS documentation demands errnop to be ERANGE
-        // and to return with NSS_STATUS_TRYAGAIN
-        // see: http://www.gnu.org/software/libc/manual/html_node/NSS-Modules-Interface.html#NSS-Modules-Interface
-        unsafe { *errnop = ERANGE; }
+    if !buf.check(needed, errnop) {
         return NSS_STATUS_TRYAGAIN;
     }
+
     unsafe {
-        // as_ptr() *MUST* be called inside the unsafe block!
-        (*result).gr_name = strncpy(*next_buf, cstr_gr_name.as_ptr(), gr_name_len);
-        *next_buf = next_buf.offset(gr_name_len as isize + 1)
+        if !key.is_null() {
+            *key = buf.write_text(&e.am_key);
+        }
+        *value = buf.write_text(&e.am_value);
     }
-    bufleft -= gr_name_len + 1;
 
-    if bufleft <= gr_passwd_len {
-        // see above
-        unsafe { *errnop = ERANGE; }
-        return NSS_STATUS_TRYAGAIN;
+    // successfully written everytying to buffer
+    // errnop does not need to be set
+    NSS_STATUS_SUCCESS
+}
+
+// write_subid_ranges hands the ranges to libsubid in a malloc'ed array, which the caller frees
+pub fn write_subid_ranges(entries: &[AlexandriaSubid], ranges: *mut *mut subid_range, count: *mut c_int) -> subid_status {
+    let n = entries.len();
+    let arr = unsafe { malloc((n + 1) * size_of::<subid_range>()) as *mut subid_range };
+    if arr.is_null() {
+        return SUBID_STATUS_ERROR;
     }
-    unsafe {
-        // as_ptr() *MUST* be called inside the unsafe block!
-        (*result).gr_passwd = strncpy(*next_buf, cstr_gr_passwd.as_ptr(), gr_passwd_len);
-        *next_buf = next_buf.offset(gr_passwd_len as isize  + 1)
+
+    for (i, e) in entries.iter().enumerate() {
+        unsafe {
+            (*arr.offset(i as isize)).start = e.start as c_ulong;
+            (*arr.offset(i as isize)).count = e.count as c_ulong;
+        }
     }
-    bufleft -= gr_passwd_len + 1;
 
-    // not 100% clear why this MUST be in an unsafe block
     unsafe {
-        (*result).gr_gid = e.gr_gid;
+        *ranges = arr;
+        *count = n as c_int;
     }
+    SUBID_STATUS_SUCCESS
+}
 
-    if bufleft <= cstr_gr_mems_cap {
-        unsafe { *errnop = ERANGE; }
-        return NSS_STATUS_TRYAGAIN;
+// write_subid_owners hands the uids to libsubid in a malloc'ed array, which the caller frees
+pub fn write_subid_owners(owners: Vec<uid_t>, uids: *mut *mut uid_t, count: *mut c_int) -> subid_status {
+    let n = owners.len();
+    let arr = unsafe { malloc((n + 1) * size_of::<uid_t>()) as *mut uid_t };
+    if arr.is_null() {
+        return SUBID_STATUS_ERROR;
     }
 
     unsafe {
-        (*result).gr_mem = *next_buf as *mut *mut c_char;
-        copy(cstr_gr_mems.as_ptr(), *next_buf as *mut *mut c_char, cstr_gr_mems_len);
+        copy(owners.as_ptr(), arr, n);
+        *uids = arr;
+        *count = n as c_int;
     }
-
-    // successfully written everytying to result and buffer
-    // errnop does not need to be set
-    return NSS_STATUS_SUCCESS;
+    SUBID_STATUS_SUCCESS
 }
 
-pub fn write_shadow(e: AlexandriaShadow, result: *mut spwd, mut buffer: *mut c_char, buflen: size_t, mut errnop: *mut c_int) -> nss_status {
-    let next_buf = &mut buffer;
-    let mut bufleft = buflen;
-
-    // let's carefully craft C strings here:
-    // - NSS is expecting ERANGE in errnop only if the buffer is too small
-    // - however, we still want to try again, so we do the following for error handling:
-    // - NSS_STATUS_TRYAGAIN to retry
-    // - ENOMEM for errnop
-    // TODO: a macro could make that part shorter
-
-    // sp_namp
-    let sp_namp_len = e.sp_namp.len();
-    let cstr_sp_namp = match CString::new(e.sp_namp) {
-        Ok(cstr) => cstr,
-        Err(_) => {
-            unsafe { *errnop = ENOMEM; }
-            return NSS_STATUS_TRYAGAIN;
-        },
-    };
-
-    // sp_pwdp
-    let sp_pwdp_len = e.sp_pwdp.len();
-    let cstr_sp_pwdp = match CString::new(e.sp_pwdp) {
-        Ok(cstr) => cstr,
-        Err(_) => {
-            unsafe { *errnop = ENOMEM; }
-            return NSS_STATUS_TRYAGAIN;
-        }
-    };
-
-    // clear buffer with NUL bytes
-    unsafe { write_bytes(*next_buf, 0, buflen as usize); }
-
-    if bufleft <= sp_namp_len {
-        // the buffer is not big enough
-        // the glibc NSS documentation demands errnop to be ERANGE
-        // and to return with NSS_STATUS_TRYAGAIN
-        // see: http://www.gnu.org/software/libc/manual/html_node/NSS-Modules-Interface.html#NSS-Modules-Interface
-        unsafe { *errnop = ERANGE; }
-        return NSS_STATUS_TRYAGAIN;
-    }
+// write_gids appends gids to the array of initgroups_dyn, from *start on, leaving out skip and the
+// gids which are in there already. glibc owns the array, which is grown with realloc as needed, up
+// to limit entries if limit is positive. The gids which don't fit into limit are left out.
+pub fn write_gids(gids: Vec<gid_t>, skip: gid_t, start: *mut c_long, size: *mut c_long, groupsp: *mut *mut gid_t, limit: c_long, errnop: *mut c_int) -> nss_status {
     unsafe {
-        // as_ptr() *MUST* be called inside the unsafe block!
-        (*result).sp_namp = strncpy(*next_buf, cstr_sp_namp.as_ptr(), sp_namp_len);
-        *next_buf = next_buf.offset(sp_namp_len as isize + 1)
+        for gid in gids {
+            let known: &[gid_t] = if *start > 0 { slice::from_raw_parts(*groupsp, *start as usize) } else { &[] };
+            if gid == skip || known.contains(&gid) {
+                continue;
+            }
+            if *start == *size {
+                if limit > 0 && *size >= limit {
+                    break;
+                }
+                let mut new_size = if *size > 0 { *size * 2 } else { 16 };
+                if limit > 0 && new_size > limit {
+                    new_size = limit;
+                }
+                let grown = realloc(*groupsp as *mut c_void, new_size as usize * size_of::<gid_t>()) as *mut gid_t;
+                if grown.is_null() {
+                    *errnop = ENOMEM;
+                    return NSS_STATUS_TRYAGAIN;
+                }
+                *groupsp = grown;
+                *size = new_size;
+            }
+            *(*groupsp).offset(*start as isize) = gid;
+            *start += 1;
+        }
     }
-    bufleft -= sp_namp_len + 1;
+    NSS_STATUS_SUCCESS
+}
 
-    if bufleft <= sp_pwdp_len {
-        // see above
-        unsafe { *errnop = ERANGE; }
-        return NSS_STATUS_TRYAGAIN;
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+    use libc::c_int;
+    use libc::{ENOENT, ERANGE};
+    use types::nss_status;
+    use types::nss_status::*;
+    use types::subid_status;
+    use super::{catch, guard, panic_message};
+
+    fn is(status: nss_status, want: nss_status) -> bool {
+        status as c_int == want as c_int
     }
-    unsafe {
-        // as_ptr() *MUST* be called inside the unsafe block!
-        (*result).sp_pwdp = strncpy(*next_buf, cstr_sp_pwdp.as_ptr(), sp_pwdp_len);
-        //*next_buf = next_buf.offset(sp_pwdp_len as isize  + 1)
+
+    #[test]
+    fn catch_returns_fallback_on_panic() {
+        let status = catch("test_catch", subid_status::SUBID_STATUS_ERROR, || -> subid_status { panic!("boom") });
+        match status {
+            subid_status::SUBID_STATUS_ERROR => {},
+            _ => panic!("expected the fallback"),
+        }
+        match catch("test_catch", subid_status::SUBID_STATUS_ERROR, || subid_status::SUBID_STATUS_SUCCESS) {
+            subid_status::SUBID_STATUS_SUCCESS => {},
+            _ => panic!("expected the result of the closure"),
+        }
     }
-    //bufleft -= sp_pwdp_len + 1;
 
-    unsafe {
-        (*result).sp_lstchg = e.sp_lstchg;
-        (*result).sp_min = e.sp_min;
-        (*result).sp_max = e.sp_max;
-        (*result).sp_warn = e.sp_warn;
-        (*result).sp_inact = e.sp_inact;
-        (*result).sp_expire = e.sp_expire;
-        (*result).sp_flag = e.sp_flag;
+    #[test]
+    fn guard_reports_panic_as_unavail() {
+        let mut errno: c_int = 0;
+        let status = guard("test_guard", &mut errno, || -> nss_status { panic!("boom") });
+        assert!(is(status, NSS_STATUS_UNAVAIL));
+        assert_eq!(errno, ENOENT);
+
+        // without a panic, errnop is left to the function
+        let mut errno: c_int = ERANGE;
+        let status = guard("test_guard", &mut errno, || NSS_STATUS_NOTFOUND);
+        assert!(is(status, NSS_STATUS_NOTFOUND));
+        assert_eq!(errno, ERANGE);
+
+        // functions without an errnop pass null
+        let status = guard("test_guard", ::std::ptr::null_mut(), || -> nss_status { panic!("boom") });
+        assert!(is(status, NSS_STATUS_UNAVAIL));
     }
 
-    // successfully written everytying to result and buffer
-    // errnop does not need to be set
-    return NSS_STATUS_SUCCESS;
+    #[test]
+    fn panic_messages() {
+        let payload: Box<dyn Any + Send> = Box::new("static message");
+        assert_eq!(panic_message(&*payload), "static message");
+        let payload: Box<dyn Any + Send> = Box::new(format!("formatted {}", 42));
+        assert_eq!(panic_message(&*payload), "formatted 42");
+        let payload: Box<dyn Any + Send> = Box::new(42);
+        assert_eq!(panic_message(&*payload), "unknown cause");
+    }
 }